@@ -7,11 +7,18 @@ pub struct AuthorizedProps<'a, G: Html> {
     #[builder(default)]
     unauthorized: Option<View<G>>,
     pub user: &'a ReadSignal<Option<User>>,
+    /// Only show the children to users with this role, e.g. `admin`.
+    #[builder(default)]
+    pub role: Option<&'static str>,
 }
 
 #[component]
 pub fn Authorized<'a, G: Html>(cx: Scope<'a>, props: AuthorizedProps<'a, G>) -> View<G> {
-    let is_authorized = create_selector(cx, || props.user.get().is_some());
+    let role = props.role;
+    let is_authorized = create_selector(cx, move || match props.user.get().as_ref() {
+        Some(user) => role.map_or(true, |role| user.has_role(role)),
+        None => false,
+    });
     let children = props.children.call(cx);
     let unauthorized = props.unauthorized;
     View::new_dyn(cx, move || {
@@ -18,6 +18,9 @@ pub fn Navbar<'a, G: Html>(cx: Scope<'a>, props: NavbarProps<'a>) -> View<G> {
             }
             div(class="flex-none") {
                 Authorized(user = props.user) {
+                    span(class="mr-2") {
+                        (props.user.get().as_ref().as_ref().map(|u| u.display_name.clone()).unwrap_or_default())
+                    }
                     div(class="dropdown dropdown-end") {
                         label(tabindex="0", class="btn btn-ghost btn-circle avatar") {
                             div(class="w-10 rounded-full") {
@@ -26,6 +29,9 @@ pub fn Navbar<'a, G: Html>(cx: Scope<'a>, props: NavbarProps<'a>) -> View<G> {
                         }
                         ul(tabindex="0", class="menu menu-compact dropdown-content mt-3 p-2 shadow bg-base-100 rounded-box w-52") {
                             li { a(href="settings") { "Settings" } }
+                            Authorized(user = props.user, role = Some("admin")) {
+                                li { a(href="admin") { "Users" } }
+                            }
                             li { a(href=CONFIG.logout_url) { "Logout" } }
                         }
                     }
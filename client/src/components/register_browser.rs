@@ -1,7 +1,8 @@
 use perseus::spawn_local_scoped;
 use sycamore::prelude::{component, create_signal, view, Html, Prop, Scope, Signal, View};
+use web_sys::AbortController;
 
-use crate::{config::CONFIG, global_state::*};
+use crate::{config::CONFIG, global_state::*, log};
 
 #[derive(Prop)]
 pub struct RegisterProps<'a> {
@@ -14,8 +15,34 @@ pub struct RegisterProps<'a> {
 #[component]
 pub fn Register<'a, G: Html>(cx: Scope<'a>, props: RegisterProps<'a>) -> View<G> {
     let username_entered = create_signal(cx, "".to_string());
+    let link_sent = create_signal(cx, false);
+    let autofill = create_signal(cx, AbortController::new().ok());
+
+    // Offer passkeys in the autofill of the email input until the user picks one or
+    // logs in with the button instead.
+    spawn_local_scoped(cx, async move {
+        let signal = match autofill.get_untracked().as_ref() {
+            Some(controller) => controller.signal(),
+            None => return,
+        };
+        match crate::service::actions::authenticate_conditional(&CONFIG, &signal).await {
+            Ok(_) => {
+                props.login_state.set(AuthState::Yes);
+                props.error.set("".to_string());
+                let user = AppStateRx::get_identity_state().await;
+                AppStateRx::update_identity_state(&props.user, &props.error, &user);
+            }
+            // Aborted or not supported by the browser, the button still works.
+            Err(err) => {
+                log!("passkey autofill ended: {}", err);
+            }
+        }
+    });
 
     let on_login = move |_| {
+        if let Some(controller) = autofill.get_untracked().as_ref() {
+            controller.abort();
+        }
         spawn_local_scoped(cx, async move {
             let res =
                 crate::service::actions::authenticate(&CONFIG, username_entered.get().to_string())
@@ -35,6 +62,24 @@ pub fn Register<'a, G: Html>(cx: Scope<'a>, props: RegisterProps<'a>) -> View<G>
         });
     };
 
+    // For devices without a passkey, only works if the server has magic links enabled.
+    let on_email_link = move |_| {
+        spawn_local_scoped(cx, async move {
+            let res = crate::service::actions::request_magic_link(
+                &CONFIG,
+                username_entered.get().to_string(),
+            )
+            .await;
+            match res {
+                Ok(_) => {
+                    link_sent.set(true);
+                    props.error.set("".to_string());
+                }
+                Err(err) => props.error.set(err.to_string()),
+            }
+        });
+    };
+
     view! {cx,
         div (class="card flex-shrink-0 w-full max-w-sm shadow-2xl bg-base-100") {
             div (class="card-body") {
@@ -42,13 +87,25 @@ pub fn Register<'a, G: Html>(cx: Scope<'a>, props: RegisterProps<'a>) -> View<G>
                     label (class="label"){
                     span (class="label-text") {"Email"}
                     }
-                    input (type="text", placeholder="email", class="input input-bordered", bind:value=username_entered)
+                    input (type="text", placeholder="email", class="input input-bordered", autocomplete="username webauthn", bind:value=username_entered)
                 }
                 div (class="form-control mt-6") {
                     button (class="btn btn-primary", on:click=on_login) {
                         "Login"
                     }
+                    button (class="btn btn-link", on:click=on_email_link) {
+                        "No passkey on this device? Email me a link"
+                    }
                 }
+                (if *link_sent.get() {
+                    view! {cx,
+                        div (class="alert alert-info shadow-lg") {
+                            span { "If you have an account, a link to log in is on its way to your email." }
+                        }
+                    }
+                } else {
+                    view! {cx,}
+                })
 
                 a (class="link", href="register") { "Register" }
             }
@@ -1,17 +1,37 @@
 pub struct Config<'a> {
     pub identity_url: &'a str,
+    pub credentials_url: &'a str,
+    pub profile_url: &'a str,
     pub logout_url: &'a str,
     pub register_start: &'a str,
+    pub register_verify: &'a str,
     pub register_finish: &'a str,
     pub login_start: &'a str,
     pub login_finish: &'a str,
+    pub login_discoverable_start: &'a str,
+    pub login_discoverable_finish: &'a str,
+    pub recover: &'a str,
+    pub recovery_codes_url: &'a str,
+    pub magic_link: &'a str,
+    pub magic_link_verify: &'a str,
+    pub admin_users_url: &'a str,
 }
 
 pub const CONFIG: Config<'static> = Config {
     identity_url: "https://localhost/identity",
+    credentials_url: "https://localhost/credentials",
+    profile_url: "https://localhost/profile",
     logout_url: "https://localhost/logout",
     register_start: "https://localhost/register_start",
+    register_verify: "https://localhost/register_verify",
     register_finish: "https://localhost/register_finish",
     login_start: "https://localhost/login_start",
     login_finish: "https://localhost/login_finish",
+    login_discoverable_start: "https://localhost/login_discoverable_start",
+    login_discoverable_finish: "https://localhost/login_discoverable_finish",
+    recover: "https://localhost/recover",
+    recovery_codes_url: "https://localhost/recovery_codes",
+    magic_link: "https://localhost/magic_link",
+    magic_link_verify: "https://localhost/magic_link/verify",
+    admin_users_url: "https://localhost/admin/users",
 };
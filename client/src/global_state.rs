@@ -21,7 +21,7 @@ pub enum AuthState {
     Server,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct User {
     /// Since a user's username could change at anytime, we need to bind to a unique id.
     /// We use uuid's for this purpose, and you should generate these randomly. If the
@@ -31,6 +31,67 @@ pub struct User {
     pub unique_id: Uuid,
     pub name: String,
     pub display_name: String,
+    /// Unix seconds
+    #[serde(default)]
+    pub created_at: i64,
+    /// e.g. `admin`
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl User {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// A passkey of the current user as listed by the server, without any key material.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Credential {
+    pub id: String,
+    pub nickname: String,
+    /// Unix seconds
+    pub created_at: i64,
+    /// Unix seconds, `None` until the passkey is used to log in.
+    pub last_used_at: Option<i64>,
+    pub backup_eligible: bool,
+    pub backup_state: bool,
+}
+
+/// One page of the users an administrator searched for.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// Matching users on all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// A user with their passkeys and latest security events, as shown to administrators.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    pub user: User,
+    pub credentials: Vec<Credential>,
+    /// Newest first
+    pub recent_events: Vec<AuditEvent>,
+}
+
+/// An entry of the audit log of a user, as shown to administrators.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditEvent {
+    /// Unix seconds
+    pub timestamp: i64,
+    /// e.g. `login` or `disable`
+    pub action: String,
+    pub credential_id: Option<String>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// `success`, or the error code the request failed with.
+    pub outcome: String,
 }
 
 #[perseus::global_build_state]
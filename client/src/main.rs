@@ -42,7 +42,10 @@ pub fn main<G: Html>() -> PerseusApp<G> {
     PerseusApp::new()
         .global_state_creator(crate::global_state::get_global_state_creator())
         .error_pages(crate::error_pages::get_error_pages)
+        .template(crate::templates::admin::get_template)
+        .template(crate::templates::admin_user::get_template)
         .template(crate::templates::index::get_template)
+        .template(crate::templates::magic_link::get_template)
         .template(crate::templates::register::get_template)
         .template(crate::templates::settings::get_template)
         .index_view(|cx| {
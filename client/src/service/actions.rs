@@ -1,261 +1,455 @@
-use anyhow::Ok;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    console, window, CredentialCreationOptions, Request, RequestInit, RequestMode, Response,
+    console, window, AbortSignal, CredentialCreationOptions, Request, RequestInit, RequestMode,
+    Response,
 };
 use webauthn_rs_proto::{
-    CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+    CreationChallengeResponse, Mediation, PublicKeyCredential, RegisterPublicKeyCredential,
     RequestChallengeResponse,
 };
 
-use crate::{config::Config, utils::group::Group};
+use super::error::{ClientError, WebAuthnError};
+use crate::{
+    config::Config,
+    global_state::{Credential, User, UserDetails, UserPage},
+    utils::group::Group,
+};
 
-pub async fn get(url: &str) -> anyhow::Result<Response> {
+pub async fn get(url: &str) -> Result<Response, ClientError> {
     console::log_1(&JsValue::from_str(&format!("get {}", url)));
-    let window = window().ok_or_else(|| anyhow::anyhow!("Failed to obtain window"))?;
+    let window = window().ok_or_else(|| ClientError::unexpected("Failed to obtain window"))?;
 
     let mut opts = RequestInit::new();
     opts.method("GET");
     opts.mode(RequestMode::Cors);
     opts.credentials(web_sys::RequestCredentials::Include);
-    let request = Request::new_with_str_and_init(url, &opts)
-        .map_err(|e| anyhow::anyhow!("Failed to create fetch request {:?}", e))?;
+    let request = Request::new_with_str_and_init(url, &opts).map_err(|e| {
+        ClientError::unexpected(format!("Failed to create fetch request {:?}", e))
+    })?;
     request
         .headers()
         .set("content-type", "application/json")
-        .map_err(|e| anyhow::anyhow!("Failed to set header {:?}", e))?;
+        .map_err(|e| ClientError::unexpected(format!("Failed to set header {:?}", e)))?;
     let resp_value = JsFuture::from(window.fetch_with_request(&request))
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to fetch {:?}", e))?;
-    let resp: Response = resp_value
-        .clone()
-        .dyn_into()
-        .map_err(|e| anyhow::anyhow!("Failed to cast JSON into fetch response {:?}", e))?;
+        .map_err(|e| ClientError::network(&e))?;
+    let resp: Response = resp_value.clone().dyn_into().map_err(|e| {
+        ClientError::unexpected(format!("Failed to cast JSON into fetch response {:?}", e))
+    })?;
     if resp.ok() {
         console::log_2(&JsValue::from_str("get"), &JsValue::from(resp.status()));
         Ok(resp)
     } else {
-        let prom = resp
-            .text()
-            .map_err(|e| anyhow::anyhow!("Failed to get text from fetch response {:?}", e))?;
-        let text = JsFuture::from(prom)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to get text from fetch response {:?}", e))?
-            .as_string()
-            .map(|e| anyhow::anyhow!("GET to {} failed with {:?}", url, e))
-            .unwrap_or_else(|| anyhow::anyhow!("Failed to get text from fetch response"));
-        Err(text)
+        Err(error_from_response(resp).await)
     }
 }
 
-pub async fn get_json(url: &str) -> anyhow::Result<JsValue> {
+/// Turn a response with an error status into the problem it reports.
+async fn error_from_response(resp: Response) -> ClientError {
+    let text = match resp.text() {
+        Ok(prom) => JsFuture::from(prom).await.ok().and_then(|t| t.as_string()),
+        Err(_) => None,
+    };
+    let retry_after = resp
+        .headers()
+        .get("Retry-After")
+        .ok()
+        .flatten()
+        .and_then(|seconds| seconds.parse().ok());
+    ClientError::server(resp.status(), &text.unwrap_or_default(), retry_after)
+}
+
+/// Read the body of a successful response as JSON.
+async fn json(resp: Response) -> Result<JsValue, ClientError> {
+    let prom = resp.json().map_err(|e| {
+        ClientError::unexpected(format!("Failed to get JSON from fetch response {:?}", e))
+    })?;
+    JsFuture::from(prom).await.map_err(|e| {
+        ClientError::unexpected(format!("Failed to get JSON from fetch response {:?}", e))
+    })
+}
+
+pub async fn get_json(url: &str) -> Result<JsValue, ClientError> {
     let resp = get(url).await?;
-    let prom = resp
-        .json()
-        .map_err(|e| anyhow::anyhow!("Failed to get JSON from fetch response {:?}", e))?;
-    let jsval = JsFuture::from(prom)
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to get JSON from fetch response {:?}", e))?;
-    Ok(jsval)
+    json(resp).await
 }
 
-async fn post(url: &str, body: Option<&JsValue>) -> anyhow::Result<Response> {
-    console::log_1(&JsValue::from_str(&format!("post {} {:?}", url, body)));
-    let window = window().ok_or_else(|| anyhow::anyhow!("Failed to obtain window"))?;
+async fn post(url: &str, body: Option<&JsValue>) -> Result<Response, ClientError> {
+    send("POST", url, body).await
+}
+
+/// Only the method, URL and status are logged, bodies can hold recovery codes and the
+/// tokens of links.
+async fn send(method: &str, url: &str, body: Option<&JsValue>) -> Result<Response, ClientError> {
+    console::log_1(&JsValue::from_str(&format!("{} {}", method, url)));
+    let window = window().ok_or_else(|| ClientError::unexpected("Failed to obtain window"))?;
 
     let mut opts = RequestInit::new();
-    opts.method("POST");
+    opts.method(method);
     opts.mode(RequestMode::Cors);
     opts.body(body);
     opts.credentials(web_sys::RequestCredentials::Include);
-    let request = Request::new_with_str_and_init(url, &opts)
-        .map_err(|e| anyhow::anyhow!("Failed to create fetch request {:?}", e))?;
+    let request = Request::new_with_str_and_init(url, &opts).map_err(|e| {
+        ClientError::unexpected(format!("Failed to create fetch request {:?}", e))
+    })?;
     request
         .headers()
         .set("content-type", "application/json")
-        .map_err(|e| anyhow::anyhow!("Failed to set header {:?}", e))?;
+        .map_err(|e| ClientError::unexpected(format!("Failed to set header {:?}", e)))?;
     let resp_value = JsFuture::from(window.fetch_with_request(&request))
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to fetch {:?}", e))?;
-    let resp: Response = resp_value
-        .clone()
-        .dyn_into()
-        .map_err(|e| anyhow::anyhow!("Failed to cast JSON into fetch response {:?}", e))?;
+        .map_err(|e| ClientError::network(&e))?;
+    let resp: Response = resp_value.clone().dyn_into().map_err(|e| {
+        ClientError::unexpected(format!("Failed to cast JSON into fetch response {:?}", e))
+    })?;
+    console::log_1(&JsValue::from_str(&format!(
+        "{} {} {}",
+        method,
+        url,
+        resp.status()
+    )));
     if resp.ok() {
-        console::log_2(&JsValue::from_str("post"), &JsValue::from(resp.status()));
         Ok(resp)
     } else {
-        let prom = resp
-            .text()
-            .map_err(|e| anyhow::anyhow!("Failed to get text from fetch response {:?}", e))?;
-        let text = JsFuture::from(prom)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to get text from fetch response {:?}", e))?
-            .as_string()
-            .map(|e| anyhow::anyhow!("POST to {} failed with {:?}", url, e))
-            .unwrap_or_else(|| anyhow::anyhow!("Failed to get text from fetch response"));
-        Err(text)
+        Err(error_from_response(resp).await)
     }
 }
 
-async fn post_json(url: &str, body: Option<&JsValue>) -> anyhow::Result<JsValue> {
+async fn post_json(url: &str, body: Option<&JsValue>) -> Result<JsValue, ClientError> {
     let resp = post(url, body).await?;
-    console::log_2(&JsValue::from_str("post"), &JsValue::from(resp.status()));
-    let prom = resp
-        .json()
-        .map_err(|e| anyhow::anyhow!("Failed to get JSON from fetch response {:?}", e))?;
-    let jsval = JsFuture::from(prom)
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to get JSON from fetch response {:?}", e))?;
-    console::log_2(&JsValue::from_str("post"), &jsval);
-    Ok(jsval)
+    json(resp).await
+}
+
+fn to_body<T: Serialize>(value: &T) -> Result<JsValue, ClientError> {
+    serde_json::to_string(value)
+        .map(|s| JsValue::from(s))
+        .map_err(|e| ClientError::unexpected(format!("Failed to serialize request body {}", e)))
+}
+
+/// How far [register] got. In privacy mode the server first mails a link to verify the
+/// address, which leads back to the register page to continue with [register_verified].
+/// A new account comes with its recovery codes, which are only shown this once.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Registration {
+    Completed(Vec<String>),
+    EmailSent,
 }
 
 pub async fn register<'a>(
     config: &Config<'a>,
     username: String,
     display_name: String,
-) -> anyhow::Result<()> {
+    invite: Option<String>,
+) -> Result<Registration, ClientError> {
     let grp = Group::new(&format!("register {}", username));
-    let ccr = register_start(config, username, display_name).await?;
+    let ccr = match register_start(config, username, display_name, invite).await? {
+        Some(ccr) => ccr,
+        None => return Ok(Registration::EmailSent),
+    };
     let rpkc = update_register_challenge(ccr).await?;
-    register_complete(config, rpkc).await?;
+    let recovery_codes = register_complete(config, rpkc).await?;
+    drop(grp);
+    Ok(Registration::Completed(recovery_codes))
+}
+
+/// Finish a registration with the token from the verification email, returning the
+/// recovery codes of the new account.
+pub async fn register_verified<'a>(
+    config: &Config<'a>,
+    token: String,
+) -> Result<Vec<String>, ClientError> {
+    let grp = Group::new("register verified");
+    let body = to_body(&RegistrationVerification { token })?;
+    let jsval = post_json(config.register_verify, Some(&body)).await?;
+    let rpkc = update_register_challenge(to_ccr(jsval)?).await?;
+    let recovery_codes = register_complete(config, rpkc).await?;
     drop(grp);
+    Ok(recovery_codes)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RecoveryRequest {
+    pub name: String,
+    pub code: String,
+}
+
+/// Use a recovery code, afterwards [register] adds a new passkey to the returned user.
+pub async fn recover<'a>(
+    config: &Config<'a>,
+    name: String,
+    code: String,
+) -> Result<User, ClientError> {
+    let body = to_body(&RecoveryRequest { name, code })?;
+    let jsval = post_json(config.recover, Some(&body)).await?;
+    let user = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into user {:?}", e))
+    })?;
+    Ok(user)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MagicLinkRequest {
+    pub name: String,
+}
+
+/// Ask for a login link by email. The server answers the same whether the user exists or
+/// not, so this cannot tell whether an email was sent.
+pub async fn request_magic_link<'a>(config: &Config<'a>, name: String) -> Result<(), ClientError> {
+    let body = to_body(&MagicLinkRequest { name })?;
+    post(config.magic_link, Some(&body)).await?;
     Ok(())
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MagicLinkVerification {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MagicLinkVerified {
+    #[serde(flatten)]
+    pub user: User,
+    /// False if the link only allows adding a passkey with [register].
+    pub logged_in: bool,
+    pub redirect_to: Option<String>,
+}
+
+/// Trade the token of a login link for the user it belongs to.
+pub async fn verify_magic_link<'a>(
+    config: &Config<'a>,
+    token: String,
+) -> Result<MagicLinkVerified, ClientError> {
+    let body = to_body(&MagicLinkVerification { token })?;
+    let jsval = post_json(config.magic_link_verify, Some(&body)).await?;
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into user {:?}", e))
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoveryCodes {
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    pub remaining: usize,
+}
+
+pub async fn get_recovery_codes<'a>(config: &Config<'a>) -> Result<RecoveryCodes, ClientError> {
+    let jsval = get_json(config.recovery_codes_url).await?;
+    to_recovery_codes(jsval)
+}
+
+/// Replace the recovery codes of the logged in user, the old ones stop working.
+pub async fn regenerate_recovery_codes<'a>(
+    config: &Config<'a>,
+) -> Result<RecoveryCodes, ClientError> {
+    let jsval = post_json(config.recovery_codes_url, None).await?;
+    to_recovery_codes(jsval)
+}
+
+fn to_recovery_codes(jsval: JsValue) -> Result<RecoveryCodes, ClientError> {
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!(
+            "Failed to deserialize JSON into recovery codes {:?}",
+            e
+        ))
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct UserRegistration {
     pub name: String,
     pub display_name: String,
+    /// Only needed for new accounts when the server requires invites.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RegistrationVerification {
+    pub token: String,
 }
 
+/// `None` if the server accepted the registration but mailed a link to verify the address
+/// instead of answering with the challenge.
 async fn register_start<'a>(
     config: &Config<'a>,
     username: String,
     display_name: String,
-) -> anyhow::Result<CreationChallengeResponse> {
-    let body = serde_json::to_string(&UserRegistration {
+    invite: Option<String>,
+) -> Result<Option<CreationChallengeResponse>, ClientError> {
+    let body = to_body(&UserRegistration {
         name: username,
         display_name: display_name,
-    })
-    .map(|s| JsValue::from(s))
-    .map_err(|e| anyhow::Error::msg(format!("Failed to serialize request body {}", e)))?;
-    let jsval = post_json(config.register_start, Some(&body)).await?;
-    let ccr: CreationChallengeResponse = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
-        anyhow::anyhow!(
+        invite,
+    })?;
+    let resp = post(config.register_start, Some(&body)).await?;
+    if resp.status() == 202 {
+        return Ok(None);
+    }
+    let jsval = json(resp).await?;
+    Ok(Some(to_ccr(jsval)?))
+}
+
+fn to_ccr(jsval: JsValue) -> Result<CreationChallengeResponse, ClientError> {
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!(
             "Failed to deserialize JSON into CreationChallengeResponse {:?}",
             e
-        )
-    })?;
-    Ok(ccr)
+        ))
+    })
 }
 
 async fn update_register_challenge(
     ccr: CreationChallengeResponse,
-) -> anyhow::Result<RegisterPublicKeyCredential> {
+) -> Result<RegisterPublicKeyCredential, ClientError> {
     console::log_1(&JsValue::from_str("update_register_challenge"));
-    let window = window().ok_or_else(|| anyhow::anyhow!("Failed to obtain window"))?;
+    let window = window().ok_or_else(|| ClientError::unexpected("Failed to obtain window"))?;
     let c_options: CredentialCreationOptions = ccr.clone().into();
     console::log_1(&c_options);
     let promise = window
         .navigator()
         .credentials()
         .create_with_options(&c_options)
-        .map_err(|e| {
-            anyhow::anyhow!(
-                "Failed to create credentials with options {:?} {:?}",
-                &c_options,
-                e
-            )
-        })?;
-    let jsval = JsFuture::from(promise).await.map_err(|e| {
-        anyhow::Error::msg(format!(
-            "Unable to complete credential creation with options {:?}",
-            e
-        ))
-    })?;
+        .map_err(|e| WebAuthnError::from_js(&e))?;
+    let jsval = JsFuture::from(promise)
+        .await
+        .map_err(|e| WebAuthnError::from_js(&e))?;
     let w_rpkc = web_sys::PublicKeyCredential::from(jsval);
     let rpkc = RegisterPublicKeyCredential::from(w_rpkc);
     Ok(rpkc)
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Registered {
+    /// Only set for new accounts
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
 async fn register_complete<'a>(
     config: &Config<'a>,
     rpkc: RegisterPublicKeyCredential,
-) -> anyhow::Result<()> {
+) -> Result<Vec<String>, ClientError> {
     console::log_2(
         &JsValue::from_str("register_complete"),
         &JsValue::from_str(&format!("{:?}", rpkc)),
     );
-    let req_jsvalue = serde_json::to_string(&rpkc)
-        .map(|s| JsValue::from(s))
-        .map_err(|e| anyhow::anyhow!("Failed to get text from fetch response {:?}", e))?;
-    let public_key_credential = post(config.register_finish, Some(&req_jsvalue)).await?;
-    console::log_2(
-        &JsValue::from_str("register_complete"),
-        &public_key_credential,
-    );
-    Ok(())
+    let req_jsvalue = to_body(&rpkc)?;
+    let jsval = post_json(config.register_finish, Some(&req_jsvalue)).await?;
+    let registered: Registered = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into registration {:?}", e))
+    })?;
+    Ok(registered.recovery_codes)
 }
 
-pub async fn authenticate<'a>(config: &Config<'a>, username: String) -> anyhow::Result<()> {
+pub async fn authenticate<'a>(config: &Config<'a>, username: String) -> Result<(), ClientError> {
     let grp = Group::new(&format!("authenticate {}", username));
     let rcr = authenticate_begin(config, username).await?;
-    let pkc = update_authenticate_challenge(rcr).await?;
-    authenticate_complete(config, pkc).await?;
+    let pkc = update_authenticate_challenge(rcr, None).await?;
+    authenticate_complete(config.login_finish, pkc).await?;
     drop(grp);
     Ok(())
 }
 
+/// Offer the passkeys of this site in the autofill of inputs marked with
+/// `autocomplete="username webauthn"`. Only resolves once the user picks a passkey, so
+/// `signal` has to be aborted before starting any other authentication.
+pub async fn authenticate_conditional<'a>(
+    config: &Config<'a>,
+    signal: &AbortSignal,
+) -> Result<(), ClientError> {
+    if !is_conditional_mediation_available().await {
+        return Err(WebAuthnError::NotSupported.into());
+    }
+    let grp = Group::new("authenticate conditional");
+    let mut rcr = authenticate_discoverable_begin(config).await?;
+    rcr.mediation = Some(Mediation::Conditional);
+    let pkc = update_authenticate_challenge(rcr, Some(signal)).await?;
+    authenticate_complete(config.login_discoverable_finish, pkc).await?;
+    drop(grp);
+    Ok(())
+}
+
+/// `PublicKeyCredential.isConditionalMediationAvailable()` is not part of web_sys yet and
+/// missing in older browsers.
+async fn is_conditional_mediation_available() -> bool {
+    let available = window()
+        .and_then(|window| js_sys::Reflect::get(&window, &"PublicKeyCredential".into()).ok())
+        .and_then(|pkc| {
+            js_sys::Reflect::get(&pkc, &"isConditionalMediationAvailable".into())
+                .ok()?
+                .dyn_into::<js_sys::Function>()
+                .ok()?
+                .call0(&pkc)
+                .ok()
+        })
+        .and_then(|promise| promise.dyn_into::<js_sys::Promise>().ok());
+    match available {
+        Some(promise) => JsFuture::from(promise)
+            .await
+            .ok()
+            .and_then(|available| available.as_bool())
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+async fn authenticate_discoverable_begin<'a>(
+    config: &Config<'a>,
+) -> Result<RequestChallengeResponse, ClientError> {
+    console::log_1(&JsValue::from_str("authenticate_discoverable_begin"));
+    let jsval = post_json(config.login_discoverable_start, None).await?;
+    let rcr: RequestChallengeResponse = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!(
+            "Failed to deserialize JSON into RequestChallengeResponse {:?}",
+            e
+        ))
+    })?;
+    Ok(rcr)
+}
+
 async fn authenticate_begin<'a>(
     config: &Config<'a>,
     username: String,
-) -> anyhow::Result<RequestChallengeResponse> {
+) -> Result<RequestChallengeResponse, ClientError> {
     console::log_2(
         &JsValue::from_str("authenticate_begin"),
         &JsValue::from_str(&format!("username {:?}", username)),
     );
     let jsval = post_json(config.login_start, Some(&JsValue::from_str(&username))).await?;
     let rcr: RequestChallengeResponse = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
-        anyhow::anyhow!(
+        ClientError::unexpected(format!(
             "Failed to deserialize JSON into RequestChallengeResponse {:?}",
             e
-        )
+        ))
     })?;
     Ok(rcr)
 }
 
 async fn update_authenticate_challenge(
     rcr: RequestChallengeResponse,
-) -> anyhow::Result<PublicKeyCredential> {
+    signal: Option<&AbortSignal>,
+) -> Result<PublicKeyCredential, ClientError> {
     console::log_2(
         &JsValue::from_str("update_authenticate_challenge"),
         &JsValue::from_str(&format!("rcr {:?}", rcr)),
     );
-    let window = window().ok_or_else(|| anyhow::anyhow!("Failed to obtain window"))?;
+    let window = window().ok_or_else(|| ClientError::unexpected("Failed to obtain window"))?;
 
-    let c_options: web_sys::CredentialRequestOptions = rcr.into();
+    let mut c_options: web_sys::CredentialRequestOptions = rcr.into();
+    if let Some(signal) = signal {
+        c_options.signal(signal);
+    }
     let promise = window
         .navigator()
         .credentials()
         .get_with_options(&c_options)
-        .map_err(|e| {
-            anyhow::anyhow!(
-                "Failed to get credentials from container {:?} {:?}",
-                c_options,
-                e
-            )
-        })?;
-    let jsval = JsFuture::from(promise).await.map_err(|e| {
-        anyhow::anyhow!(
-            "Failed to get credentials from container {:?} {:?}",
-            c_options,
-            e
-        )
-    })?;
+        .map_err(|e| WebAuthnError::from_js(&e))?;
+    let jsval = JsFuture::from(promise)
+        .await
+        .map_err(|e| WebAuthnError::from_js(&e))?;
     // Wait on the promise, when complete it will issue a callback.
     let w_rpkc = web_sys::PublicKeyCredential::from(jsval);
     // Serialise the web_sys::pkc into the webauthn proto version, ready to
@@ -264,23 +458,139 @@ async fn update_authenticate_challenge(
     Ok(pkc)
 }
 
-async fn authenticate_complete<'a>(
-    config: &Config<'a>,
-    pkc: PublicKeyCredential,
-) -> anyhow::Result<()> {
+async fn authenticate_complete(url: &str, pkc: PublicKeyCredential) -> Result<(), ClientError> {
     console::log_2(
         &JsValue::from_str("authenticate_complete"),
         &JsValue::from_str(&format!("pkc {:?}", pkc)),
     );
 
-    let req_jsvalue = serde_json::to_string(&pkc)
-        .map(|s| JsValue::from(&s))
-        .map_err(|e| anyhow::anyhow!("Failed to serialize {:?} {:?}", pkc, e))?;
+    let req_jsvalue = to_body(&pkc)?;
 
-    let _resp = post(config.login_finish, Some(&req_jsvalue)).await?;
+    let logged_in = post_json(url, Some(&req_jsvalue)).await?;
     console::log_2(
         &JsValue::from_str("authenticate_complete"),
         &JsValue::from_str("AuthenticateSuccess"),
     );
+    // Set when the login was started by an OpenID Connect client, which waits for the
+    // browser to come back with the code.
+    let redirect_to = js_sys::Reflect::get(&logged_in, &"redirect_to".into())
+        .ok()
+        .and_then(|value| value.as_string());
+    if let Some(redirect_to) = redirect_to {
+        window()
+            .ok_or_else(|| ClientError::unexpected("Failed to obtain window"))?
+            .location()
+            .set_href(&redirect_to)
+            .map_err(|e| ClientError::unexpected(format!("Failed to redirect {:?}", e)))?;
+    }
+    Ok(())
+}
+
+pub async fn list_credentials<'a>(config: &Config<'a>) -> Result<Vec<Credential>, ClientError> {
+    let jsval = get_json(config.credentials_url).await?;
+    let credentials = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into credentials {:?}", e))
+    })?;
+    Ok(credentials)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CredentialRename {
+    pub nickname: String,
+}
+
+pub async fn rename_credential<'a>(
+    config: &Config<'a>,
+    id: &str,
+    nickname: String,
+) -> Result<(), ClientError> {
+    let body = to_body(&CredentialRename { nickname })?;
+    let url = format!("{}/{}", config.credentials_url, id);
+    send("PUT", &url, Some(&body)).await?;
+    Ok(())
+}
+
+/// Change the username and display name of the logged in user, returning the updated user.
+pub async fn update_profile<'a>(
+    config: &Config<'a>,
+    name: String,
+    display_name: String,
+) -> Result<User, ClientError> {
+    let body = to_body(&UserRegistration {
+        name,
+        display_name,
+        invite: None,
+    })?;
+    let resp = send("PUT", config.profile_url, Some(&body)).await?;
+    let jsval = json(resp).await?;
+    let user = serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into user {:?}", e))
+    })?;
+    Ok(user)
+}
+
+pub async fn delete_credential<'a>(config: &Config<'a>, id: &str) -> Result<(), ClientError> {
+    let url = format!("{}/{}", config.credentials_url, id);
+    send("DELETE", &url, None).await?;
+    Ok(())
+}
+
+/// Users whose name or display name contains `search`, only for administrators.
+pub async fn list_users<'a>(
+    config: &Config<'a>,
+    search: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<UserPage, ClientError> {
+    let url = format!(
+        "{}?search={}&offset={}&limit={}",
+        config.admin_users_url,
+        js_sys::encode_uri_component(search),
+        offset,
+        limit
+    );
+    let jsval = get_json(&url).await?;
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into users {:?}", e))
+    })
+}
+
+pub async fn get_user<'a>(config: &Config<'a>, id: &str) -> Result<UserDetails, ClientError> {
+    let url = format!("{}/{}", config.admin_users_url, id);
+    let jsval = get_json(&url).await?;
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into user {:?}", e))
+    })
+}
+
+/// Disable or enable an account, disabling also logs the user out everywhere.
+pub async fn set_user_disabled<'a>(
+    config: &Config<'a>,
+    id: &str,
+    disabled: bool,
+) -> Result<User, ClientError> {
+    let action = if disabled { "disable" } else { "enable" };
+    let url = format!("{}/{}/{}", config.admin_users_url, id, action);
+    let jsval = post_json(&url, None).await?;
+    serde_wasm_bindgen::from_value(jsval).map_err(|e| {
+        ClientError::unexpected(format!("Failed to deserialize JSON into user {:?}", e))
+    })
+}
+
+/// Remove a passkey of another user, even their last one.
+pub async fn revoke_user_credential<'a>(
+    config: &Config<'a>,
+    id: &str,
+    cred_id: &str,
+) -> Result<(), ClientError> {
+    let url = format!("{}/{}/credentials/{}", config.admin_users_url, id, cred_id);
+    send("DELETE", &url, None).await?;
+    Ok(())
+}
+
+/// End every session of the user and revoke their refresh tokens.
+pub async fn logout_user<'a>(config: &Config<'a>, id: &str) -> Result<(), ClientError> {
+    let url = format!("{}/{}/logout", config.admin_users_url, id);
+    post(&url, None).await?;
     Ok(())
 }
@@ -0,0 +1,191 @@
+use serde::Deserialize;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{console, DomException};
+
+/// Why a request to the server or to the authenticator failed.
+///
+/// `Display` renders a message for the user, all of them are in [ClientError::message] so
+/// they can be translated in one place. The technical details are logged to the console
+/// when the error is created.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// No answer at all, e.g. the server is down or its certificate is not trusted.
+    Network(String),
+    /// The server answered with a problem details object, `code` is its stable error code.
+    /// `retry_after` are the seconds from the `Retry-After` header, if any.
+    Server {
+        status: u16,
+        code: String,
+        detail: String,
+        retry_after: Option<u64>,
+    },
+    /// `navigator.credentials.create()` or `get()` was rejected.
+    WebAuthn(WebAuthnError),
+    /// A bug in the client or a response it does not understand.
+    Unexpected(String),
+}
+
+/// The `DOMException` names the WebAuthn API rejects with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebAuthnError {
+    /// The user cancelled the dialog or it timed out, browsers don't tell them apart.
+    NotAllowed,
+    /// The authenticator already holds a credential excluded by the server.
+    InvalidState,
+    /// The origin of the page does not match the relying party id of the server.
+    Security,
+    /// The page aborted the request, e.g. to end passkey autofill.
+    Abort,
+    /// The browser or the authenticator lacks a requested feature.
+    NotSupported,
+    Other { name: String, message: String },
+}
+
+/// The part of a problem details response the client needs.
+#[derive(Deserialize)]
+struct Problem {
+    code: String,
+    detail: String,
+}
+
+impl ClientError {
+    pub fn network(err: &JsValue) -> Self {
+        console::warn_2(&JsValue::from_str("network error"), err);
+        ClientError::Network(format!("{:?}", err))
+    }
+
+    pub fn unexpected(message: impl Into<String>) -> Self {
+        let message = message.into();
+        console::warn_1(&JsValue::from_str(&message));
+        ClientError::Unexpected(message)
+    }
+
+    /// Classify the body of a failed response, which the server sends as problem details.
+    pub fn server(status: u16, body: &str, retry_after: Option<u64>) -> Self {
+        console::warn_1(&JsValue::from_str(&format!("{} {}", status, body)));
+        match serde_json::from_str::<Problem>(body) {
+            Ok(problem) => ClientError::Server {
+                status,
+                code: problem.code,
+                detail: problem.detail,
+                retry_after,
+            },
+            Err(_) => ClientError::Server {
+                status,
+                code: "unknown".to_string(),
+                detail: body.to_string(),
+                retry_after,
+            },
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let message = match self {
+            ClientError::Network(_) => {
+                "The server could not be reached, check your connection and try again."
+            }
+            ClientError::Server {
+                code,
+                detail,
+                retry_after,
+                ..
+            } => match code.as_str() {
+                // Validation messages of the server are already meant for the user.
+                "invalid_input" => return detail.clone(),
+                "rate_limited" => {
+                    return match retry_after {
+                        Some(1) => "Too many attempts, please try again in a second.".to_string(),
+                        Some(seconds) => format!(
+                            "Too many attempts, please try again in {} seconds.",
+                            seconds
+                        ),
+                        None => "Too many attempts, please try again later.".to_string(),
+                    }
+                }
+                "unauthenticated" => "Your session has ended, please log in again.",
+                "session_state_missing" => "This took too long, please try again.",
+                "registration_failed" => "The new passkey could not be verified, please try again.",
+                "authentication_failed" => "The passkey could not be verified, please try again.",
+                "user_not_found" => "There is no account with this username.",
+                "no_credentials" => "This account has no passkeys.",
+                "credential_not_found" => "This passkey does not exist anymore.",
+                "username_taken" => "This username is already taken.",
+                "invalid_recovery_code" => {
+                    "The username or recovery code is wrong, each code can only be used once."
+                }
+                "untrusted_authenticator" => {
+                    "This authenticator is not allowed here, please use another one."
+                }
+                // Registration and magic links alike.
+                "invalid_token" => {
+                    "This link is invalid, has expired or was used already, please ask for a new one."
+                }
+                "last_credential" => "This is your only passkey, add another one before deleting it.",
+                "account_disabled" => "This account has been disabled by an administrator.",
+                "registration_closed" => "New accounts cannot be registered at the moment.",
+                "invalid_invite" => "This invite code is invalid, has expired or was used already.",
+                "domain_not_allowed" => "Usernames have to be email addresses of an allowed domain.",
+                "missing_role" => "You are not allowed to do this.",
+                _ => "Something went wrong on the server, please try again later.",
+            },
+            ClientError::WebAuthn(err) => match err {
+                WebAuthnError::NotAllowed => "The passkey request was cancelled or timed out.",
+                WebAuthnError::InvalidState => {
+                    "This authenticator is already registered for your account."
+                }
+                WebAuthnError::Security => {
+                    "This site is not allowed to use passkeys for the server's domain."
+                }
+                WebAuthnError::Abort => "The passkey request was aborted.",
+                WebAuthnError::NotSupported => {
+                    "Your browser or authenticator does not support this kind of passkey."
+                }
+                WebAuthnError::Other { .. } => "The passkey request failed.",
+            },
+            ClientError::Unexpected(_) => {
+                "Something went wrong, please reload the page and try again."
+            }
+        };
+        message.to_string()
+    }
+}
+
+impl WebAuthnError {
+    pub fn from_js(err: &JsValue) -> Self {
+        console::warn_2(&JsValue::from_str("webauthn error"), err);
+        let exception = match err.dyn_ref::<DomException>() {
+            Some(exception) => exception,
+            None => {
+                return WebAuthnError::Other {
+                    name: "Error".to_string(),
+                    message: format!("{:?}", err),
+                }
+            }
+        };
+        match exception.name().as_str() {
+            "NotAllowedError" => WebAuthnError::NotAllowed,
+            "InvalidStateError" => WebAuthnError::InvalidState,
+            "SecurityError" => WebAuthnError::Security,
+            "AbortError" => WebAuthnError::Abort,
+            "NotSupportedError" => WebAuthnError::NotSupported,
+            name => WebAuthnError::Other {
+                name: name.to_string(),
+                message: exception.message(),
+            },
+        }
+    }
+}
+
+impl From<WebAuthnError> for ClientError {
+    fn from(err: WebAuthnError) -> Self {
+        ClientError::WebAuthn(err)
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+impl std::error::Error for ClientError {}
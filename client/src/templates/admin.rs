@@ -0,0 +1,180 @@
+use crate::{
+    components::{Authorized, Navbar},
+    global_state::*,
+    utils::time::format_unix,
+};
+use perseus::prelude::*;
+use sycamore::prelude::*;
+
+/// Users per page, the server allows up to 100.
+const PAGE_SIZE: usize = 20;
+
+/// All users for administrators, searchable by name and display name.
+#[perseus::template_rx]
+pub fn admin_page<'a, G: Html>(cx: Scope<'a>, _: (), app_state: AppStateRx<'a>) -> View<G> {
+    #[cfg(target_arch = "wasm32")]
+    AppStateRx::load_identity_state(&app_state, cx);
+
+    let error = app_state.error;
+    let search = create_signal(cx, String::new());
+    // The submitted search, paging keeps to it even if the input has been edited since.
+    let query = create_signal(cx, String::new());
+    let page = create_signal(cx, UserPage::default());
+    // The identity arrives after the first render, other users would only get an error.
+    let is_admin = create_selector(cx, move || {
+        app_state
+            .user
+            .get()
+            .as_ref()
+            .as_ref()
+            .map_or(false, |user| user.has_role("admin"))
+    });
+    create_effect(cx, move || {
+        if *is_admin.get() {
+            load(cx, query, 0, page, error);
+        }
+    });
+
+    let on_search = move |_| {
+        query.set(search.get().trim().to_string());
+        load(cx, query, 0, page, error)
+    };
+    let on_previous = move |_| {
+        let offset = page.get().offset.saturating_sub(PAGE_SIZE);
+        load(cx, query, offset, page, error)
+    };
+    let on_next = move |_| load(cx, query, page.get().offset + PAGE_SIZE, page, error);
+
+    let users = create_memo(cx, || page.get().users.clone());
+    let range = create_memo(cx, || {
+        let page = page.get();
+        match page.users.len() {
+            0 => "No users found".to_string(),
+            n => format!(
+                "{} to {} of {}",
+                page.offset + 1,
+                page.offset + n,
+                page.total
+            ),
+        }
+    });
+    let is_first = create_memo(cx, || page.get().offset == 0);
+    let is_last = create_memo(cx, || {
+        let page = page.get();
+        page.offset + page.users.len() >= page.total
+    });
+
+    let unauthorized = view! { cx,
+        p { "Only administrators can see this page." }
+        a(class="link", href="/") { "Go back" }
+    };
+    view! { cx,
+        Navbar(user = app_state.user, error = app_state.error)
+        Authorized(user = app_state.user, role = Some("admin"), unauthorized = Some(unauthorized)) {
+            div (class="hero min-h-[60vh] bg-base-200") {
+                div (class="hero-content flex-col w-full max-w-4xl") {
+                    h1(class="text-3xl font-bold") { "Users" }
+                    div (class="input-group w-full max-w-sm") {
+                        input (type="text", placeholder="name or display name", class="input input-bordered w-full", bind:value=search)
+                        button (class="btn btn-primary", on:click=on_search) { "Search" }
+                    }
+                    div (class="overflow-x-auto w-full") {
+                        table (class="table w-full") {
+                            thead {
+                                tr {
+                                    th { "Username" }
+                                    th { "Display name" }
+                                    th { "Created" }
+                                    th { "Roles" }
+                                    th { "Status" }
+                                }
+                            }
+                            tbody {
+                                Indexed(
+                                    iterable = users,
+                                    view = |cx, user| {
+                                        let href = format!("admin_user?id={}", user.unique_id);
+                                        let status = if user.disabled { "disabled" } else { "active" };
+                                        view! { cx,
+                                            tr {
+                                                td { a (class="link", href=href) { (user.name) } }
+                                                td { (user.display_name) }
+                                                td { (format_unix(user.created_at)) }
+                                                td { (user.roles.join(", ")) }
+                                                td { (status) }
+                                            }
+                                        }
+                                    }
+                                )
+                            }
+                        }
+                    }
+                    div (class="flex items-center gap-4") {
+                        button (class="btn btn-sm", disabled=*is_first.get(), on:click=on_previous) { "Previous" }
+                        span { (range.get()) }
+                        button (class="btn btn-sm", disabled=*is_last.get(), on:click=on_next) { "Next" }
+                    }
+                    (if *error.get() != "" {
+                        view!{cx,
+                            div (class="alert alert-error shadow-lg mt-6") {
+                                span { (*error.get()) }
+                            }
+                        }
+                    } else {
+                        view!{ cx,}
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load<'a>(
+    cx: Scope<'a>,
+    query: &'a Signal<String>,
+    offset: usize,
+    page: &'a Signal<UserPage>,
+    error: &'a Signal<String>,
+) {
+    // Untracked, so the effect loading the first page does not rerun on every search.
+    let query = query.get_untracked().to_string();
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::list_users(
+            &crate::config::CONFIG,
+            &query,
+            offset,
+            PAGE_SIZE,
+        )
+        .await
+        {
+            Ok(users) => {
+                error.set("".to_string());
+                page.set(users);
+            }
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+// Nothing to fetch while rendering on the server.
+#[cfg(not(target_arch = "wasm32"))]
+fn load<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<String>,
+    _: usize,
+    _: &'a Signal<UserPage>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[perseus::head]
+pub fn head(cx: Scope) -> View<SsrNode> {
+    view! { cx,
+        title { "WebAuthn - Users" }
+    }
+}
+
+pub fn get_template<G: Html>() -> Template<G> {
+    Template::new("admin").template(admin_page).head(head)
+}
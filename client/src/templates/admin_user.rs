@@ -0,0 +1,361 @@
+use crate::{
+    components::{Authorized, Navbar},
+    global_state::*,
+    utils::time::format_unix,
+};
+use perseus::prelude::*;
+use sycamore::prelude::*;
+
+/// One user for administrators, with their passkeys and recent activity. The user is
+/// picked by the `id` in the query, e.g. `admin_user?id=...` as linked from the user list.
+#[perseus::template_rx]
+pub fn admin_user_page<'a, G: Html>(cx: Scope<'a>, _: (), app_state: AppStateRx<'a>) -> View<G> {
+    #[cfg(target_arch = "wasm32")]
+    AppStateRx::load_identity_state(&app_state, cx);
+
+    let error = app_state.error;
+    let user_id = create_signal(cx, query_user_id());
+    let details = create_signal(cx, None::<UserDetails>);
+    // Like the list of users, only fetched once the identity says admin.
+    let is_admin = create_selector(cx, move || {
+        app_state
+            .user
+            .get()
+            .as_ref()
+            .as_ref()
+            .map_or(false, |user| user.has_role("admin"))
+    });
+    create_effect(cx, move || {
+        if *is_admin.get() {
+            reload(cx, user_id, details, error);
+        }
+    });
+
+    let on_toggle = move |_| toggle_disabled(cx, user_id, details, error);
+    let on_logout = move |_| logout(cx, user_id, details, error);
+
+    let user = create_memo(cx, || {
+        details
+            .get()
+            .as_ref()
+            .as_ref()
+            .map(|details| details.user.clone())
+    });
+    let credentials = create_memo(cx, || {
+        details
+            .get()
+            .as_ref()
+            .as_ref()
+            .map(|details| details.credentials.clone())
+            .unwrap_or_default()
+    });
+    let events = create_memo(cx, || {
+        details
+            .get()
+            .as_ref()
+            .as_ref()
+            .map(|details| details.recent_events.clone())
+            .unwrap_or_default()
+    });
+
+    let unauthorized = view! { cx,
+        p { "Only administrators can see this page." }
+        a(class="link", href="/") { "Go back" }
+    };
+    view! { cx,
+        Navbar(user = app_state.user, error = app_state.error)
+        Authorized(user = app_state.user, role = Some("admin"), unauthorized = Some(unauthorized)) {
+            div (class="hero min-h-[60vh] bg-base-200") {
+                div (class="hero-content flex-col w-full max-w-4xl") {
+                    a (class="link self-start", href="admin") { "All users" }
+                    (match user.get().as_ref() {
+                        Some(user) => {
+                            let title = format!("{} ({})", user.display_name, user.name);
+                            let created = format!("Registered {}", format_unix(user.created_at));
+                            let roles = if user.roles.is_empty() {
+                                "No roles".to_string()
+                            } else {
+                                format!("Roles: {}", user.roles.join(", "))
+                            };
+                            let (status, toggle) = if user.disabled {
+                                ("The account is disabled.", "Enable")
+                            } else {
+                                ("The account is active.", "Disable")
+                            };
+                            view! { cx,
+                                h1(class="text-3xl font-bold") { (title) }
+                                div (class="card w-full max-w-sm shadow-2xl bg-base-100") {
+                                    div (class="card-body") {
+                                        p { (created) }
+                                        p { (roles) }
+                                        p { (status) }
+                                        div (class="btn-group mt-6") {
+                                            button (class="btn btn-error", on:click=on_toggle) { (toggle) }
+                                            button (class="btn", on:click=on_logout) { "Log out everywhere" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        None => view! { cx,},
+                    })
+                    h1(class="text-3xl font-bold") { "Passkeys" }
+                    div (class="overflow-x-auto w-full") {
+                        table (class="table w-full") {
+                            thead {
+                                tr {
+                                    th { "Name" }
+                                    th { "Created" }
+                                    th { "Last used" }
+                                    th {}
+                                }
+                            }
+                            tbody {
+                                Indexed(
+                                    iterable = credentials,
+                                    view = move |cx, credential| {
+                                        let last_used = credential
+                                            .last_used_at
+                                            .map(format_unix)
+                                            .unwrap_or_else(|| "never".to_string());
+                                        let on_revoke = {
+                                            let credential = credential.clone();
+                                            move |_| revoke(cx, user_id, &credential, details, error)
+                                        };
+                                        view! { cx,
+                                            tr {
+                                                td { (credential.nickname) }
+                                                td { (format_unix(credential.created_at)) }
+                                                td { (last_used) }
+                                                td {
+                                                    button (class="btn btn-sm btn-error", on:click=on_revoke) { "Revoke" }
+                                                }
+                                            }
+                                        }
+                                    }
+                                )
+                            }
+                        }
+                    }
+                    h1(class="text-3xl font-bold") { "Recent activity" }
+                    div (class="overflow-x-auto w-full") {
+                        table (class="table w-full") {
+                            thead {
+                                tr {
+                                    th { "Time" }
+                                    th { "Action" }
+                                    th { "Outcome" }
+                                    th { "IP address" }
+                                    th { "Browser" }
+                                }
+                            }
+                            tbody {
+                                Indexed(
+                                    iterable = events,
+                                    view = |cx, event| {
+                                        let client_ip = event.client_ip.unwrap_or_default();
+                                        let user_agent = event.user_agent.unwrap_or_default();
+                                        view! { cx,
+                                            tr {
+                                                td { (format_unix(event.timestamp)) }
+                                                td { (event.action) }
+                                                td { (event.outcome) }
+                                                td { (client_ip) }
+                                                td { (user_agent) }
+                                            }
+                                        }
+                                    }
+                                )
+                            }
+                        }
+                    }
+                    (if *error.get() != "" {
+                        view!{cx,
+                            div (class="alert alert-error shadow-lg mt-6") {
+                                span { (*error.get()) }
+                            }
+                        }
+                    } else {
+                        view!{ cx,}
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn query_user_id() -> String {
+    web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+        .and_then(|params| params.get("id"))
+        .unwrap_or_default()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn confirm(message: &str) -> bool {
+    web_sys::window()
+        .and_then(|w| w.confirm_with_message(message).ok())
+        .unwrap_or(false)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn reload<'a>(
+    cx: Scope<'a>,
+    user_id: &'a Signal<String>,
+    details: &'a Signal<Option<UserDetails>>,
+    error: &'a Signal<String>,
+) {
+    if user_id.get().is_empty() {
+        error.set("No user selected, pick one from the list of users.".to_string());
+        return;
+    }
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::get_user(&crate::config::CONFIG, &user_id.get()).await {
+            Ok(user) => details.set(Some(user)),
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+/// Disabling also ends all sessions of the user, enabling lets them log in again.
+#[cfg(target_arch = "wasm32")]
+fn toggle_disabled<'a>(
+    cx: Scope<'a>,
+    user_id: &'a Signal<String>,
+    details: &'a Signal<Option<UserDetails>>,
+    error: &'a Signal<String>,
+) {
+    let disabled = match details.get().as_ref() {
+        Some(details) => !details.user.disabled,
+        None => return,
+    };
+    if disabled
+        && !confirm("Disable this account? The user is logged out and cannot log in anymore.")
+    {
+        return;
+    }
+    perseus::spawn_local_scoped(cx, async move {
+        let res = crate::service::actions::set_user_disabled(
+            &crate::config::CONFIG,
+            &user_id.get(),
+            disabled,
+        )
+        .await;
+        match res {
+            Ok(_) => error.set("".to_string()),
+            Err(err) => error.set(err.to_string()),
+        }
+        reload(cx, user_id, details, error);
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn logout<'a>(
+    cx: Scope<'a>,
+    user_id: &'a Signal<String>,
+    details: &'a Signal<Option<UserDetails>>,
+    error: &'a Signal<String>,
+) {
+    if !confirm("End every session of this user? They have to log in again on all devices.") {
+        return;
+    }
+    perseus::spawn_local_scoped(cx, async move {
+        let res =
+            crate::service::actions::logout_user(&crate::config::CONFIG, &user_id.get()).await;
+        match res {
+            Ok(_) => error.set("".to_string()),
+            Err(err) => error.set(err.to_string()),
+        }
+        reload(cx, user_id, details, error);
+    });
+}
+
+/// Unlike deleting their own passkeys, this also removes the last one of a user.
+#[cfg(target_arch = "wasm32")]
+fn revoke<'a>(
+    cx: Scope<'a>,
+    user_id: &'a Signal<String>,
+    credential: &Credential,
+    details: &'a Signal<Option<UserDetails>>,
+    error: &'a Signal<String>,
+) {
+    let message = format!(
+        "Revoke the passkey \"{}\"? It cannot be used to log in anymore.",
+        credential.nickname
+    );
+    if !confirm(&message) {
+        return;
+    }
+    let id = credential.id.clone();
+    perseus::spawn_local_scoped(cx, async move {
+        let res = crate::service::actions::revoke_user_credential(
+            &crate::config::CONFIG,
+            &user_id.get(),
+            &id,
+        )
+        .await;
+        match res {
+            Ok(_) => error.set("".to_string()),
+            Err(err) => error.set(err.to_string()),
+        }
+        reload(cx, user_id, details, error);
+    });
+}
+
+// Nothing to fetch or click while rendering on the server.
+#[cfg(not(target_arch = "wasm32"))]
+fn query_user_id() -> String {
+    String::new()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn reload<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<String>,
+    _: &'a Signal<Option<UserDetails>>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn toggle_disabled<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<String>,
+    _: &'a Signal<Option<UserDetails>>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn logout<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<String>,
+    _: &'a Signal<Option<UserDetails>>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn revoke<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<String>,
+    _: &Credential,
+    _: &'a Signal<Option<UserDetails>>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[perseus::head]
+pub fn head(cx: Scope) -> View<SsrNode> {
+    view! { cx,
+        title { "WebAuthn - User" }
+    }
+}
+
+pub fn get_template<G: Html>() -> Template<G> {
+    Template::new("admin_user")
+        .template(admin_user_page)
+        .head(head)
+}
@@ -0,0 +1,109 @@
+use crate::{components::Navbar, global_state::*};
+use perseus::prelude::*;
+use sycamore::prelude::*;
+
+/// The target of the link in the login email. Depending on the server the link logs the
+/// user in right away, or only lets them create a passkey for their account.
+#[perseus::template_rx]
+pub fn magic_link_page<'a, G: Html>(cx: Scope<'a>, _: (), app_state: AppStateRx<'a>) -> View<G> {
+    // The user of the link, while it waits for a new passkey.
+    let pending = create_signal(cx, None::<User>);
+    #[cfg(target_arch = "wasm32")]
+    verify(cx, pending, app_state.error);
+
+    let on_create = move |_| {
+        #[cfg(target_arch = "wasm32")]
+        if let Some(user) = pending.get().as_ref().clone() {
+            perseus::spawn_local_scoped(cx, async move {
+                let res = crate::service::actions::register(
+                    &crate::config::CONFIG,
+                    user.name,
+                    user.display_name,
+                    None,
+                )
+                .await;
+                match res {
+                    Ok(_) => {
+                        app_state.error.set("".to_string());
+                        web_sys::window().unwrap().location().set_href("/").unwrap();
+                    }
+                    Err(err) => app_state.error.set(err.to_string()),
+                }
+            });
+        }
+    };
+
+    view! { cx,
+        Navbar(user = app_state.user, error = app_state.error)
+        div (class="hero bg-base-200") {
+            div (class="hero-content flex-col") {
+                div (class="card flex-shrink-0 w-full max-w-sm shadow-2xl bg-base-100") {
+                    div (class="card-body") {
+                        (match pending.get().as_ref() {
+                            Some(user) => {
+                                let greeting = format!("Welcome back {}, create a passkey on this device to log in.", user.display_name);
+                                view!{ cx,
+                                    p { (greeting) }
+                                    div (class="form-control mt-6") {
+                                        button (class="btn btn-primary", on:click=on_create) { "Create passkey" }
+                                    }
+                                }
+                            }
+                            None if *app_state.error.get() == "" => view!{ cx,
+                                p { "Checking your link..." }
+                            },
+                            None => view!{ cx,},
+                        })
+                        (if *app_state.error.get() != "" {
+                            view!{cx,
+                                div (class="alert alert-error shadow-lg mt-6") {
+                                    span { (*app_state.error.get()) }
+                                }
+                            }
+                        } else {
+                            view!{ cx,}
+                        })
+                        a (class="link", href="/") { "Go back" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Trade the token of the link, then go on where the server says.
+#[cfg(target_arch = "wasm32")]
+fn verify<'a>(cx: Scope<'a>, pending: &'a Signal<Option<User>>, error: &'a Signal<String>) {
+    let token = web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+        .and_then(|params| params.get("token"));
+    let token = match token {
+        Some(token) => token,
+        None => {
+            error.set("The link is incomplete, copy all of it from the email.".to_string());
+            return;
+        }
+    };
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::verify_magic_link(&crate::config::CONFIG, token).await {
+            Ok(verified) if verified.logged_in => {
+                let target = verified.redirect_to.unwrap_or_else(|| "/".to_string());
+                web_sys::window().unwrap().location().set_href(&target).unwrap();
+            }
+            Ok(verified) => pending.set(Some(verified.user)),
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+#[perseus::head]
+pub fn head(cx: Scope) -> View<SsrNode> {
+    view! { cx,
+        title { "WebAuthn - Email link" }
+    }
+}
+
+pub fn get_template<G: Html>() -> Template<G> {
+    Template::new("magic_link").template(magic_link_page).head(head)
+}
@@ -1,3 +1,6 @@
+pub mod admin;
+pub mod admin_user;
 pub mod index;
+pub mod magic_link;
 pub mod register;
 pub mod settings;
\ No newline at end of file
@@ -22,18 +22,59 @@ pub fn register_page<'a, G: Html>(
 
     let user_name_entered = create_signal_from_rc(cx, page_state.user_name.get());
     let display_name_entered = create_signal_from_rc(cx, page_state.display_name.get());
+    // Only needed when the server only lets invited users register.
+    let invite_entered = create_signal(cx, String::new());
+    // In privacy mode the server mails a link back to this page with a token.
+    let email_sent = create_signal(cx, false);
+    let token = create_signal(cx, None::<String>);
+    #[cfg(target_arch = "wasm32")]
+    token.set(registration_token());
+    // A user who lost their passkeys registers a new one with a recovery code.
+    let recovering = create_signal(cx, false);
+    let recovery_code_entered = create_signal(cx, String::new());
+    // Shown once after creating an account, instead of going back right away.
+    let recovery_codes = create_signal(cx, Vec::<String>::new());
 
+    let on_recover = move |_| recovering.set(!*recovering.get());
     let on_register = move |_| {
         #[cfg(target_arch = "wasm32")]
         perseus::spawn_local_scoped(cx, async move {
-            let res = crate::service::actions::register(
-                &crate::config::CONFIG,
-                user_name_entered.get().to_string(),
-                display_name_entered.get().to_string(),
-            )
-            .await;
+            let res = match token.get().as_ref() {
+                Some(token) => crate::service::actions::register_verified(
+                    &crate::config::CONFIG,
+                    token.clone(),
+                )
+                .await
+                .map(crate::service::actions::Registration::Completed),
+                None if *recovering.get() => recover(
+                    user_name_entered.get().to_string(),
+                    recovery_code_entered.get().to_string(),
+                )
+                .await,
+                None => {
+                    let invite = invite_entered.get().trim().to_string();
+                    crate::service::actions::register(
+                        &crate::config::CONFIG,
+                        user_name_entered.get().to_string(),
+                        display_name_entered.get().to_string(),
+                        (!invite.is_empty()).then_some(invite),
+                    )
+                    .await
+                }
+            };
             match res {
-                Ok(_) => {
+                Ok(crate::service::actions::Registration::EmailSent) => {
+                    email_sent.set(true);
+                    app_state.error.set("".to_string());
+                    return;
+                }
+                Ok(crate::service::actions::Registration::Completed(codes)) if !codes.is_empty() => {
+                    app_state.reg_state.set(AuthState::Yes);
+                    app_state.error.set("".to_string());
+                    recovery_codes.set(codes);
+                    return;
+                }
+                Ok(crate::service::actions::Registration::Completed(_)) => {
                     app_state.reg_state.set(AuthState::Yes);
                     app_state.error.set("".to_string());
                 }
@@ -55,22 +96,72 @@ pub fn register_page<'a, G: Html>(
             div (class="hero-content flex-col") {
                 div (class="card flex-shrink-0 w-full max-w-sm shadow-2xl bg-base-100") {
                     div (class="card-body") {
-                        div (class="form-control") {
-                            label (class="label"){
-                            span (class="label-text") {"Email"}
+                        (if !recovery_codes.get().is_empty() {
+                            view!{ cx,
+                                p { "Your account is ready. Write down these recovery codes and keep them safe, each of them lets you add a new passkey once if you lose yours. They are only shown this once." }
+                                ul (class="font-mono mt-4") {
+                                    Indexed(
+                                        iterable = recovery_codes,
+                                        view = |cx, code| view! { cx, li { (code) } }
+                                    )
+                                }
+                                a (class="btn btn-primary mt-6", href="/") { "Continue" }
                             }
-                            input (type="text", placeholder="email", class="input input-bordered", bind:value=user_name_entered)
-                        }
-                        div (class="form-control") {
-                            label (class="label"){
-                            span (class="label-text") {"Display name"}
+                        } else if token.get().is_some() {
+                            view!{ cx,
+                                p { "Your email address is verified, create a passkey to finish the registration." }
                             }
-                            input (type="text", placeholder="display name", class="input input-bordered", bind:value=display_name_entered)
-                        }
-                        div (class="form-control mt-6") {
+                        } else if *recovering.get() {
+                            view!{ cx,
+                                div (class="form-control") {
+                                    label (class="label"){
+                                    span (class="label-text") {"Email"}
+                                    }
+                                    input (type="text", placeholder="email", class="input input-bordered", bind:value=user_name_entered)
+                                }
+                                div (class="form-control") {
+                                    label (class="label"){
+                                    span (class="label-text") {"Recovery code"}
+                                    }
+                                    input (type="text", placeholder="XXXX-XXXX-XXXX-XXXX", class="input input-bordered", autocomplete="off", bind:value=recovery_code_entered)
+                                }
+                            }
+                        } else {
+                            view!{ cx,
+                                div (class="form-control") {
+                                    label (class="label"){
+                                    span (class="label-text") {"Email"}
+                                    }
+                                    input (type="text", placeholder="email", class="input input-bordered", bind:value=user_name_entered)
+                                }
+                                div (class="form-control") {
+                                    label (class="label"){
+                                    span (class="label-text") {"Display name"}
+                                    }
+                                    input (type="text", placeholder="display name", class="input input-bordered", bind:value=display_name_entered)
+                                }
+                                div (class="form-control") {
+                                    label (class="label"){
+                                    span (class="label-text") {"Invite code"}
+                                    span (class="label-text-alt") {"if you were invited"}
+                                    }
+                                    input (type="text", placeholder="XXXX-XXXX-XXXX-XXXX", class="input input-bordered", autocomplete="off", bind:value=invite_entered)
+                                }
+                            }
+                        })
+                        div (class="form-control mt-6", hidden=!recovery_codes.get().is_empty()) {
                             button (class="btn btn-primary", on:click=on_register) {
-                                "Register"
+                                (if token.get().is_some() || *recovering.get() { "Create passkey" } else { "Register" })
                             }
+                            (if *email_sent.get() {
+                                view!{cx,
+                                    div (class="alert alert-info shadow-lg mt-6") {
+                                        span { "Check your email, we sent you a link to continue the registration." }
+                                    }
+                                }
+                            } else {
+                                view!{ cx,}
+                            })
                             (if *app_state.error.get() != "" {
                                 view!{cx,
                                     div (class="alert alert-error shadow-lg mt-6") {
@@ -86,6 +177,15 @@ pub fn register_page<'a, G: Html>(
                                 view!{ cx,}
                             })
                         }
+                        (if token.get().is_none() && recovery_codes.get().is_empty() {
+                            view!{ cx,
+                                a (class="link", on:click=on_recover) {
+                                    (if *recovering.get() { "Register a new account" } else { "Lost your passkey?" })
+                                }
+                            }
+                        } else {
+                            view!{ cx,}
+                        })
                         a (class="link", href="/") { "Go back" }
                     }
                 }
@@ -109,6 +209,27 @@ pub fn register_page<'a, G: Html>(
     }
 }
 
+/// Use the recovery code, then register a new passkey under the name and display name
+/// the account already has.
+#[cfg(target_arch = "wasm32")]
+async fn recover(
+    name: String,
+    code: String,
+) -> Result<crate::service::actions::Registration, crate::service::error::ClientError> {
+    let user = crate::service::actions::recover(&crate::config::CONFIG, name, code).await?;
+    crate::service::actions::register(&crate::config::CONFIG, user.name, user.display_name, None)
+        .await
+}
+
+/// The token of the link in the verification email, if the page was opened from it.
+#[cfg(target_arch = "wasm32")]
+fn registration_token() -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    web_sys::UrlSearchParams::new_with_str(&search)
+        .ok()?
+        .get("token")
+}
+
 #[perseus::head]
 pub fn head(cx: Scope) -> View<SsrNode> {
     view! { cx,
@@ -1,6 +1,7 @@
 use crate::{
     components::{Authorized, Navbar},
     global_state::*,
+    utils::time::format_unix,
 };
 use perseus::prelude::*;
 use sycamore::prelude::*;
@@ -9,6 +10,27 @@ use sycamore::prelude::*;
 pub fn settings_page<'a, G: Html>(cx: Scope<'a>, _: (), app_state: AppStateRx<'a>) -> View<G> {
     #[cfg(target_arch = "wasm32")]
     AppStateRx::load_identity_state(&app_state, cx);
+
+    let credentials = create_signal(cx, Vec::<Credential>::new());
+    let error = app_state.error;
+    reload(cx, credentials, error);
+
+    let on_add = move |_| add_passkey(cx, app_state.user, credentials, error);
+
+    let name = create_signal(cx, String::new());
+    let display_name = create_signal(cx, String::new());
+    create_effect(cx, move || {
+        if let Some(user) = app_state.user.get().as_ref() {
+            name.set(user.name.clone());
+            display_name.set(user.display_name.clone());
+        }
+    });
+    let on_save = move |_| save_profile(cx, app_state.user, name, display_name, error);
+
+    let recovery_codes = create_signal(cx, RecoveryCodes::default());
+    load_recovery_codes(cx, recovery_codes, error);
+    let on_regenerate = move |_| regenerate_recovery_codes(cx, recovery_codes, error);
+
     let unauthorized = view! { cx,
         a(class="link", href="/") { "Go back "}
     };
@@ -16,14 +38,336 @@ pub fn settings_page<'a, G: Html>(cx: Scope<'a>, _: (), app_state: AppStateRx<'a
         Navbar(user = app_state.user, error = app_state.error)
         Authorized(user = app_state.user, unauthorized = Some(unauthorized)) {
             div (class="hero min-h-[60vh] bg-base-200") {
-                div (class="hero-content flex-col") {
-                    "todo"
+                div (class="hero-content flex-col w-full max-w-4xl") {
+                    h1(class="text-3xl font-bold") { "Profile" }
+                    div (class="card w-full max-w-sm shadow-2xl bg-base-100") {
+                        div (class="card-body") {
+                            div (class="form-control") {
+                                label (class="label") {
+                                    span (class="label-text") { "Username" }
+                                }
+                                input (type="text", class="input input-bordered", bind:value=name)
+                            }
+                            div (class="form-control") {
+                                label (class="label") {
+                                    span (class="label-text") { "Display name" }
+                                }
+                                input (type="text", class="input input-bordered", bind:value=display_name)
+                            }
+                            div (class="form-control mt-6") {
+                                button (class="btn btn-primary", on:click=on_save) { "Save" }
+                            }
+                        }
+                    }
+                    h1(class="text-3xl font-bold") { "Passkeys" }
+                    div (class="overflow-x-auto w-full") {
+                        table (class="table w-full") {
+                            thead {
+                                tr {
+                                    th { "Name" }
+                                    th { "Created" }
+                                    th { "Last used" }
+                                    th { "Synced" }
+                                    th {}
+                                }
+                            }
+                            tbody {
+                                Indexed(
+                                    iterable = credentials,
+                                    view = move |cx, credential| {
+                                        let last_used = credential
+                                            .last_used_at
+                                            .map(format_unix)
+                                            .unwrap_or_else(|| "never".to_string());
+                                        let synced = match (credential.backup_eligible, credential.backup_state) {
+                                            (_, true) => "yes",
+                                            (true, false) => "not yet",
+                                            (false, false) => "no",
+                                        };
+                                        let is_last = credentials.get().len() <= 1;
+                                        let on_rename = {
+                                            let credential = credential.clone();
+                                            move |_| rename(cx, &credential, credentials, error)
+                                        };
+                                        let on_delete = {
+                                            let credential = credential.clone();
+                                            move |_| delete(cx, &credential, credentials, error)
+                                        };
+                                        view! { cx,
+                                            tr {
+                                                td { (credential.nickname) }
+                                                td { (format_unix(credential.created_at)) }
+                                                td { (last_used) }
+                                                td { (synced) }
+                                                td {
+                                                    div (class="btn-group") {
+                                                        button (class="btn btn-sm", on:click=on_rename) { "Rename" }
+                                                        button (class="btn btn-sm btn-error", disabled=is_last, on:click=on_delete) { "Delete" }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                )
+                            }
+                        }
+                    }
+                    button (class="btn btn-primary", on:click=on_add) { "Add another passkey" }
+                    h1(class="text-3xl font-bold") { "Recovery codes" }
+                    div (class="card w-full max-w-sm shadow-2xl bg-base-100") {
+                        div (class="card-body") {
+                            p { (format!("{} of your recovery codes are left, each of them adds a new passkey once if you lose yours.", recovery_codes.get().remaining)) }
+                            (if recovery_codes.get().codes.is_empty() {
+                                view!{ cx,}
+                            } else {
+                                view!{ cx,
+                                    p { "Write down your new codes, they are only shown this once." }
+                                    ul (class="font-mono") {
+                                        Indexed(
+                                            iterable = create_memo(cx, || recovery_codes.get().codes.clone()),
+                                            view = |cx, code| view! { cx, li { (code) } }
+                                        )
+                                    }
+                                }
+                            })
+                            div (class="form-control mt-6") {
+                                button (class="btn", on:click=on_regenerate) { "Generate new codes" }
+                            }
+                        }
+                    }
+                    (if *error.get() != "" {
+                        view!{cx,
+                            div (class="alert alert-error shadow-lg mt-6") {
+                                div {
+                                    svg(xmlns="http://www.w3.org/2000/svg", class="stroke-current flex-shrink-0 h-6 w-6", fill="none", viewBox="0 0 24 24") {
+                                        path(stroke-linecap="round", stroke-linejoin="round", stroke-width="2", d="M10 14l2-2m0 0l2-2m-2 2l-2-2m2 2l2 2m7-2a9 9 0 11-18 0 9 9 0 0118 0z")
+                                    }
+                                    span { (*error.get()) }
+                                }
+                            }
+                        }
+                    } else {
+                        view!{ cx,}
+                    })
                 }
             }
         }
     }
 }
 
+/// The number of unused recovery codes, and the codes themselves right after generating
+/// them.
+#[derive(Clone, Default)]
+struct RecoveryCodes {
+    remaining: usize,
+    codes: Vec<String>,
+}
+
+#[cfg(target_arch = "wasm32")]
+fn report<T>(error: &Signal<String>, res: Result<T, crate::service::error::ClientError>) {
+    match res {
+        Ok(_) => error.set("".to_string()),
+        Err(err) => error.set(err.to_string()),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn reload<'a>(cx: Scope<'a>, credentials: &'a Signal<Vec<Credential>>, error: &'a Signal<String>) {
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::list_credentials(&crate::config::CONFIG).await {
+            Ok(list) => credentials.set(list),
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+/// Registering the name of the logged in user adds a passkey to the account.
+#[cfg(target_arch = "wasm32")]
+fn add_passkey<'a>(
+    cx: Scope<'a>,
+    user: &'a Signal<Option<User>>,
+    credentials: &'a Signal<Vec<Credential>>,
+    error: &'a Signal<String>,
+) {
+    let user = match user.get().as_ref() {
+        Some(user) => user.clone(),
+        None => return,
+    };
+    perseus::spawn_local_scoped(cx, async move {
+        let res = crate::service::actions::register(
+            &crate::config::CONFIG,
+            user.name,
+            user.display_name,
+            None,
+        )
+        .await;
+        report(error, res);
+        reload(cx, credentials, error);
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_profile<'a>(
+    cx: Scope<'a>,
+    user: &'a Signal<Option<User>>,
+    name: &'a Signal<String>,
+    display_name: &'a Signal<String>,
+    error: &'a Signal<String>,
+) {
+    let name = name.get().trim().to_string();
+    let display_name = display_name.get().trim().to_string();
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::update_profile(&crate::config::CONFIG, name, display_name)
+            .await
+        {
+            Ok(updated) => {
+                error.set("".to_string());
+                user.set(Some(updated));
+            }
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn rename<'a>(
+    cx: Scope<'a>,
+    credential: &Credential,
+    credentials: &'a Signal<Vec<Credential>>,
+    error: &'a Signal<String>,
+) {
+    let nickname = web_sys::window()
+        .and_then(|w| {
+            w.prompt_with_message_and_default("Name of the passkey", &credential.nickname)
+                .ok()
+        })
+        .flatten();
+    let nickname = match nickname {
+        Some(nickname) => nickname,
+        None => return,
+    };
+    let id = credential.id.clone();
+    perseus::spawn_local_scoped(cx, async move {
+        let res =
+            crate::service::actions::rename_credential(&crate::config::CONFIG, &id, nickname).await;
+        report(error, res);
+        reload(cx, credentials, error);
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn delete<'a>(
+    cx: Scope<'a>,
+    credential: &Credential,
+    credentials: &'a Signal<Vec<Credential>>,
+    error: &'a Signal<String>,
+) {
+    let message = format!(
+        "Delete the passkey \"{}\"? It cannot be used to log in anymore.",
+        credential.nickname
+    );
+    let confirmed = web_sys::window()
+        .and_then(|w| w.confirm_with_message(&message).ok())
+        .unwrap_or(false);
+    if !confirmed {
+        return;
+    }
+    let id = credential.id.clone();
+    perseus::spawn_local_scoped(cx, async move {
+        let res = crate::service::actions::delete_credential(&crate::config::CONFIG, &id).await;
+        report(error, res);
+        reload(cx, credentials, error);
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_recovery_codes<'a>(
+    cx: Scope<'a>,
+    recovery_codes: &'a Signal<RecoveryCodes>,
+    error: &'a Signal<String>,
+) {
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::get_recovery_codes(&crate::config::CONFIG).await {
+            Ok(codes) => recovery_codes.set(RecoveryCodes {
+                remaining: codes.remaining,
+                codes: Vec::new(),
+            }),
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn regenerate_recovery_codes<'a>(
+    cx: Scope<'a>,
+    recovery_codes: &'a Signal<RecoveryCodes>,
+    error: &'a Signal<String>,
+) {
+    let confirmed = web_sys::window()
+        .and_then(|w| {
+            w.confirm_with_message("Generate new recovery codes? The old ones stop working.")
+                .ok()
+        })
+        .unwrap_or(false);
+    if !confirmed {
+        return;
+    }
+    perseus::spawn_local_scoped(cx, async move {
+        match crate::service::actions::regenerate_recovery_codes(&crate::config::CONFIG).await {
+            Ok(codes) => {
+                error.set("".to_string());
+                recovery_codes.set(RecoveryCodes {
+                    remaining: codes.remaining,
+                    codes: codes.recovery_codes,
+                });
+            }
+            Err(err) => error.set(err.to_string()),
+        }
+    });
+}
+
+// Nothing to fetch or click while rendering on the server.
+#[cfg(not(target_arch = "wasm32"))]
+fn reload<'a>(_: Scope<'a>, _: &'a Signal<Vec<Credential>>, _: &'a Signal<String>) {}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn add_passkey<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<Option<User>>,
+    _: &'a Signal<Vec<Credential>>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_profile<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<Option<User>>,
+    _: &'a Signal<String>,
+    _: &'a Signal<String>,
+    _: &'a Signal<String>,
+) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn rename<'a>(_: Scope<'a>, _: &Credential, _: &'a Signal<Vec<Credential>>, _: &'a Signal<String>) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn delete<'a>(_: Scope<'a>, _: &Credential, _: &'a Signal<Vec<Credential>>, _: &'a Signal<String>) {
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_recovery_codes<'a>(_: Scope<'a>, _: &'a Signal<RecoveryCodes>, _: &'a Signal<String>) {}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn regenerate_recovery_codes<'a>(
+    _: Scope<'a>,
+    _: &'a Signal<RecoveryCodes>,
+    _: &'a Signal<String>,
+) {
+}
+
 #[perseus::head]
 pub fn head(cx: Scope) -> View<SsrNode> {
     view! { cx,
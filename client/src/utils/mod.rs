@@ -2,3 +2,4 @@
 pub mod group;
 #[cfg(target_arch = "wasm32")]
 pub mod log;
+pub mod time;
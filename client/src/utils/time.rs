@@ -0,0 +1,12 @@
+/// Render unix seconds in the locale of the browser.
+#[cfg(target_arch = "wasm32")]
+pub fn format_unix(seconds: i64) -> String {
+    let date = js_sys::Date::new(&wasm_bindgen::JsValue::from_f64(seconds as f64 * 1000.0));
+    String::from(date.to_locale_string("default", &wasm_bindgen::JsValue::UNDEFINED))
+}
+
+/// The server has no idea about the locale of the user, so leave that to the browser.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn format_unix(seconds: i64) -> String {
+    seconds.to_string()
+}
@@ -1,14 +1,27 @@
+use crate::admin::*;
+use crate::api_tokens::*;
 use crate::auth::*;
+use crate::config::MagicLinkMode;
 use crate::errors::MyError;
+use crate::magic_link::*;
 use crate::models::*;
+use crate::oidc::*;
+use crate::openapi::ApiDoc;
+use crate::rate_limit::RateLimit;
+use crate::recovery::*;
+use crate::registration_policy;
+use crate::roles::{first_is_admin, initial_roles, Admin, RequireRole};
 use actix_identity::Identity;
 use actix_session::Session;
-use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse};
-use log::info;
+use actix_web::http::header;
+use actix_web::{delete, get, post, put, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use uuid::Uuid;
 use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
 
+#[utoipa::path(responses((status = 200, description = "A greeting naming the logged in user", body = String, content_type = "text/plain")))]
 #[get("/")]
 async fn index(identity: Option<Identity>) -> Result<HttpResponse, MyError> {
     let body = match identity
@@ -22,59 +35,296 @@ async fn index(identity: Option<Identity>) -> Result<HttpResponse, MyError> {
     Ok(HttpResponse::Ok().body(body))
 }
 
+fn identity_to_id(identity: Option<Identity>) -> Result<Uuid, MyError> {
+    let id = identity
+        .ok_or(MyError::Unauthenticated)?
+        .id()
+        .map_err(|_| MyError::Unauthenticated)?;
+    Uuid::parse_str(&id).map_err(|e| {
+        anyhow::Error::msg(format!("Failed to parse user unique identity {}", e)).into()
+    })
+}
+
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The logged in user", body = User),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The user has been removed", body = Problem, content_type = "application/problem+json"),
+    )
+)]
 #[get("/identity")]
 async fn get_identity(
-    identity: Identity,
+    authenticated: AuthenticatedUser,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse, MyError> {
-    let id = identity
-        .id()
-        .map_err(|e| anyhow::Error::msg(format!("Failed to get identity {}", e)))?;
-    let user_unique_id = Uuid::parse_str(&id)
-        .map_err(|e| anyhow::Error::msg(format!("Failed to parse user unique identity {}", e)))?;
-    let users_guard = state.users.lock().await;
-    let user = get_user(&users_guard, user_unique_id)?;
+    let user_unique_id = authenticated.unique_id;
+    let user = get_user(&state, user_unique_id).await?;
 
     Ok(HttpResponse::Ok().json(user))
 }
 
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Change the username and display name of the logged in user, the unique id stays the same.
+/// In privacy mode the username is the verified email address, only the display name can
+/// be changed then.
+#[utoipa::path(
+    request_body = UserRegistration,
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The changed user", body = User),
+        (status = 400, description = "The name or display name is empty or too long, or the name changes in privacy mode", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The registration policy does not allow the name", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Another user has this name", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/profile")]
+async fn update_user_profile(
+    authenticated: AuthenticatedUser,
+    profile: web::Json<UserRegistration>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    let name = profile.name.trim();
+    let display_name = profile.display_name.trim();
+    for (field, value) in [("name", name), ("display name", display_name)] {
+        if value.is_empty() || value.chars().count() > MAX_NAME_LENGTH {
+            return Err(MyError::InvalidInput(format!(
+                "The {} must have between 1 and {} characters",
+                field, MAX_NAME_LENGTH
+            )));
+        }
+    }
+    info!(
+        "Update profile {} to {} {}",
+        user_unique_id, name, display_name
+    );
+    registration_policy::check_domain(&state.config.registration, name)?;
+    // A new address would skip the verification, and its answer tell whether it is taken.
+    if state.config.privacy_mode && get_user(&state, user_unique_id).await?.name != name {
+        return Err(MyError::InvalidInput(
+            "The username is your verified email address and cannot be changed".to_string(),
+        ));
+    }
+
+    match update_profile(&state, user_unique_id, name, display_name).await? {
+        ProfileUpdate::Updated(user) => Ok(HttpResponse::Ok().json(user)),
+        ProfileUpdate::NotFound => Err(MyError::UserNotFound),
+        ProfileUpdate::NameTaken => Err(MyError::UsernameTaken),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub(crate) struct CredentialRename {
+    pub nickname: String,
+}
+
+/// The passkeys of the logged in user.
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The passkeys of the logged in user", body = [CredentialInfo]),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/credentials")]
+async fn get_credentials(
+    authenticated: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    let credentials: Vec<CredentialInfo> = list_credentials(&state, user_unique_id)
+        .await?
+        .iter()
+        .map(CredentialInfo::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(credentials))
+}
+
+#[utoipa::path(
+    params(("cred_id" = String, Path, description = "The base64url encoded credential id")),
+    request_body = CredentialRename,
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "Renamed"),
+        (status = 400, description = "The nickname is empty or too long", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The user has no such passkey", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/credentials/{cred_id}")]
+async fn rename_credential(
+    authenticated: AuthenticatedUser,
+    cred_id: web::Path<String>,
+    rename: web::Json<CredentialRename>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    let nickname = rename.nickname.trim();
+    if nickname.is_empty() || nickname.chars().count() > MAX_NAME_LENGTH {
+        return Err(MyError::InvalidInput(format!(
+            "The nickname must have between 1 and {} characters",
+            MAX_NAME_LENGTH
+        )));
+    }
+    info!("Rename credential {} to {}", cred_id, nickname);
+
+    if set_credential_nickname(&state, user_unique_id, &cred_id, nickname).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(MyError::CredentialNotFound)
+    }
+}
+
+#[utoipa::path(
+    params(("cred_id" = String, Path, description = "The base64url encoded credential id")),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The user has no such passkey", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "It is the last passkey of the user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/credentials/{cred_id}")]
+async fn delete_credential(
+    authenticated: AuthenticatedUser,
+    cred_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    info!("Delete credential {}", cred_id);
+
+    match remove_credential(&state, user_unique_id, &cred_id).await? {
+        CredentialRemoval::Removed => Ok(HttpResponse::NoContent().finish()),
+        CredentialRemoval::NotFound => Err(MyError::CredentialNotFound),
+        CredentialRemoval::LastCredential => Err(MyError::LastCredential),
+    }
+}
+
+/// Session counts for monitoring, only for administrators.
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Session counts", body = SessionStats),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/stats/sessions")]
+async fn get_session_stats(
+    _admin: RequireRole<Admin>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let stats = state.sessions.stats().await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Counters, latencies and gauges in the Prometheus text format. Only for administrators,
+/// unless `public_metrics` is enabled.
+#[utoipa::path(
+    security((), ("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/metrics")]
+async fn get_metrics(
+    request: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    if !state.config.public_metrics {
+        RequireRole::<Admin>::extract(&request).await?;
+    }
+    let body = state.metrics.render(&state).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body))
+}
+
+/// This API as an OpenAPI 3 document.
+#[utoipa::path(responses((status = 200, description = "OpenAPI 3 document", body = Object)))]
+#[get("/openapi.json")]
+async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    security(("session_cookie" = [])),
+    responses((status = 303, description = "Logged out, redirects to `redirect_logout`"))
+)]
 #[get("/logout")]
-async fn logout(identity: Identity) -> Result<HttpResponse, MyError> {
+async fn logout(
+    request: HttpRequest,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let mut event = audit_event(&state, &request, AuditAction::Logout);
+    event.user_id = identity.id().ok().and_then(|id| Uuid::parse_str(&id).ok());
     identity.logout();
+    record_audit_event(&state, event, &Ok(())).await;
     Ok(HttpResponse::SeeOther()
-        .insert_header(("LOCATION", "https://localhost:8443/"))
+        .insert_header(("LOCATION", state.config.redirect_logout.as_str()))
         .finish())
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct UserRegistration {
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub(crate) struct UserRegistration {
     pub name: String,
     pub display_name: String,
+    /// Invite code, required for new accounts when registration is by invitation only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
 }
 
-fn is_same_user(user_unique_id: Uuid, identity: &Identity) -> bool {
-    user_unique_id == Uuid::parse_str(&identity.id().unwrap()).unwrap()
+/// The user a registration adds a passkey to: the logged in user, or the one the session
+/// recovers with a recovery code.
+async fn current_user(
+    state: &AppState,
+    identity: Option<Identity>,
+    session: &Session,
+) -> Result<Option<Uuid>, MyError> {
+    let logged_in = identity
+        .and_then(|identity| identity.id().ok())
+        .and_then(|id| Uuid::parse_str(&id).ok());
+    match logged_in {
+        Some(user_id) => Ok(Some(user_id)),
+        None => Ok(recovering_user(state, session).await?),
+    }
 }
 
 fn is_username_available(
     user_unique_id: Option<Uuid>,
-    identity: Option<Identity>,
-) -> anyhow::Result<Uuid> {
-    match (user_unique_id, identity) {
-        // case: user_name is taken by and currently user is anonymous
-        (Some(_), None) => Err(anyhow::Error::msg("Username is already taken")),
-        // case: user_name is taken by another user than the currently logged in user
-        (Some(user_unique_id), Some(identity)) if !is_same_user(user_unique_id, &identity) => {
-            Err(anyhow::Error::msg("Username is already taken"))
+    current_user: Option<Uuid>,
+) -> Result<Uuid, MyError> {
+    match (user_unique_id, current_user) {
+        // case: user_name is taken by the current user
+        (Some(user_unique_id), Some(current_user)) if user_unique_id == current_user => {
+            Ok(user_unique_id)
         }
-        // case: user_name is taken by currently logged in user
-        (Some(user_unique_id), Some(_)) => Ok(user_unique_id),
+        // case: user_name is taken by another user or the current user is anonymous
+        (Some(_), _) => Err(MyError::UsernameTaken),
         // case: user_name is unused
         (None, _) => Ok(Uuid::new_v4()),
     }
 }
 
-#[post("/register_start")]
+#[utoipa::path(
+    request_body = UserRegistration,
+    responses(
+        (status = 200, description = "The options for `navigator.credentials.create()`", body = CreationChallengeResponse),
+        (status = 202, description = "Privacy mode, an email with a link to continue has been sent"),
+        (status = 403, description = "The registration policy does not allow this new account", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The name is taken by another user", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/register_start", wrap = "RateLimit")]
 async fn register_start(
     user_registration: web::Json<UserRegistration>,
     state: web::Data<AppState>,
@@ -83,24 +333,125 @@ async fn register_start(
 ) -> Result<HttpResponse, MyError> {
     info!("Start register {:?}", user_registration);
 
-    clear_reg_state(&session);
+    let result = async {
+        clear_reg_state(&session);
+        registration_policy::clear_invite(&session);
 
-    let users_guard = state.users.lock().await;
-    let user_unique_id = name_to_id(&users_guard, &user_registration.name);
-    let user_unique_id = is_username_available(user_unique_id, identity)?;
-    let user = User {
-        unique_id: user_unique_id,
-        name: user_registration.name.to_string(),
-        display_name: user_registration.display_name.to_string(),
-    };
-    let exclude_credentials = get_existing_credentials(&users_guard, user.unique_id);
-    let (ccr, reg_state) = start_passkey_registration(&state, &user, exclude_credentials)?;
-    insert_reg_state(&session, &user, &reg_state)?;
+        let user_unique_id = name_to_id(&state, &user_registration.name).await?;
+        let current_user = current_user(&state, identity, &session).await?;
+        let is_own_name = user_unique_id.is_some() && user_unique_id == current_user;
+        let invite = user_registration.invite.as_deref();
+        // Answer the same whether the name is taken or not, only its owner learns which.
+        // The policy is checked for taken names as well, so it does not tell them apart.
+        if state.config.privacy_mode && !is_own_name {
+            registration_policy::check(&state, &user_registration.name, invite).await?;
+            send_registration_email(
+                &state,
+                &user_registration.name,
+                &user_registration.display_name,
+                invite,
+                user_unique_id.is_some(),
+            )
+            .await?;
+            return Ok(HttpResponse::Accepted().finish());
+        }
+        let user_unique_id = is_username_available(user_unique_id, current_user)?;
+        // Only new accounts are subject to the policy, existing users add passkeys freely.
+        if !is_own_name {
+            registration_policy::check(&state, &user_registration.name, invite).await?;
+            registration_policy::insert_invite(&session, invite)?;
+        }
+        let user = User {
+            unique_id: user_unique_id,
+            name: user_registration.name.to_string(),
+            display_name: user_registration.display_name.to_string(),
+            created_at: unix_now(),
+            roles: vec![],
+            disabled: false,
+        };
+        start_registration(&state, &session, &user).await
+    }
+    .await;
+    state.metrics.ceremony("register", "start", &result);
+    result
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub(crate) struct RegistrationVerification {
+    pub token: String,
+}
+
+/// Continue a registration in privacy mode with the token from the verification email.
+#[utoipa::path(
+    request_body = RegistrationVerification,
+    responses(
+        (status = 200, description = "The options for `navigator.credentials.create()`", body = CreationChallengeResponse),
+        (status = 400, description = "The token is invalid or has expired", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The invite has been used or the policy changed in the meantime", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The name has been taken in the meantime", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/register_verify", wrap = "RateLimit")]
+async fn register_verify(
+    verification: web::Json<RegistrationVerification>,
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<HttpResponse, MyError> {
+    info!("Verify register");
+
+    let result = async {
+        clear_reg_state(&session);
+        registration_policy::clear_invite(&session);
+
+        let registration = state
+            .privacy
+            .verify(&verification.token)
+            .ok_or(MyError::InvalidToken)?;
+        // The address is verified, so telling a taken name apart is fine now.
+        if name_to_id(&state, &registration.name).await?.is_some() {
+            return Err(MyError::UsernameTaken);
+        }
+        let invite = registration.invite.as_deref();
+        registration_policy::check(&state, &registration.name, invite).await?;
+        registration_policy::insert_invite(&session, invite)?;
+        let user = User {
+            unique_id: Uuid::new_v4(),
+            name: registration.name,
+            display_name: registration.display_name,
+            created_at: unix_now(),
+            roles: vec![],
+            disabled: false,
+        };
+        start_registration(&state, &session, &user).await
+    }
+    .await;
+    state.metrics.ceremony("register", "verify", &result);
+    result
+}
+
+async fn start_registration(
+    state: &AppState,
+    session: &Session,
+    user: &User,
+) -> Result<HttpResponse, MyError> {
+    let exclude_credentials = get_existing_credentials(state, user.unique_id).await;
+    let (ccr, reg_state) = start_passkey_registration(state, user, exclude_credentials)?;
+    insert_reg_state(session, user, &reg_state)?;
 
     Ok(HttpResponse::Ok().json(&ccr))
 }
 
-#[post("/register_finish")]
+#[utoipa::path(
+    request_body = RegisterPublicKeyCredential,
+    responses(
+        (status = 200, description = "Registered and logged in, new accounts get their recovery codes", body = Registered),
+        (status = 400, description = "No registration in progress or the credential is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The authenticator is not trusted, the invite has been used in the meantime or the account has been disabled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/register_finish", wrap = "RateLimit")]
 async fn register_finish(
     request: HttpRequest,
     reg: web::Json<RegisterPublicKeyCredential>,
@@ -109,44 +460,111 @@ async fn register_finish(
 ) -> Result<HttpResponse, MyError> {
     info!("Finish register");
 
-    let (user, reg_state) = get_reg_state(&session)?;
+    let mut event = audit_event(&state, &request, AuditAction::Register);
+    event.credential_id = Some(reg.id.clone());
+    let result = async {
+        let (mut user, reg_state) = get_reg_state(&session)?;
+        event.user_id = Some(user.unique_id);
 
-    session
-        .remove("reg_state")
-        .ok_or_else(|| anyhow::Error::msg("Cannot remove reg_state"))?;
+        session.remove("reg_state").ok_or(MyError::SessionState)?;
 
-    let sk = finish_passkey_registration(&state, &reg, &reg_state)?;
-    insert_user(&state, &user, sk).await;
+        let credential = finish_passkey_registration(&state, &reg, &reg_state)?;
+        let existing = state.users.get_user(user.unique_id).await?;
+        // The account may have been disabled while the session was recovering it.
+        if let Some(existing) = &existing {
+            require_enabled(existing)?;
+        }
+        let is_new_account = existing.is_none();
+        let mut invite = None;
+        if is_new_account {
+            invite = registration_policy::take_invite(&state, &session)?;
+            user.roles = initial_roles(&state.config, &user.name);
+        }
+        let first_is_admin = first_is_admin(&state.config);
+        insert_user(&state, &user, credential, invite.as_deref(), first_is_admin).await?;
+        // An existing user keeps the profile it already had.
+        let user = get_user(&state, user.unique_id).await?;
+        let recovery_codes = if is_new_account {
+            Some(regenerate_codes(&state, user.unique_id).await?)
+        } else {
+            None
+        };
+        clear_recovery(&session);
 
-    Identity::login(&request.extensions(), user.unique_id.to_string())
-        .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
-    Ok(HttpResponse::Ok().json(user))
+        Identity::login(&request.extensions(), user.unique_id.to_string())
+            .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
+        Ok(Registered {
+            user,
+            recovery_codes,
+        })
+    }
+    .await;
+    state.metrics.ceremony("register", "finish", &result);
+    record_audit_event(&state, event, &result).await;
+    let registered = result?;
+    insert_token_grant(&session, registered.user.unique_id)?;
+    Ok(HttpResponse::Ok().json(registered))
 }
 
-#[post("/login_start")]
+#[utoipa::path(
+    request_body(content = String, description = "The username", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "The options for `navigator.credentials.get()`", body = RequestChallengeResponse),
+        (status = 404, description = "Unknown user, never in privacy mode", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/login_start", wrap = "RateLimit")]
 async fn login_start(
     username: String,
     state: web::Data<AppState>,
     session: Session,
 ) -> Result<HttpResponse, MyError> {
     info!("Start Authentication {}", username);
-    clear_auth_state(&session);
 
-    let users_guard = state.users.lock().await;
-    let user_unique_id =
-        name_to_id(&users_guard, &username).ok_or_else(|| anyhow::Error::msg("User not found"))?;
+    let result = async {
+        clear_auth_state(&session);
 
-    let allow_credentials = get_allowed_credentials(&users_guard, user_unique_id)?;
+        let user_unique_id = name_to_id(&state, &username).await?;
+        let (user_unique_id, allow_credentials) = if state.config.privacy_mode {
+            // Unknown users get a decoy passkey after the same lookups as everybody else, the
+            // login then fails in `login_finish` like any wrong passkey would.
+            let user_unique_id = user_unique_id.unwrap_or_else(Uuid::nil);
+            let mut allow_credentials = state.users.get_credentials(user_unique_id).await?;
+            if allow_credentials.is_empty() {
+                allow_credentials = vec![state.privacy.decoy_passkey(&username)?];
+            }
+            (user_unique_id, allow_credentials)
+        } else {
+            let user_unique_id = user_unique_id.ok_or(MyError::UserNotFound)?;
+            (
+                user_unique_id,
+                get_allowed_credentials(&state, user_unique_id).await?,
+            )
+        };
 
-    let (rcr, auth_state) = start_passkey_authentication(&state, allow_credentials)?;
+        let (rcr, auth_state) = start_passkey_authentication(&state, &allow_credentials)?;
 
-    drop(users_guard);
-    insert_auth_state(&session, user_unique_id, &auth_state)?;
+        insert_auth_state(&session, user_unique_id, &auth_state)?;
 
-    Ok(HttpResponse::Ok().json(rcr))
+        Ok(HttpResponse::Ok().json(rcr))
+    }
+    .await;
+    state.metrics.ceremony("login", "start", &result);
+    result
 }
 
-#[post("/login_finish")]
+#[utoipa::path(
+    request_body = PublicKeyCredential,
+    responses(
+        (status = 200, description = "Logged in, follow `redirect_to` if set", body = LoggedIn),
+        (status = 400, description = "No login in progress", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "The assertion is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/login_finish", wrap = "RateLimit")]
 async fn login_finish(
     request: HttpRequest,
     auth: web::Json<PublicKeyCredential>,
@@ -155,15 +573,740 @@ async fn login_finish(
 ) -> Result<HttpResponse, MyError> {
     info!("Finish Authentication");
 
-    let (user_unique_id, auth_state) = get_auth_state(&session)?;
-    clear_auth_state(&session);
-    let auth_result = finish_passkey_authentication(&state, &auth, &auth_state)?;
-    update_credential(&state, user_unique_id, &auth_result).await?;
+    let mut event = audit_event(&state, &request, AuditAction::Login);
+    event.credential_id = Some(auth.id.clone());
+    let result = async {
+        let (user_unique_id, auth_state) = get_auth_state(&session)?;
+        // Unknown users of the privacy mode log in as the nil uuid.
+        event.user_id = Some(user_unique_id).filter(|id| !id.is_nil());
+        clear_auth_state(&session);
+        let auth_result = finish_passkey_authentication(&state, &auth, &auth_state)?;
+        let user = get_user(&state, user_unique_id).await?;
+        // Disabled accounts leave no trace on their passkeys either.
+        require_enabled(&user)?;
+        update_credential(&state, user_unique_id, &auth_result).await?;
 
-    let users_guard = state.users.lock().await;
-    let user = get_user(&users_guard, user_unique_id)?;
+        Identity::login(&request.extensions(), user_unique_id.to_string())
+            .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
+        Ok(user)
+    }
+    .await;
+    state.metrics.ceremony("login", "finish", &result);
+    record_audit_event(&state, event, &result).await;
+    let user = result?;
+    insert_token_grant(&session, user.unique_id)?;
+    let redirect_to = complete_pending_authorization(&state, &session, user.unique_id)?;
+    Ok(HttpResponse::Ok().json(LoggedIn { user, redirect_to }))
+}
 
-    Identity::login(&request.extensions(), user_unique_id.to_string())
-        .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
-    Ok(HttpResponse::Ok().json(user))
+/// Start a login without a username, used for passkey autofill.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The options for `navigator.credentials.get()`", body = RequestChallengeResponse),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/login_discoverable_start", wrap = "RateLimit")]
+async fn login_discoverable_start(
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<HttpResponse, MyError> {
+    info!("Start discoverable Authentication");
+
+    let result = async {
+        clear_discoverable_auth_state(&session);
+
+        let (rcr, auth_state) = start_discoverable_authentication(&state)?;
+
+        insert_discoverable_auth_state(&session, &auth_state)?;
+
+        Ok(HttpResponse::Ok().json(rcr))
+    }
+    .await;
+    state
+        .metrics
+        .ceremony("login_discoverable", "start", &result);
+    result
+}
+
+#[utoipa::path(
+    request_body = PublicKeyCredential,
+    responses(
+        (status = 200, description = "Logged in, follow `redirect_to` if set", body = LoggedIn),
+        (status = 400, description = "No login in progress", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "The assertion is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/login_discoverable_finish", wrap = "RateLimit")]
+async fn login_discoverable_finish(
+    request: HttpRequest,
+    auth: web::Json<PublicKeyCredential>,
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<HttpResponse, MyError> {
+    info!("Finish discoverable Authentication");
+
+    let mut event = audit_event(&state, &request, AuditAction::Login);
+    event.credential_id = Some(auth.id.clone());
+    let result = async {
+        let auth_state = get_discoverable_auth_state(&session)?;
+        clear_discoverable_auth_state(&session);
+        let user_unique_id = identify_discoverable_authentication(&state, &auth)?;
+        let credentials = get_allowed_credentials(&state, user_unique_id).await?;
+        let auth_result =
+            finish_discoverable_authentication(&state, &auth, auth_state, &credentials)?;
+        // The user handle is chosen by the client, so only a verified passkey says whose
+        // log the attempt belongs in.
+        event.user_id = Some(user_unique_id);
+        let user = get_user(&state, user_unique_id).await?;
+        require_enabled(&user)?;
+        update_credential(&state, user_unique_id, &auth_result).await?;
+
+        Identity::login(&request.extensions(), user_unique_id.to_string())
+            .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
+        Ok(user)
+    }
+    .await;
+    state
+        .metrics
+        .ceremony("login_discoverable", "finish", &result);
+    record_audit_event(&state, event, &result).await;
+    let user = result?;
+    insert_token_grant(&session, user.unique_id)?;
+    let redirect_to = complete_pending_authorization(&state, &session, user.unique_id)?;
+    Ok(HttpResponse::Ok().json(LoggedIn { user, redirect_to }))
+}
+
+/// Trade the session of a registration or login that finished within the last five
+/// minutes for bearer tokens, once per ceremony. For API clients that cannot keep the
+/// session cookie.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Access and refresh token", body = BearerTokens),
+        (status = 401, description = "No ceremony finished recently or the tokens have been issued already", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/token")]
+async fn issue_api_tokens(
+    session: Session,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = take_token_grant(&session).ok_or(MyError::Unauthenticated)?;
+    require_enabled(&get_user(&state, user_unique_id).await?)?;
+    info!("Issue tokens {}", user_unique_id);
+    let tokens = state.api_tokens.issue(user_unique_id).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(tokens))
+}
+
+/// Exchange a refresh token for new tokens, the refresh token cannot be used again.
+#[utoipa::path(
+    request_body = RefreshTokenRequest,
+    responses(
+        (status = 200, description = "Access and refresh token", body = BearerTokens),
+        (status = 401, description = "Unknown, expired or used refresh token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/token/refresh")]
+async fn refresh_api_tokens(
+    request: web::Json<RefreshTokenRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let tokens = state
+        .api_tokens
+        .refresh(state.users.as_ref(), &request.refresh_token)
+        .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(tokens))
+}
+
+/// Revoke a refresh token, e.g. when the API client logs out. Its access token stays
+/// valid until it expires.
+#[utoipa::path(
+    request_body = RefreshTokenRequest,
+    responses((status = 204, description = "Revoked, also for unknown tokens"))
+)]
+#[post("/token/revoke")]
+async fn revoke_api_token(
+    request: web::Json<RefreshTokenRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    state.api_tokens.revoke(&request.refresh_token).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Use a recovery code of a user who lost their passkeys. The session may then register a
+/// new passkey for the user with `/register_start` and `/register_finish` within ten
+/// minutes, it is not logged in until then.
+#[utoipa::path(
+    request_body = RecoveryRequest,
+    responses(
+        (status = 200, description = "The user to register a passkey for", body = User),
+        (status = 401, description = "Unknown user or wrong code", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/recover", wrap = "RateLimit")]
+async fn recover(
+    request: HttpRequest,
+    recovery: web::Json<RecoveryRequest>,
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<HttpResponse, MyError> {
+    info!("Recover {}", recovery.name);
+
+    let mut event = audit_event(&state, &request, AuditAction::Recovery);
+    let result = async {
+        clear_reg_state(&session);
+        clear_recovery(&session);
+        // Unknown users get the same answer as wrong codes.
+        let user_unique_id = name_to_id(&state, &recovery.name)
+            .await?
+            .ok_or(MyError::InvalidRecoveryCode)?;
+        event.user_id = Some(user_unique_id);
+        // Before the code is used up, disabled accounts would lose one on every attempt.
+        let user = get_user(&state, user_unique_id).await?;
+        require_enabled(&user)?;
+        if !use_code(&state, user_unique_id, &recovery.code).await? {
+            return Err(MyError::InvalidRecoveryCode);
+        }
+        insert_recovery(&session, user_unique_id)?;
+        Ok(user)
+    }
+    .await;
+    state.metrics.ceremony("recovery", "code", &result);
+    record_audit_event(&state, event, &result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+/// How many recovery codes the logged in user has left.
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The number of unused codes", body = RecoveryCodes),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/recovery_codes")]
+async fn get_recovery_codes(
+    authenticated: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    let remaining = state.users.count_recovery_codes(user_unique_id).await?;
+    Ok(HttpResponse::Ok().json(RecoveryCodes {
+        recovery_codes: None,
+        remaining,
+    }))
+}
+
+/// Replace the recovery codes of the logged in user, the old ones stop working.
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The new codes, only shown this once", body = RecoveryCodes),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/recovery_codes")]
+async fn regenerate_recovery_codes(
+    authenticated: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    info!("Regenerate recovery codes {}", user_unique_id);
+    let codes = regenerate_codes(&state, user_unique_id).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(RecoveryCodes {
+            remaining: codes.len(),
+            recovery_codes: Some(codes),
+        }))
+}
+
+fn magic_links(state: &AppState) -> Result<&MagicLinks, MyError> {
+    state
+        .magic_links
+        .as_ref()
+        .ok_or_else(|| anyhow::Error::msg("Magic links are disabled").into())
+}
+
+/// Mail a link that logs the user in, for devices without a passkey. Like every
+/// `magic_link` route only with `magic_link.enabled`. The mail is sent in the background,
+/// so the answer is the same whether the user exists or not. Disabled users get no mail.
+#[utoipa::path(
+    request_body = MagicLinkRequest,
+    responses(
+        (status = 202, description = "A link is on its way if the user exists"),
+        (status = 400, description = "The username is not an email address", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/magic_link", wrap = "RateLimit")]
+async fn request_magic_link(
+    magic_link: web::Json<MagicLinkRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Request magic link {}", magic_link.name);
+
+    let result = async {
+        magic_links(&state)?;
+        require_email_address(&magic_link.name)?;
+        if let Some(user_unique_id) = name_to_id(&state, &magic_link.name).await? {
+            let user = get_user(&state, user_unique_id).await?;
+            if !user.disabled {
+                let state = state.clone();
+                actix_web::rt::spawn(async move {
+                    let sent: Result<(), MyError> = async {
+                        let token = magic_links(&state)?.issue(user.unique_id)?;
+                        send_magic_link_email(&state, &user, &token).await
+                    }
+                    .await;
+                    if let Err(e) = sent {
+                        error!("Failed to send a magic link to {} {:?}", user.unique_id, e);
+                    }
+                });
+            }
+        }
+        Ok(HttpResponse::Accepted().finish())
+    }
+    .await;
+    state.metrics.ceremony("magic_link", "request", &result);
+    result
+}
+
+/// Follow a magic link. Depending on `magic_link.mode` this logs the user in, or only
+/// lets the session register a passkey for them within ten minutes.
+#[utoipa::path(
+    request_body = MagicLinkVerification,
+    responses(
+        (status = 200, description = "The user of the link, follow `redirect_to` if set", body = MagicLinkVerified),
+        (status = 400, description = "The link is invalid, has expired or was used before", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/magic_link/verify", wrap = "RateLimit")]
+async fn verify_magic_link(
+    request: HttpRequest,
+    verification: web::Json<MagicLinkVerification>,
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<HttpResponse, MyError> {
+    info!("Verify magic link");
+
+    let mut event = audit_event(&state, &request, AuditAction::MagicLink);
+    let result = async {
+        let magic_links = magic_links(&state)?;
+        clear_reg_state(&session);
+        clear_recovery(&session);
+        let user_unique_id = magic_links.redeem(&verification.token).await?;
+        event.user_id = Some(user_unique_id);
+        let user = get_user(&state, user_unique_id).await?;
+        require_enabled(&user)?;
+        let logged_in = magic_links.mode() == MagicLinkMode::Login;
+        if logged_in {
+            Identity::login(&request.extensions(), user_unique_id.to_string())
+                .map_err(|e| anyhow::Error::msg(format!("Login failed {}", e)))?;
+        } else {
+            insert_recovery(&session, user_unique_id)?;
+        }
+        Ok((user, logged_in))
+    }
+    .await;
+    state.metrics.ceremony("magic_link", "verify", &result);
+    record_audit_event(&state, event, &result).await;
+    let (user, logged_in) = result?;
+    let redirect_to = if logged_in {
+        insert_token_grant(&session, user.unique_id)?;
+        complete_pending_authorization(&state, &session, user.unique_id)?
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(MagicLinkVerified {
+        user,
+        logged_in,
+        redirect_to,
+    }))
+}
+
+const DEFAULT_SECURITY_EVENTS: usize = 50;
+const MAX_SECURITY_EVENTS: usize = 500;
+
+#[derive(Deserialize, Debug, IntoParams)]
+struct SecurityEventsQuery {
+    /// At most 500, 50 if not given
+    limit: Option<usize>,
+}
+
+/// The latest registrations, logins and logouts of the logged in user, newest first.
+#[utoipa::path(
+    params(SecurityEventsQuery),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Newest first", body = [AuditEvent]),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/security_events")]
+async fn get_security_events(
+    authenticated: AuthenticatedUser,
+    query: web::Query<SecurityEventsQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user_unique_id = authenticated.unique_id;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SECURITY_EVENTS)
+        .min(MAX_SECURITY_EVENTS);
+    let events = state.audit_log.recent_events(user_unique_id, limit).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Users whose name or display name contains `search`, for administrators.
+#[utoipa::path(
+    params(UserSearch),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "One page of users, oldest first", body = UserPage),
+        (status = 400, description = "The offset is too large", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/users")]
+async fn admin_list_users(
+    _admin: RequireRole<Admin>,
+    query: web::Query<UserSearch>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let search = query.search.as_deref().unwrap_or_default().trim();
+    let offset = query.offset.unwrap_or_default();
+    // SQLite takes an i64 and would treat a wrapped, negative offset as 0.
+    if i64::try_from(offset).is_err() {
+        return Err(MyError::InvalidInput("The offset is too large".to_string()));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let (users, total) = state.users.search_users(search, offset, limit).await?;
+    Ok(HttpResponse::Ok().json(UserPage {
+        users,
+        total,
+        offset,
+        limit,
+    }))
+}
+
+/// A user with their passkeys and latest security events, for administrators.
+#[utoipa::path(
+    params(("user_id" = Uuid, Path, description = "The unique id of the user")),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The user", body = UserDetails),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/users/{user_id}")]
+async fn admin_get_user(
+    _admin: RequireRole<Admin>,
+    user_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let user = get_user(&state, *user_id).await?;
+    let credentials = list_credentials(&state, user.unique_id)
+        .await?
+        .iter()
+        .map(CredentialInfo::from)
+        .collect();
+    let recent_events = state
+        .audit_log
+        .recent_events(user.unique_id, RECENT_EVENTS)
+        .await?;
+    Ok(HttpResponse::Ok().json(UserDetails {
+        user,
+        credentials,
+        recent_events,
+    }))
+}
+
+/// Disable an account, which also logs the user out everywhere.
+#[utoipa::path(
+    params(("user_id" = Uuid, Path, description = "The unique id of the user")),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The disabled user", body = User),
+        (status = 400, description = "Administrators cannot disable themselves", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/users/{user_id}/disable")]
+async fn admin_disable_user(
+    request: HttpRequest,
+    admin: RequireRole<Admin>,
+    user_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Disable user {} by {}", user_id, admin.user.unique_id);
+    // Otherwise the last administrator could lock everybody out of the admin API.
+    if *user_id == admin.user.unique_id {
+        return Err(MyError::InvalidInput(
+            "You cannot disable your own account".to_string(),
+        ));
+    }
+    let mut event = audit_event(&state, &request, AuditAction::Disable);
+    event.user_id = Some(*user_id);
+    event.actor = Some(admin.user.unique_id);
+    let result = async {
+        set_disabled(&state, *user_id, true).await?;
+        get_user(&state, *user_id).await
+    }
+    .await;
+    record_audit_event(&state, event, &result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+/// Let a disabled user log in again.
+#[utoipa::path(
+    params(("user_id" = Uuid, Path, description = "The unique id of the user")),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The enabled user", body = User),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/users/{user_id}/enable")]
+async fn admin_enable_user(
+    request: HttpRequest,
+    admin: RequireRole<Admin>,
+    user_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Enable user {} by {}", user_id, admin.user.unique_id);
+    let mut event = audit_event(&state, &request, AuditAction::Enable);
+    event.user_id = Some(*user_id);
+    event.actor = Some(admin.user.unique_id);
+    let result = async {
+        set_disabled(&state, *user_id, false).await?;
+        get_user(&state, *user_id).await
+    }
+    .await;
+    record_audit_event(&state, event, &result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+/// Remove a passkey of a user, unlike `DELETE /credentials/{cred_id}` also the last one.
+#[utoipa::path(
+    params(
+        ("user_id" = Uuid, Path, description = "The unique id of the user"),
+        ("cred_id" = String, Path, description = "The base64url encoded credential id"),
+    ),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The user has no such passkey", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/admin/users/{user_id}/credentials/{cred_id}")]
+async fn admin_revoke_credential(
+    request: HttpRequest,
+    admin: RequireRole<Admin>,
+    path: web::Path<(Uuid, String)>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let (user_id, cred_id) = path.into_inner();
+    info!(
+        "Revoke credential {} of {} by {}",
+        cred_id, user_id, admin.user.unique_id
+    );
+    let mut event = audit_event(&state, &request, AuditAction::RevokeCredential);
+    event.user_id = Some(user_id);
+    event.actor = Some(admin.user.unique_id);
+    event.credential_id = Some(cred_id.clone());
+    let result = async {
+        if state.users.revoke_credential(user_id, &cred_id).await? {
+            Ok(())
+        } else {
+            Err(MyError::CredentialNotFound)
+        }
+    }
+    .await;
+    record_audit_event(&state, event, &result).await;
+    result?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// End every session of a user and revoke their refresh tokens.
+#[utoipa::path(
+    params(("user_id" = Uuid, Path, description = "The unique id of the user")),
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "Logged out everywhere"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/users/{user_id}/logout")]
+async fn admin_logout_user(
+    request: HttpRequest,
+    admin: RequireRole<Admin>,
+    user_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Log out user {} by {}", user_id, admin.user.unique_id);
+    let mut event = audit_event(&state, &request, AuditAction::ForceLogout);
+    event.user_id = Some(*user_id);
+    event.actor = Some(admin.user.unique_id);
+    let result = async {
+        get_user(&state, *user_id).await?;
+        let sessions = force_logout(&state, *user_id).await?;
+        info!("Ended {} sessions", sessions);
+        Ok(())
+    }
+    .await;
+    record_audit_event(&state, event, &result).await;
+    result?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Create an invite code for the `invite` registration policy.
+#[utoipa::path(
+    security(("session_cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The invite, valid for `registration.invite_lifetime` seconds", body = Invite),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/invites")]
+async fn admin_create_invite(
+    admin: RequireRole<Admin>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Create invite by {}", admin.user.unique_id);
+    let invite = registration_policy::create_invite(
+        state.users.as_ref(),
+        state.config.registration.invite_lifetime,
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(invite))
+}
+
+fn oidc(state: &AppState) -> Result<&Oidc, MyError> {
+    state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| anyhow::Error::msg("OpenID Connect is disabled").into())
+}
+
+/// The OpenID Connect discovery document, like every `oidc` route only with `oidc.enabled`.
+#[utoipa::path(responses((status = 200, description = "OpenID Provider Metadata", body = Object)))]
+#[get("/.well-known/openid-configuration")]
+async fn oidc_discovery(state: web::Data<AppState>) -> Result<HttpResponse, MyError> {
+    Ok(HttpResponse::Ok().json(oidc(&state)?.discovery()))
+}
+
+/// The public key the tokens are signed with.
+#[utoipa::path(responses((status = 200, description = "JSON Web Key Set", body = Object)))]
+#[get("/oidc/jwks")]
+async fn oidc_jwks(state: web::Data<AppState>) -> Result<HttpResponse, MyError> {
+    Ok(HttpResponse::Ok().json(oidc(&state)?.jwks()?))
+}
+
+/// Start an authorization code flow. Users who are not logged in are sent to
+/// `oidc.login_url` first, `login_finish` then completes the authorization.
+#[utoipa::path(
+    params(AuthorizationRequest),
+    responses(
+        (status = 303, description = "To the client with a code or an error, or to the login page"),
+        (status = 400, description = "Unknown client or redirect URI", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/oidc/authorize")]
+async fn oidc_authorize(
+    query: web::Query<AuthorizationRequest>,
+    identity: Option<Identity>,
+    session: Session,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    info!("Authorize {}", query.client_id);
+    let oidc = oidc(&state)?;
+    let location = match oidc.authorize(&query)? {
+        Authorization::Refused(location) => location,
+        Authorization::Pending(authorization) => match identity_to_id(identity) {
+            Ok(user_unique_id) => oidc.issue_code(authorization, user_unique_id)?,
+            Err(_) => {
+                insert_pending_authorization(&session, &authorization)?;
+                state.config.oidc.login_url.clone()
+            }
+        },
+    };
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+/// Exchange an authorization code for tokens.
+#[utoipa::path(
+    request_body(content = TokenRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "ID and access token", body = TokenResponse),
+        (status = 400, description = "RFC 6749 error, e.g. `invalid_grant`", body = Object),
+        (status = 401, description = "`invalid_client`", body = Object),
+    )
+)]
+#[post("/oidc/token")]
+async fn oidc_token(
+    request: HttpRequest,
+    form: web::Form<TokenRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, TokenError> {
+    let oidc = state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| anyhow::Error::msg("OpenID Connect is disabled"))?;
+    let tokens = oidc.exchange(&state, &form, request.headers()).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(tokens))
+}
+
+/// The user an access token was issued for.
+#[utoipa::path(
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The claims allowed by the scope of the token", body = UserInfo),
+        (status = 401, description = "Missing, invalid or expired access token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The account has been disabled", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/oidc/userinfo")]
+async fn oidc_userinfo(
+    request: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let oidc = oidc(&state)?;
+    let claims = oidc
+        .access_token(request.headers())
+        .ok_or(MyError::Unauthenticated)?;
+    let user_unique_id = Uuid::parse_str(&claims.sub).map_err(|_| MyError::Unauthenticated)?;
+    let user = get_user(&state, user_unique_id).await?;
+    require_enabled(&user)?;
+    Ok(HttpResponse::Ok().json(oidc.user_info(&claims, user)))
 }
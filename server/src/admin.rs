@@ -0,0 +1,63 @@
+use crate::errors::MyError;
+use crate::models::{AppState, AuditEvent, CredentialInfo, User};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Events shown with the details of a user.
+pub const RECENT_EVENTS: usize = 20;
+
+/// The query of `/admin/users`.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct UserSearch {
+    /// Part of the name or display name, ignoring case, all users if not given
+    pub search: Option<String>,
+    /// Users to skip, 0 if not given
+    pub offset: Option<usize>,
+    /// At most 100, 20 if not given
+    pub limit: Option<usize>,
+}
+
+/// One page of the answer of `/admin/users`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserPage {
+    /// Oldest first
+    pub users: Vec<User>,
+    /// Matching users on all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// A user as an administrator sees them.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    pub user: User,
+    pub credentials: Vec<CredentialInfo>,
+    /// Newest first
+    pub recent_events: Vec<AuditEvent>,
+}
+
+/// End every session of the user and revoke their refresh tokens, access tokens that have
+/// been issued already stay valid until they expire. Returns how many sessions ended.
+pub async fn force_logout(state: &AppState, user_id: Uuid) -> Result<usize> {
+    let sessions = state.sessions.delete_user_sessions(user_id).await?;
+    state.api_tokens.revoke_user(user_id).await?;
+    Ok(sessions)
+}
+
+/// Disable or enable the account, a disabled user is logged out everywhere.
+pub async fn set_disabled(state: &AppState, user_id: Uuid, disabled: bool) -> Result<(), MyError> {
+    if !state.users.set_disabled(user_id, disabled).await? {
+        return Err(MyError::UserNotFound);
+    }
+    if disabled {
+        force_logout(state, user_id).await?;
+    }
+    Ok(())
+}
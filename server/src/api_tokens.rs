@@ -0,0 +1,247 @@
+use crate::auth::require_enabled;
+use crate::config::ApiTokenConfig;
+use crate::errors::MyError;
+use crate::jwt::{bearer_token, encode, JwtKeys, ACCESS_TOKEN};
+use crate::models::{unix_now, AppState};
+use crate::refresh_token_store::RefreshTokenStore;
+use crate::user_store::UserStore;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// The `aud` of access tokens for this API. Access tokens of the OpenID Connect provider
+/// are meant for its clients, so they are refused here even if signed with the same key.
+const AUDIENCE: &str = "api";
+
+/// Seconds after a ceremony in which its session can be traded for tokens.
+const TOKEN_GRANT_LIFETIME: i64 = 5 * 60;
+
+const TOKEN_GRANT: &str = "token_grant";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenGrant {
+    user_id: Uuid,
+    granted_at: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct AccessTokenClaims {
+    /// `rp_origin`
+    iss: String,
+    sub: Uuid,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// The tokens of an API client.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BearerTokens {
+    /// Sent as `Authorization: Bearer` instead of the session cookie
+    pub access_token: String,
+    /// Always `Bearer`
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+    /// Exchanged for new tokens at `/token/refresh`, only once
+    pub refresh_token: String,
+}
+
+/// The body of `/token/refresh` and `/token/revoke`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+/// Short-lived access tokens and single-use refresh tokens for clients that cannot keep
+/// the session cookie, such as mobile apps and scripts.
+///
+/// Access tokens are JWTs, so they are checked without a lookup and stay valid until they
+/// expire. Refresh tokens are random and stored, every refresh replaces the token.
+pub struct ApiTokens {
+    config: ApiTokenConfig,
+    issuer: String,
+    keys: JwtKeys,
+    refresh_tokens: Arc<dyn RefreshTokenStore>,
+}
+
+impl ApiTokens {
+    pub fn new(
+        config: ApiTokenConfig,
+        issuer: &str,
+        keys: JwtKeys,
+        refresh_tokens: Arc<dyn RefreshTokenStore>,
+    ) -> Self {
+        Self {
+            config,
+            issuer: issuer.to_string(),
+            keys,
+            refresh_tokens,
+        }
+    }
+
+    pub fn from_config(
+        config: &ApiTokenConfig,
+        issuer: &str,
+        refresh_tokens: Arc<dyn RefreshTokenStore>,
+    ) -> Result<Self> {
+        let keys = JwtKeys::read_or_generate(Path::new(&config.signing_key))?;
+        Ok(Self::new(config.clone(), issuer, keys, refresh_tokens))
+    }
+
+    /// A new access and refresh token for the user.
+    pub async fn issue(&self, user_id: Uuid) -> Result<BearerTokens> {
+        let now = unix_now();
+        let claims = AccessTokenClaims {
+            iss: self.issuer.clone(),
+            sub: user_id,
+            aud: AUDIENCE.to_string(),
+            iat: now,
+            exp: now + self.config.access_token_lifetime,
+        };
+        let mut refresh_token = [0; 32];
+        openssl::rand::rand_bytes(&mut refresh_token)?;
+        let refresh_token = encode(&refresh_token);
+        self.refresh_tokens
+            .insert(
+                &hash(&refresh_token),
+                user_id,
+                now + self.config.refresh_token_lifetime,
+            )
+            .await?;
+        Ok(BearerTokens {
+            access_token: self.keys.sign(ACCESS_TOKEN, &claims)?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.config.access_token_lifetime,
+            refresh_token,
+        })
+    }
+
+    /// Replace the refresh token with new tokens, as long as the account is enabled.
+    pub async fn refresh(
+        &self,
+        users: &dyn UserStore,
+        refresh_token: &str,
+    ) -> Result<BearerTokens, MyError> {
+        let user_id = self
+            .refresh_tokens
+            .take(&hash(refresh_token))
+            .await?
+            .ok_or(MyError::InvalidRefreshToken)?;
+        let user = users
+            .get_user(user_id)
+            .await?
+            .ok_or(MyError::InvalidRefreshToken)?;
+        require_enabled(&user)?;
+        Ok(self.issue(user_id).await?)
+    }
+
+    /// Make the refresh token unusable, unknown tokens are ignored.
+    pub async fn revoke(&self, refresh_token: &str) -> Result<()> {
+        self.refresh_tokens.take(&hash(refresh_token)).await?;
+        Ok(())
+    }
+
+    /// Make all refresh tokens of the user unusable. Access tokens stay valid until they
+    /// expire, unless the user is disabled.
+    pub async fn revoke_user(&self, user_id: Uuid) -> Result<()> {
+        self.refresh_tokens.revoke_user(user_id).await
+    }
+
+    /// The user of a valid access token.
+    fn verify(&self, access_token: &str) -> Option<Uuid> {
+        self.keys
+            .verify::<AccessTokenClaims>(ACCESS_TOKEN, access_token)
+            .filter(|claims| claims.iss == self.issuer && claims.aud == AUDIENCE)
+            .map(|claims| claims.sub)
+    }
+}
+
+fn hash(refresh_token: &str) -> String {
+    encode(&openssl::sha::sha256(refresh_token.as_bytes()))
+}
+
+/// Let the session that just finished a ceremony trade it for tokens once.
+pub fn insert_token_grant(session: &Session, user_id: Uuid) -> Result<(), MyError> {
+    let grant = TokenGrant {
+        user_id,
+        granted_at: unix_now(),
+    };
+    session
+        .insert(TOKEN_GRANT, grant)
+        .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)).into())
+}
+
+/// The user of the last ceremony of the session, unless it has been traded already or
+/// was too long ago.
+pub fn take_token_grant(session: &Session) -> Option<Uuid> {
+    session
+        .remove_as::<TokenGrant>(TOKEN_GRANT)
+        .and_then(Result::ok)
+        .filter(|grant| grant.granted_at + TOKEN_GRANT_LIFETIME > unix_now())
+        .map(|grant| grant.user_id)
+}
+
+/// The logged in user, identified by an `Authorization: Bearer` access token or else by
+/// the session cookie. A request with an invalid token is refused even if it also carries
+/// a valid cookie.
+pub struct AuthenticatedUser {
+    pub unique_id: Uuid,
+}
+
+/// The state of the app serving the request, for extractors.
+pub fn app_state(req: &HttpRequest) -> Result<web::Data<AppState>, MyError> {
+    req.app_data::<web::Data<AppState>>()
+        .cloned()
+        .ok_or_else(|| anyhow::Error::msg("AppState is missing").into())
+}
+
+impl AuthenticatedUser {
+    /// Only checks the token or cookie, see [FromRequest] for the full check.
+    pub fn authenticate(req: &HttpRequest) -> Result<Self, MyError> {
+        if let Some(token) = bearer_token(req.headers()) {
+            let unique_id = app_state(req)?
+                .api_tokens
+                .verify(token)
+                .ok_or(MyError::Unauthenticated)?;
+            return Ok(Self { unique_id });
+        }
+        let id = Identity::extract(req)
+            .into_inner()
+            .map_err(|_| MyError::Unauthenticated)?
+            .id()
+            .map_err(|_| MyError::Unauthenticated)?;
+        let unique_id = Uuid::parse_str(&id).map_err(|e| {
+            anyhow::Error::msg(format!("Failed to parse user unique identity {}", e))
+        })?;
+        Ok(Self { unique_id })
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = MyError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, MyError>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let authenticated = Self::authenticate(req);
+        let state = app_state(req);
+        Box::pin(async move {
+            let authenticated = authenticated?;
+            // Disabled accounts lose access at once, even with an access token that has
+            // not expired yet. Removed users are left to the handlers.
+            if let Some(user) = state?.users.get_user(authenticated.unique_id).await? {
+                require_enabled(&user)?;
+            }
+            Ok(authenticated)
+        })
+    }
+}
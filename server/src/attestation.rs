@@ -0,0 +1,359 @@
+use crate::config::AttestationConfig;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use openssl::hash::MessageDigest;
+use openssl::sign::Verifier;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+use serde::Deserialize;
+use serde_cbor_2::Value;
+use std::collections::BTreeSet;
+use webauthn_rs::prelude::{
+    AttestationCa, AttestationCaList, Base64UrlSafeData, PasskeyRegistration,
+    PasswordlessKeyRegistration, RegisterPublicKeyCredential, Uuid,
+};
+
+/// Statuses of the FIDO Metadata Service after which an authenticator is no longer trusted.
+const UNTRUSTED_STATUSES: &[&str] = &[
+    "REVOKED",
+    "USER_VERIFICATION_BYPASS",
+    "ATTESTATION_KEY_COMPROMISE",
+    "USER_KEY_REMOTE_COMPROMISE",
+    "USER_KEY_PHYSICAL_COMPROMISE",
+];
+
+/// The CAs registrations have to be attested by, or `None` if any authenticator is
+/// accepted. Combines the `ca_files` with the authenticators of the `mds_blob`.
+pub fn ca_list(config: &AttestationConfig) -> Result<Option<AttestationCaList>> {
+    if !config.required {
+        return Ok(None);
+    }
+    let mut cas = AttestationCaList::default();
+    let aaguids: BTreeSet<Uuid> = config.aaguids.iter().copied().collect();
+    for path in &config.ca_files {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+        for ca in X509::stack_from_pem(&pem).with_context(|| format!("Invalid PEM {}", path))? {
+            insert(
+                &mut cas,
+                AttestationCa {
+                    ca,
+                    aaguids: aaguids.clone(),
+                },
+            )?;
+        }
+    }
+    if let (Some(blob), Some(root)) = (&config.mds_blob, &config.mds_root_ca) {
+        let jwt =
+            std::fs::read_to_string(blob).with_context(|| format!("Failed to read {}", blob))?;
+        let pem = std::fs::read(root).with_context(|| format!("Failed to read {}", root))?;
+        let root = X509::from_pem(&pem).with_context(|| format!("Invalid PEM {}", root))?;
+        for ca in from_mds(jwt.trim(), &root)? {
+            insert(&mut cas, ca)?;
+        }
+    }
+    if cas.is_empty() {
+        anyhow::bail!("attestation is required but no CA is trusted");
+    }
+    info!("Trusting {} attestation CAs", cas.cas.len());
+    Ok(Some(cas))
+}
+
+/// Add `ca`, merging the allowed AAGUIDs with those of the same CA added before. An empty
+/// set allows every authenticator of the CA and wins over any restriction.
+fn insert(cas: &mut AttestationCaList, ca: AttestationCa) -> Result<()> {
+    let kid = Base64UrlSafeData(ca.get_kid()?);
+    match cas.cas.get_mut(&kid) {
+        Some(existing) if existing.aaguids.is_empty() => {}
+        Some(existing) if ca.aaguids.is_empty() => existing.aaguids.clear(),
+        Some(existing) => existing.aaguids.extend(ca.aaguids),
+        None => {
+            cas.cas.insert(kid, ca);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    x5c: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct MdsBlob {
+    entries: Vec<MdsEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MdsEntry {
+    /// Only FIDO2 authenticators have one, U2F and UAF ones are skipped.
+    aaguid: Option<Uuid>,
+    metadata_statement: Option<MetadataStatement>,
+    #[serde(default)]
+    status_reports: Vec<StatusReport>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetadataStatement {
+    description: Option<String>,
+    /// Base64 encoded DER certificates.
+    #[serde(default)]
+    attestation_root_certificates: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusReport {
+    status: String,
+}
+
+/// The CAs of the authenticators in a FIDO MDS3 blob, each limited to the AAGUID it is
+/// listed for. The blob is a JWT whose signing certificate has to chain up to `root`.
+/// Authenticators that were ever reported as compromised are left out.
+fn from_mds(jwt: &str, root: &X509) -> Result<Vec<AttestationCa>> {
+    let mut parts = jwt.split('.');
+    let (header, payload, signature) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature), None) => (header, payload, signature),
+            _ => anyhow::bail!("The MDS blob is not a JWT"),
+        };
+    let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD);
+    let header: JwtHeader = serde_json::from_slice(&decode(header)?)?;
+    if header.alg != "RS256" {
+        anyhow::bail!("Unsupported MDS blob signature {}", header.alg);
+    }
+    let mut certificates = header
+        .x5c
+        .iter()
+        .map(|cert| Ok(X509::from_der(&base64::decode(cert)?)?))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let signer = certificates
+        .next()
+        .context("The MDS blob has no signing certificate")?;
+    let mut chain = Stack::new()?;
+    for cert in certificates {
+        chain.push(cert)?;
+    }
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(root.clone())?;
+    let store = store.build();
+    let mut context = X509StoreContext::new()?;
+    if !context.init(&store, &signer, &chain, |context| context.verify_cert())? {
+        anyhow::bail!(
+            "The MDS blob is not signed by the root CA: {}",
+            context.error()
+        );
+    }
+    let public_key = signer.public_key()?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+    // The signature covers `header.payload` as they appear in the blob.
+    verifier.update(&jwt.as_bytes()[..jwt.len() - signature.len() - 1])?;
+    if !verifier.verify(&decode(signature)?)? {
+        anyhow::bail!("The signature of the MDS blob is invalid");
+    }
+
+    let blob: MdsBlob = serde_json::from_slice(&decode(payload)?)?;
+    let mut cas = vec![];
+    for entry in blob.entries {
+        let (aaguid, statement) = match (entry.aaguid, entry.metadata_statement) {
+            (Some(aaguid), Some(statement)) => (aaguid, statement),
+            _ => continue,
+        };
+        let description = statement.description.unwrap_or_default();
+        if let Some(report) = entry
+            .status_reports
+            .iter()
+            .find(|report| UNTRUSTED_STATUSES.contains(&report.status.as_str()))
+        {
+            warn!("Not trusting {} {}: {}", aaguid, description, report.status);
+            continue;
+        }
+        for cert in statement.attestation_root_certificates {
+            cas.push(AttestationCa {
+                ca: X509::from_der(&base64::decode(cert)?)?,
+                aaguids: BTreeSet::from([aaguid]),
+            });
+        }
+    }
+    Ok(cas)
+}
+
+/// Only the part of a [PasswordlessKeyRegistration] without the CA list is kept in the
+/// session, the list can hold hundreds of certificates. Both registrations serialise
+/// their ceremony state the same way, so the passkey one carries it.
+pub fn without_ca_list(reg_state: PasswordlessKeyRegistration) -> Result<PasskeyRegistration> {
+    let mut value = serde_json::to_value(reg_state)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("ca_list");
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Attach the CA list to the ceremony state kept by [without_ca_list].
+pub fn with_ca_list(
+    reg_state: &PasskeyRegistration,
+    ca_list: &AttestationCaList,
+) -> Result<PasswordlessKeyRegistration> {
+    let mut value = serde_json::to_value(reg_state)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert("ca_list".to_string(), serde_json::to_value(ca_list)?);
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// The AAGUID identifying the model of the authenticator, `None` if it does not tell, e.g.
+/// because the browser zeroed it. Read from the authenticator data of the attestation.
+pub fn aaguid(reg: &RegisterPublicKeyCredential) -> Option<Uuid> {
+    let object = serde_cbor_2::from_slice(&reg.response.attestation_object.0).ok()?;
+    let auth_data = match object {
+        Value::Map(mut map) => match map.remove(&Value::Text("authData".to_string()))? {
+            Value::Bytes(auth_data) => auth_data,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    // rpIdHash (32 bytes), flags (1), signCount (4), then the attested credential data
+    // starting with the AAGUID (16) if the AT flag is set.
+    const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+    if auth_data.get(32)? & ATTESTED_CREDENTIAL_DATA == 0 {
+        return None;
+    }
+    let aaguid = Uuid::from_slice(auth_data.get(37..53)?).ok()?;
+    (!aaguid.is_nil()).then_some(aaguid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::X509NameBuilder;
+
+    fn certificate(name: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        let (issuer_name, issuer_key) = match issuer {
+            Some((cert, key)) => (cert.subject_name(), key),
+            None => (subject.as_ref(), &key),
+        };
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    fn jwt(payload: &serde_json::Value, signer: &X509, key: &PKey<Private>) -> String {
+        let header = serde_json::json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "x5c": [base64::encode(signer.to_der().unwrap())],
+        });
+        let encode = |value: &serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        let content = format!("{}.{}", encode(&header), encode(payload));
+        let mut sign = Signer::new(MessageDigest::sha256(), key).unwrap();
+        sign.update(content.as_bytes()).unwrap();
+        let signature = base64::encode_config(sign.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", content, signature)
+    }
+
+    #[test]
+    fn mds_blob_is_verified_and_filtered() {
+        let (root, root_key) = certificate("MDS Root", None);
+        let (signer, signer_key) = certificate("MDS Signer", Some((&root, &root_key)));
+        let (vendor, _) = certificate("Vendor", None);
+        let vendor_der = base64::encode(vendor.to_der().unwrap());
+        let trusted = Uuid::new_v4();
+        let revoked = Uuid::new_v4();
+        let payload = serde_json::json!({
+            "no": 1,
+            "entries": [
+                {
+                    "aaguid": trusted,
+                    "metadataStatement": {
+                        "description": "Trusted key",
+                        "attestationRootCertificates": [vendor_der],
+                    },
+                    "statusReports": [{ "status": "FIDO_CERTIFIED_L1" }],
+                },
+                {
+                    "aaguid": revoked,
+                    "metadataStatement": {
+                        "description": "Revoked key",
+                        "attestationRootCertificates": [vendor_der],
+                    },
+                    "statusReports": [{ "status": "REVOKED" }],
+                },
+                {
+                    "attestationCertificateKeyIdentifiers": ["u2f"],
+                    "metadataStatement": { "attestationRootCertificates": [vendor_der] },
+                },
+            ],
+        });
+        let blob = jwt(&payload, &signer, &signer_key);
+
+        let cas = from_mds(&blob, &root).unwrap();
+        assert_eq!(cas.len(), 1);
+        assert_eq!(cas[0].ca.to_der().unwrap(), vendor.to_der().unwrap());
+        assert_eq!(cas[0].aaguids, BTreeSet::from([trusted]));
+
+        let (other_root, _) = certificate("Other Root", None);
+        assert!(from_mds(&blob, &other_root).is_err());
+        let tampered = jwt(
+            &payload,
+            &signer,
+            &PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+        );
+        assert!(from_mds(&tampered, &root).is_err());
+    }
+
+    #[test]
+    fn unrestricted_cas_win_when_merged() {
+        let (vendor, _) = certificate("Vendor", None);
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let mut cas = AttestationCaList::default();
+        let with = |aaguids: &[Uuid]| AttestationCa {
+            ca: vendor.clone(),
+            aaguids: aaguids.iter().copied().collect(),
+        };
+
+        insert(&mut cas, with(&[first])).unwrap();
+        insert(&mut cas, with(&[second])).unwrap();
+        let ca = cas.cas.values().next().unwrap();
+        assert_eq!(ca.aaguids, BTreeSet::from([first, second]));
+
+        insert(&mut cas, with(&[])).unwrap();
+        insert(&mut cas, with(&[first])).unwrap();
+        assert_eq!(cas.cas.len(), 1);
+        assert!(cas.cas.values().next().unwrap().aaguids.is_empty());
+    }
+}
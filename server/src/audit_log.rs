@@ -0,0 +1,81 @@
+use crate::models::*;
+use anyhow::Result;
+use async_std::sync::RwLock;
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// Append-only record of registrations, logins and logouts, successful or not. Entries are
+/// never changed or deleted through this trait.
+#[async_trait::async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn record(&self, event: &AuditEvent) -> Result<()>;
+
+    /// The latest `limit` events of the user, newest first.
+    async fn recent_events(&self, user_id: Uuid, limit: usize) -> Result<Vec<AuditEvent>>;
+}
+
+/// Events kept by [MemoryAuditLog], older ones are dropped so failed logins can not fill
+/// up the memory.
+const MAX_EVENTS: usize = 10_000;
+
+/// Keeps the log in memory next to a [crate::user_store::MemoryUserStore], it is lost on
+/// restart just like the accounts it is about. Only the latest [MAX_EVENTS] are kept.
+#[derive(Default)]
+pub struct MemoryAuditLog {
+    events: RwLock<VecDeque<AuditEvent>>,
+}
+
+#[async_trait::async_trait]
+impl AuditLog for MemoryAuditLog {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        let mut events = self.events.write().await;
+        if events.len() >= MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        Ok(())
+    }
+
+    async fn recent_events(&self, user_id: Uuid, limit: usize) -> Result<Vec<AuditEvent>> {
+        Ok(self
+            .events
+            .read()
+            .await
+            .iter()
+            .rev()
+            .filter(|event| event.user_id == Some(user_id))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn memory_log_keeps_the_latest_events() {
+        let log = MemoryAuditLog::default();
+        let alice = Uuid::new_v4();
+        for timestamp in 0..=MAX_EVENTS as i64 {
+            let event = AuditEvent {
+                timestamp,
+                action: AuditAction::Login,
+                user_id: Some(alice),
+                actor: None,
+                credential_id: None,
+                client_ip: None,
+                user_agent: None,
+                outcome: AUDIT_SUCCESS.to_string(),
+            };
+            log.record(&event).await.unwrap();
+        }
+
+        assert_eq!(log.events.read().await.len(), MAX_EVENTS);
+        let events = log.recent_events(alice, MAX_EVENTS + 1).await.unwrap();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].timestamp, MAX_EVENTS as i64);
+        assert_eq!(events[MAX_EVENTS - 1].timestamp, 1);
+    }
+}
@@ -1,7 +1,13 @@
+use crate::attestation;
+use crate::errors::{MyError, Result};
+use crate::mailer::Email;
 use crate::models::*;
+use crate::privacy::{PendingRegistration, REGISTRATION_TOKEN_LIFETIME};
+use crate::rate_limit::client_ip;
 use actix_session::Session;
-use anyhow::Result;
-use async_std::sync::MutexGuard;
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use log::error;
 use webauthn_rs::prelude::*;
 
 /// Remove any previous registrations that may have occured from the session.
@@ -9,24 +15,24 @@ pub fn clear_reg_state(session: &Session) {
     session.remove("reg_state");
 }
 
-/// Note that due to the session store in use being a server side memory store, this is
+/// Note that due to the session store in use being a server side store, this is
 /// safe to store the reg_state into the session since it is not client controlled and
 /// not open to replay attacks. If this was a cookie store, this would be UNSAFE.
 pub fn insert_reg_state(
     session: &Session,
     user: &User,
     reg_state: &PasskeyRegistration,
-) -> anyhow::Result<()> {
+) -> Result<()> {
     session
         .insert("reg_state", (user, reg_state))
-        .map_err(|e| anyhow::Error::msg(format!("Failed to insert {}", e)))
+        .map_err(|e| anyhow::Error::msg(format!("Failed to insert {}", e)).into())
 }
 
-pub fn get_reg_state(session: &Session) -> anyhow::Result<(User, PasskeyRegistration)> {
+pub fn get_reg_state(session: &Session) -> Result<(User, PasskeyRegistration)> {
     session
         .get("reg_state")
-        .map_err(|_| anyhow::Error::msg("Session missing"))
-        .and_then(|s| s.ok_or_else(|| anyhow::Error::msg("Corrupt Session")))
+        .map_err(|_| MyError::SessionState)
+        .and_then(|s| s.ok_or(MyError::SessionState))
 }
 
 pub fn clear_auth_state(session: &Session) {
@@ -37,98 +43,321 @@ pub fn insert_auth_state(
     session: &Session,
     user_unique_id: Uuid,
     auth_state: &PasskeyAuthentication,
-) -> anyhow::Result<()> {
+) -> Result<()> {
     session
         .insert("auth_state", (user_unique_id, auth_state))
-        .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)))
+        .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)).into())
 }
 
 pub fn get_auth_state(session: &Session) -> Result<(Uuid, PasskeyAuthentication)> {
     session
         .get("auth_state")
-        .map_err(|_| anyhow::Error::msg("Session missing"))
-        .and_then(|s| s.ok_or_else(|| anyhow::Error::msg("Corrupt missing")))
+        .map_err(|_| MyError::SessionState)
+        .and_then(|s| s.ok_or(MyError::SessionState))
+}
+
+pub fn clear_discoverable_auth_state(session: &Session) {
+    session.remove("discoverable_auth_state");
+}
+
+pub fn insert_discoverable_auth_state(
+    session: &Session,
+    auth_state: &DiscoverableAuthentication,
+) -> Result<()> {
+    session
+        .insert("discoverable_auth_state", auth_state)
+        .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)).into())
+}
+
+pub fn get_discoverable_auth_state(session: &Session) -> Result<DiscoverableAuthentication> {
+    session
+        .get("discoverable_auth_state")
+        .map_err(|_| MyError::SessionState)
+        .and_then(|s| s.ok_or(MyError::SessionState))
 }
 
 /// Look up their unique id from the username
-pub fn name_to_id<'a>(users_guard: &'a MutexGuard<Users>, username: &str) -> Option<Uuid> {
-    users_guard.name_to_id.get(username).copied()
+pub async fn name_to_id(state: &AppState, username: &str) -> Result<Option<Uuid>> {
+    Ok(state.users.name_to_id(username).await?)
 }
 
 /// If the user has any other credentials, we exclude these here so they can't be duplicate registered.
 /// It also hints to the browser that only new credentials should be "blinked" for interaction.
-pub fn get_existing_credentials(
-    users_guard: &MutexGuard<Users>,
+pub async fn get_existing_credentials(
+    state: &AppState,
     user_unique_id: Uuid,
 ) -> Option<Vec<Base64UrlSafeData>> {
-    get_allowed_credentials(users_guard, user_unique_id)
+    get_allowed_credentials(state, user_unique_id)
+        .await
         .map(|keys| keys.iter().map(|sk| sk.cred_id().clone()).collect())
         .ok()
 }
 
-pub fn get_allowed_credentials<'a>(
-    users_guard: &'a MutexGuard<Users>,
+pub async fn get_allowed_credentials(
+    state: &AppState,
     user_unique_id: Uuid,
-) -> anyhow::Result<&'a Vec<Passkey>> {
-    users_guard
-        .keys
-        .get(&user_unique_id)
-        .ok_or_else(|| anyhow::Error::msg("User has no credentials"))
+) -> Result<Vec<Passkey>> {
+    let keys = state.users.get_credentials(user_unique_id).await?;
+    if keys.is_empty() {
+        return Err(MyError::NoCredentials);
+    }
+    Ok(keys)
 }
 
-pub async fn insert_user(state: &AppState, user: &User, sk: Passkey) {
-    let mut users_guard = state.users.lock().await;
+/// Fails with [MyError::InvalidInvite] if a new user's invite has been used in the meantime.
+pub async fn insert_user(
+    state: &AppState,
+    user: &User,
+    credential: StoredCredential,
+    invite: Option<&str>,
+    first_is_admin: bool,
+) -> Result<()> {
+    if state
+        .users
+        .insert_user(user, credential, invite, first_is_admin)
+        .await?
+    {
+        Ok(())
+    } else {
+        Err(MyError::InvalidInvite)
+    }
+}
 
-    users_guard
-        .keys
-        .entry(user.unique_id)
-        .and_modify(|keys| keys.push(sk.clone()))
-        .or_insert_with(|| vec![sk.clone()]);
+/// Mail the link that continues a registration in privacy mode, or tell the owner of a
+/// taken name that somebody tried to register it.
+pub async fn send_registration_email(
+    state: &AppState,
+    name: &str,
+    display_name: &str,
+    invite: Option<&str>,
+    is_taken: bool,
+) -> Result<()> {
+    require_email_address(name)?;
+    let email = if is_taken {
+        Email {
+            to: name.to_string(),
+            subject: "Registration attempt".to_string(),
+            body: format!(
+                "Somebody tried to register a new account for {}, but you already have one.\n\
+                 Log in with your passkey at {}. If it wasn't you, ignore this email.\n",
+                name, state.config.rp_origin
+            ),
+        }
+    } else {
+        let token = state.privacy.sign(&PendingRegistration {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            invite: invite.map(str::to_string),
+            expires_at: unix_now() + REGISTRATION_TOKEN_LIFETIME,
+        })?;
+        Email {
+            to: name.to_string(),
+            subject: "Confirm your registration".to_string(),
+            body: format!(
+                "Open this link to finish your registration and create a passkey:\n\
+                 {}/register?token={}\n\
+                 If you did not ask for it, ignore this email.\n",
+                state.config.rp_origin, token
+            ),
+        }
+    };
+    Ok(state.mailer.send(&email).await?)
+}
+
+/// Disabled users may not log in, or keep using the API.
+pub fn require_enabled(user: &User) -> Result<()> {
+    if user.disabled {
+        return Err(MyError::AccountDisabled);
+    }
+    Ok(())
+}
+
+/// Usernames that emails are sent to have to be addresses.
+pub fn require_email_address(name: &str) -> Result<()> {
+    if !name.contains('@') || name.chars().any(char::is_whitespace) {
+        return Err(MyError::InvalidInput(
+            "The username must be an email address".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Mail the user a link that logs them in, or lets them add a passkey.
+pub async fn send_magic_link_email(state: &AppState, user: &User, token: &str) -> Result<()> {
+    let email = Email {
+        to: user.name.clone(),
+        subject: format!("Log in to {}", state.config.rp_name()),
+        body: format!(
+            "Hello {},\n\n\
+             open this link to log in, it works once and expires soon:\n\
+             {}/magic_link?token={}\n\
+             If you did not ask for it, ignore this email.\n",
+            user.display_name, state.config.rp_origin, token
+        ),
+    };
+    Ok(state.mailer.send(&email).await?)
+}
 
-    users_guard
-        .name_to_id
-        .insert(user.name.to_string(), user.unique_id);
+/// An event about `request`, the handler fills in the user and credential it is about.
+pub fn audit_event(state: &AppState, request: &HttpRequest, action: AuditAction) -> AuditEvent {
+    AuditEvent {
+        timestamp: unix_now(),
+        action,
+        user_id: None,
+        actor: None,
+        credential_id: None,
+        client_ip: client_ip(request, state.config.rate_limit.trust_forwarded_for),
+        user_agent: request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string),
+        outcome: AUDIT_SUCCESS.to_string(),
+    }
 }
 
-pub fn get_user(users_guard: &MutexGuard<Users>, user_unique_id: Uuid) -> anyhow::Result<User> {
-    let user_name = users_guard
-        .name_to_id
-        .iter()
-        .find(|x| x.1 == &user_unique_id)
-        .ok_or_else(|| anyhow::Error::msg("Failed to parse user unique identity"))?
-        .0;
-    Ok(User {
-        unique_id: user_unique_id,
-        name: user_name.to_string(),
-        display_name: user_name.to_string(),
-    })
+/// Append `event` with the outcome of `result`. A broken audit log must not lock anybody
+/// out, so failing to write it is only logged.
+pub async fn record_audit_event<T>(state: &AppState, mut event: AuditEvent, result: &Result<T>) {
+    if let Err(err) = result {
+        event.outcome = err.code().to_string();
+    }
+    if let Err(err) = state.audit_log.record(&event).await {
+        error!("Failed to record {:?} {:#}", event, err);
+    }
 }
 
+pub async fn get_user(state: &AppState, user_unique_id: Uuid) -> Result<User> {
+    state
+        .users
+        .get_user(user_unique_id)
+        .await?
+        .ok_or(MyError::UserNotFound)
+}
+
+pub async fn update_profile(
+    state: &AppState,
+    user_unique_id: Uuid,
+    name: &str,
+    display_name: &str,
+) -> Result<ProfileUpdate> {
+    Ok(state
+        .users
+        .update_profile(user_unique_id, name, display_name)
+        .await?)
+}
+
+pub async fn list_credentials(
+    state: &AppState,
+    user_unique_id: Uuid,
+) -> Result<Vec<StoredCredential>> {
+    Ok(state.users.list_credentials(user_unique_id).await?)
+}
+
+pub async fn set_credential_nickname(
+    state: &AppState,
+    user_unique_id: Uuid,
+    cred_id: &str,
+    nickname: &str,
+) -> Result<bool> {
+    Ok(state
+        .users
+        .rename_credential(user_unique_id, cred_id, nickname)
+        .await?)
+}
+
+pub async fn remove_credential(
+    state: &AppState,
+    user_unique_id: Uuid,
+    cred_id: &str,
+) -> Result<CredentialRemoval> {
+    Ok(state
+        .users
+        .delete_credential(user_unique_id, cred_id)
+        .await?)
+}
+
+/// Passkeys have to be discoverable, so that they also work for the usernameless login.
 pub fn start_passkey_registration(
     state: &AppState,
     user: &User,
     exclude_credentials: Option<Vec<Base64UrlSafeData>>,
 ) -> Result<(CreationChallengeResponse, PasskeyRegistration)> {
-    state
+    let ca_list = match &state.attestation {
+        Some(ca_list) => ca_list.clone(),
+        None => {
+            let (ccr, reg_state) = state
+                .webauthn
+                .start_passkey_registration(
+                    user.unique_id,
+                    &user.name,
+                    &user.display_name,
+                    exclude_credentials,
+                )
+                .map_err(|e| {
+                    anyhow::Error::msg(format!("start_passkey_registration failed {}", e))
+                })?;
+            return Ok((require_resident_key(ccr), reg_state));
+        }
+    };
+    // Passwordless keys are passkeys that have to be attested by one of the CAs.
+    let (ccr, reg_state) = state
         .webauthn
-        .start_passkey_registration(
+        .start_passwordlesskey_registration(
             user.unique_id,
             &user.name,
             &user.display_name,
             exclude_credentials,
+            ca_list,
+            None,
         )
-        .map_err(|e| anyhow::Error::msg(format!("start_passkey_registration failed {}", e)))
+        .map_err(|e| {
+            anyhow::Error::msg(format!("start_passwordlesskey_registration failed {}", e))
+        })?;
+    Ok((
+        require_resident_key(ccr),
+        attestation::without_ca_list(reg_state)?,
+    ))
+}
+
+/// webauthn-rs 0.4 discourages resident keys for passkeys. `requireResidentKey` is the
+/// WebAuthn level 1 spelling of `residentKey: "required"`, which browsers still honour.
+fn require_resident_key(mut ccr: CreationChallengeResponse) -> CreationChallengeResponse {
+    if let Some(selection) = ccr.public_key.authenticator_selection.as_mut() {
+        selection.require_resident_key = true;
+    }
+    ccr
 }
 
 pub fn finish_passkey_registration(
     state: &AppState,
     reg: &RegisterPublicKeyCredential,
     reg_state: &PasskeyRegistration,
-) -> anyhow::Result<Passkey> {
-    state
-        .webauthn
-        .finish_passkey_registration(reg, reg_state)
-        .map_err(|e| anyhow::Error::msg(format!("finish_passkey_registration failed {}", &e)))
+) -> Result<StoredCredential> {
+    let passkey = match &state.attestation {
+        None => state
+            .webauthn
+            .finish_passkey_registration(reg, reg_state)
+            .map_err(MyError::RegistrationFailed)?,
+        Some(ca_list) => {
+            let reg_state = attestation::with_ca_list(reg_state, ca_list)?;
+            let key = state
+                .webauthn
+                .finish_passwordlesskey_registration(reg, &reg_state)
+                .map_err(|e| match e {
+                    WebauthnError::AttestationNotVerifiable
+                    | WebauthnError::AttestationTrustFailure
+                    | WebauthnError::AttestationChainNotTrusted(_)
+                    | WebauthnError::AttestationUntrustedAaguid
+                    | WebauthnError::AttestationFormatMissingAaguid => {
+                        MyError::UntrustedAuthenticator(e)
+                    }
+                    e => MyError::RegistrationFailed(e),
+                })?;
+            Passkey::from(Credential::from(key))
+        }
+    };
+    Ok(StoredCredential::new(passkey, attestation::aaguid(reg)))
 }
 
 pub async fn update_credential(
@@ -136,29 +365,20 @@ pub async fn update_credential(
     user_unique_id: Uuid,
     auth_result: &AuthenticationResult,
 ) -> Result<()> {
-    let mut users_guard = state.users.lock().await;
-    users_guard
-        .keys
-        .get_mut(&user_unique_id)
-        .map(|keys| {
-            keys.iter_mut().for_each(|sk| {
-                // This will update the credential if it's the matching
-                // one. Otherwise it's ignored. That is why it is safe to
-                // iterate this over the full list.
-                sk.update_credential(auth_result);
-            })
-        })
-        .ok_or_else(|| anyhow::Error::msg("User has no credentials"))
+    Ok(state
+        .users
+        .update_credential(user_unique_id, auth_result)
+        .await?)
 }
 
 pub fn start_passkey_authentication(
     state: &AppState,
     allow_credentials: &[Passkey],
-) -> anyhow::Result<(RequestChallengeResponse, PasskeyAuthentication)> {
+) -> Result<(RequestChallengeResponse, PasskeyAuthentication)> {
     state
         .webauthn
         .start_passkey_authentication(allow_credentials)
-        .map_err(|e| anyhow::Error::msg(format!("passkey authentication failed {}", e)))
+        .map_err(|e| anyhow::Error::msg(format!("passkey authentication failed {}", e)).into())
 }
 
 pub fn finish_passkey_authentication(
@@ -169,5 +389,42 @@ pub fn finish_passkey_authentication(
     state
         .webauthn
         .finish_passkey_authentication(auth, auth_state)
-        .map_err(|e| anyhow::Error::msg(format!("finish passkey authentication failed {}", e)))
+        .map_err(MyError::AuthenticationFailed)
+}
+
+/// Start an authentication without knowing the user, the authenticator offers every
+/// discoverable credential it holds for this relying party.
+pub fn start_discoverable_authentication(
+    state: &AppState,
+) -> Result<(RequestChallengeResponse, DiscoverableAuthentication)> {
+    state
+        .webauthn
+        .start_discoverable_authentication()
+        .map_err(|e| anyhow::Error::msg(format!("discoverable authentication failed {}", e)).into())
+}
+
+/// The user handle returned by the authenticator is the unique id the credential was
+/// registered with.
+pub fn identify_discoverable_authentication(
+    state: &AppState,
+    auth: &PublicKeyCredential,
+) -> Result<Uuid> {
+    state
+        .webauthn
+        .identify_discoverable_authentication(auth)
+        .map(|(user_unique_id, _)| user_unique_id)
+        .map_err(MyError::AuthenticationFailed)
+}
+
+pub fn finish_discoverable_authentication(
+    state: &AppState,
+    auth: &PublicKeyCredential,
+    auth_state: DiscoverableAuthentication,
+    credentials: &[Passkey],
+) -> Result<AuthenticationResult> {
+    let credentials: Vec<DiscoverableKey> = credentials.iter().map(DiscoverableKey::from).collect();
+    state
+        .webauthn
+        .finish_discoverable_authentication(auth, auth_state, &credentials)
+        .map_err(MyError::AuthenticationFailed)
 }
@@ -0,0 +1,220 @@
+use actix_web::web;
+use anyhow::Result;
+use log::info;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+
+pub type DbPool = Pool<SqliteConnectionManager>;
+
+/// Schema migrations, applied in order. The index of the last applied migration + 1 is
+/// tracked in SQLite's `user_version` pragma, so only append to this list and never edit
+/// an entry that has already shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: users and their passkeys
+    "CREATE TABLE users (
+        unique_id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE credentials (
+        cred_id TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE,
+        passkey TEXT NOT NULL
+    );
+    CREATE INDEX credentials_user_id ON credentials (user_id);",
+    // 2: server side sessions, expires_at is in unix seconds
+    "CREATE TABLE sessions (
+        session_key TEXT PRIMARY KEY NOT NULL,
+        state TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_expires_at ON sessions (expires_at);",
+    // 3: credential metadata, existing passkeys count as created now
+    "ALTER TABLE credentials ADD COLUMN nickname TEXT NOT NULL DEFAULT 'Passkey';
+    ALTER TABLE credentials ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE credentials ADD COLUMN last_used_at INTEGER;
+    UPDATE credentials SET created_at = CAST(strftime('%s', 'now') AS INTEGER);",
+    // 4: user profiles, the display name used to be the username
+    "ALTER TABLE users ADD COLUMN display_name TEXT NOT NULL DEFAULT '';
+    ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE users SET
+        display_name = name,
+        created_at = CAST(strftime('%s', 'now') AS INTEGER);",
+    // 5: the authenticator model of a credential, unknown for existing ones
+    "ALTER TABLE credentials ADD COLUMN aaguid TEXT;",
+    // 6: audit log, kept when its user is deleted
+    "CREATE TABLE audit_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        user_id TEXT,
+        credential_id TEXT,
+        client_ip TEXT,
+        user_agent TEXT,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX audit_events_user_id ON audit_events (user_id, id);",
+    // 7: refresh tokens of API clients, by their SHA-256
+    "CREATE TABLE refresh_tokens (
+        token_hash TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX refresh_tokens_expires_at ON refresh_tokens (expires_at);",
+    // 8: hashes of the unused recovery codes
+    "CREATE TABLE recovery_codes (
+        code_hash TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE
+    );
+    CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);",
+    // 9: nonces of magic links that have been used, until the links expire
+    "CREATE TABLE used_magic_links (
+        nonce TEXT PRIMARY KEY NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX used_magic_links_expires_at ON used_magic_links (expires_at);",
+    // 10: hashes of the unused invite codes for the invite-only registration policy
+    "CREATE TABLE invites (
+        code_hash TEXT PRIMARY KEY NOT NULL,
+        expires_at INTEGER NOT NULL
+    );",
+    // 11: roles as a JSON array and disabled accounts
+    "ALTER TABLE users ADD COLUMN roles TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;",
+    // 12: the administrator behind an audit event, unknown for existing ones
+    "ALTER TABLE audit_events ADD COLUMN actor TEXT;",
+    // 13: the user a session is logged in as, taken from the identity in the state
+    "ALTER TABLE sessions ADD COLUMN user_id TEXT;
+    UPDATE sessions SET user_id = CASE
+        WHEN json_valid(json_extract(state, '$.\"actix_identity.user_id\"'))
+        THEN json_extract(json_extract(state, '$.\"actix_identity.user_id\"'), '$')
+    END
+    WHERE json_valid(state);
+    CREATE INDEX sessions_user_id ON sessions (user_id);",
+];
+
+/// Open the database at `path` and bring its schema up to date.
+pub fn open(path: &str) -> Result<Connection> {
+    let mut conn = Connection::open(path)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to open database {} {}", path, e)))?;
+    // WAL lets readers proceed while another connection is writing. The setting is
+    // stored in the database file, so it only needs to be applied once.
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    configure(&mut conn)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// Migrate the database at `path` once, then hand out pooled connections to it.
+pub fn pool(path: &str) -> Result<DbPool> {
+    open(path)?;
+    let manager = SqliteConnectionManager::file(path).with_init(configure);
+    Pool::new(manager)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to open database {} {}", path, e)))
+}
+
+/// Run `f` with a pooled connection on the blocking thread pool, so queries never stall
+/// a worker's event loop.
+pub async fn with_conn<T, F>(pool: &DbPool, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| anyhow::Error::msg(format!("Failed to get database connection {}", e)))?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| anyhow::Error::msg(format!("Database task failed {}", e)))?
+}
+
+/// Settings that SQLite only keeps per connection.
+pub fn configure(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))
+}
+
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Applying database migration {}", index + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// A database in the temporary directory for tests. It is removed together with its
+/// `-wal` and `-shm` files when dropped, also when the test fails.
+#[cfg(test)]
+pub struct TempDb {
+    pub path: String,
+}
+
+#[cfg(test)]
+impl TempDb {
+    pub fn new(name: &str) -> Self {
+        let file = format!("webauthn-{}-{}.db", name, uuid::Uuid::new_v4());
+        let path = std::env::temp_dir().join(file);
+        Self {
+            path: path.to_str().unwrap().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.path, suffix));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migration_finds_the_user_of_existing_sessions() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (
+                session_key TEXT PRIMARY KEY NOT NULL,
+                state TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            INSERT INTO sessions VALUES
+                ('alice', '{\"actix_identity.user_id\":\"\\\"alice-id\\\"\"}', 0),
+                ('anonymous', '{\"reg_state\":\"{}\"}', 0),
+                ('odd', '{\"actix_identity.user_id\":\"alice-id\"}', 0),
+                ('broken', '{', 0);",
+        )
+        .unwrap();
+
+        conn.execute_batch(MIGRATIONS[12]).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT session_key, user_id FROM sessions ORDER BY session_key")
+            .unwrap();
+        let rows: Vec<(String, Option<String>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("alice".to_string(), Some("alice-id".to_string())),
+                ("anonymous".to_string(), None),
+                ("broken".to_string(), None),
+                ("odd".to_string(), None),
+            ]
+        );
+    }
+}
@@ -1,6 +1,14 @@
-use actix_web::{error, http::StatusCode};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
 use log::error;
+use serde::Serialize;
+use utoipa::ToSchema;
+use webauthn_rs::prelude::WebauthnError;
 
+/// Turns any error into a plain actix error with the given message, for handlers that do
+/// not answer with [MyError] problem details.
+#[allow(dead_code)]
 pub trait IntoHttpError<T> {
     fn http_error(
         self,
@@ -26,27 +34,194 @@ impl<T, E: std::fmt::Debug> IntoHttpError<T> for core::result::Result<T, E> {
             Ok(val) => Ok(val),
             Err(err) => {
                 error!("http_error: {:?}", err);
-                Err(error::InternalError::new(message.to_string(), status_code).into())
+                Err(actix_web::error::InternalError::new(message.to_string(), status_code).into())
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub struct MyError {
-    err: anyhow::Error,
+pub type Result<T, E = MyError> = std::result::Result<T, E>;
+
+/// Everything a handler can fail with. The [code](MyError::code) is part of the API, the
+/// client branches on it, so existing codes must not change.
+#[derive(Debug, thiserror::Error)]
+pub enum MyError {
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Not logged in")]
+    Unauthenticated,
+    #[error("No registration or login is in progress for this session")]
+    SessionState,
+    #[error("Registration failed {0}")]
+    RegistrationFailed(#[source] WebauthnError),
+    #[error("Authentication failed {0}")]
+    AuthenticationFailed(#[source] WebauthnError),
+    #[error("User not found")]
+    UserNotFound,
+    #[error("User has no credentials")]
+    NoCredentials,
+    #[error("Credential not found")]
+    CredentialNotFound,
+    #[error("Username is already taken")]
+    UsernameTaken,
+    #[error("The last credential cannot be deleted, register another one first")]
+    LastCredential,
+    #[error("The link is invalid or has expired")]
+    InvalidToken,
+    #[error("The refresh token is invalid, expired or has been used already")]
+    InvalidRefreshToken,
+    #[error("The username or recovery code is wrong")]
+    InvalidRecoveryCode,
+    #[error("Registration of new accounts is closed")]
+    RegistrationClosed,
+    #[error("The invite code is missing, invalid or has been used")]
+    InvalidInvite,
+    #[error("Accounts with this email domain cannot be registered")]
+    DomainNotAllowed,
+    #[error("This requires the {0} role")]
+    MissingRole(&'static str),
+    #[error("The account has been disabled")]
+    AccountDisabled,
+    #[error("The authenticator is not on the list of trusted authenticators {0}")]
+    UntrustedAuthenticator(#[source] WebauthnError),
+    #[error("Too many requests, retry in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl MyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MyError::InvalidInput(_) => "invalid_input",
+            MyError::Unauthenticated => "unauthenticated",
+            MyError::SessionState => "session_state_missing",
+            MyError::RegistrationFailed(_) => "registration_failed",
+            MyError::AuthenticationFailed(_) => "authentication_failed",
+            MyError::UserNotFound => "user_not_found",
+            MyError::NoCredentials => "no_credentials",
+            MyError::CredentialNotFound => "credential_not_found",
+            MyError::UsernameTaken => "username_taken",
+            MyError::LastCredential => "last_credential",
+            MyError::InvalidToken => "invalid_token",
+            MyError::InvalidRefreshToken => "invalid_refresh_token",
+            MyError::InvalidRecoveryCode => "invalid_recovery_code",
+            MyError::RegistrationClosed => "registration_closed",
+            MyError::InvalidInvite => "invalid_invite",
+            MyError::DomainNotAllowed => "domain_not_allowed",
+            MyError::MissingRole(_) => "missing_role",
+            MyError::AccountDisabled => "account_disabled",
+            MyError::UntrustedAuthenticator(_) => "untrusted_authenticator",
+            MyError::RateLimited { .. } => "rate_limited",
+            MyError::Internal(_) => "internal_error",
+        }
+    }
 }
 
-impl std::fmt::Display for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.err.fmt(f)
+/// An RFC 7807 problem details object, extended with the stable `code` of the error.
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct Problem {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl actix_web::error::ResponseError for MyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MyError::InvalidInput(_)
+            | MyError::SessionState
+            | MyError::RegistrationFailed(_)
+            | MyError::InvalidToken => StatusCode::BAD_REQUEST,
+            MyError::Unauthenticated
+            | MyError::AuthenticationFailed(_)
+            | MyError::InvalidRefreshToken
+            | MyError::InvalidRecoveryCode => StatusCode::UNAUTHORIZED,
+            MyError::UserNotFound | MyError::NoCredentials | MyError::CredentialNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            MyError::RegistrationClosed
+            | MyError::InvalidInvite
+            | MyError::DomainNotAllowed
+            | MyError::MissingRole(_)
+            | MyError::AccountDisabled
+            | MyError::UntrustedAuthenticator(_) => StatusCode::FORBIDDEN,
+            MyError::UsernameTaken | MyError::LastCredential => StatusCode::CONFLICT,
+            MyError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            MyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // Internal errors may contain details about the database or the session store,
+        // they are logged instead of being sent to the client.
+        let detail = match self {
+            MyError::Internal(err) => {
+                error!("{:#}", err);
+                "Internal server error".to_string()
+            }
+            err => err.to_string(),
+        };
+        let problem = Problem {
+            type_: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail,
+            code: self.code(),
+        };
+        let mut response = HttpResponse::build(status);
+        if let MyError::RateLimited { retry_after } = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response
+            .content_type("application/problem+json")
+            .json(problem)
     }
 }
 
-impl actix_web::error::ResponseError for MyError {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::error::ResponseError;
+
+    #[actix_web::test]
+    async fn errors_are_problem_details() {
+        let resp = MyError::UsernameTaken.error_response();
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/problem+json"
+        );
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["status"], 409);
+        assert_eq!(problem["title"], "Conflict");
+        assert_eq!(problem["code"], "username_taken");
+        assert_eq!(problem["detail"], "Username is already taken");
+    }
+
+    #[test]
+    fn rate_limited_errors_tell_when_to_retry() {
+        let resp = MyError::RateLimited { retry_after: 12 }.error_response();
+
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "12");
+    }
+
+    #[actix_web::test]
+    async fn internal_errors_hide_their_details() {
+        let resp = MyError::from(anyhow::Error::msg("database is locked")).error_response();
 
-impl From<anyhow::Error> for MyError {
-    fn from(err: anyhow::Error) -> Self {
-        Self { err }
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "internal_error");
+        assert_eq!(problem["detail"], "Internal server error");
     }
 }
@@ -0,0 +1,179 @@
+use crate::models::unix_now;
+use actix_web::http::header::{self, HeaderMap};
+use anyhow::Result;
+use log::info;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// The `typ` of access tokens, see RFC 9068.
+pub const ACCESS_TOKEN: &str = "at+jwt";
+
+/// base64url without padding, as in JWTs. Also used for the other random and signed
+/// tokens the server hands out.
+pub fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// The claims every token carries, checked by [JwtKeys::verify].
+#[derive(Debug, Deserialize)]
+struct Expiry {
+    exp: i64,
+}
+
+/// The RSA key signing the tokens this server issues, published as a JWK so that other
+/// services can verify them without asking us.
+pub struct JwtKeys {
+    key: PKey<Private>,
+    kid: String,
+}
+
+impl JwtKeys {
+    pub fn new(key: PKey<Private>) -> Result<Self> {
+        // Derived from the public key, so the id changes whenever the key does.
+        let kid = encode(&hash(MessageDigest::sha256(), &key.public_key_to_der()?)?[..12]);
+        Ok(Self { key, kid })
+    }
+
+    /// Read the PEM encoded key from `path`, or generate one and store it there if the
+    /// file is missing.
+    pub fn read_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let pem = std::fs::read(path).map_err(|e| {
+                anyhow::Error::msg(format!("Failed to read {} {}", path.display(), e))
+            })?;
+            return Self::new(PKey::private_key_from_pem(&pem)?);
+        }
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let pem = key.private_key_to_pem_pkcs8()?;
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(&pem))
+            .map_err(|e| anyhow::Error::msg(format!("Failed to write {} {}", path.display(), e)))?;
+        info!("generated a new token signing key in {}", path.display());
+        Self::new(key)
+    }
+
+    /// Sign `claims` with RS256, `typ` tells the kinds of token apart, e.g. `JWT` for
+    /// ID tokens and [ACCESS_TOKEN] for access tokens.
+    pub fn sign<T: Serialize>(&self, typ: &str, claims: &T) -> Result<String> {
+        let header = Header {
+            alg: "RS256".to_string(),
+            typ: typ.to_string(),
+            kid: self.kid.clone(),
+        };
+        let signed = format!(
+            "{}.{}",
+            encode(&serde_json::to_vec(&header)?),
+            encode(&serde_json::to_vec(claims)?)
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(signed.as_bytes())?;
+        Ok(format!("{}.{}", signed, encode(&signer.sign_to_vec()?)))
+    }
+
+    /// The claims of a token of kind `typ` signed with this key, unless it has expired.
+    pub fn verify<T: DeserializeOwned>(&self, typ: &str, token: &str) -> Option<T> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+        let header: Header = serde_json::from_slice(&decode(header)?).ok()?;
+        if header.alg != "RS256" || header.typ != typ || header.kid != self.kid {
+            return None;
+        }
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &self.key).ok()?;
+        verifier.update(signed.as_bytes()).ok()?;
+        if !verifier.verify(&decode(signature)?).ok()? {
+            return None;
+        }
+        let claims = decode(claims)?;
+        let expiry: Expiry = serde_json::from_slice(&claims).ok()?;
+        if expiry.exp <= unix_now() {
+            return None;
+        }
+        serde_json::from_slice(&claims).ok()
+    }
+
+    /// The public key as a JSON Web Key Set.
+    pub fn jwks(&self) -> Result<serde_json::Value> {
+        let rsa = self.key.rsa()?;
+        Ok(serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.kid,
+                "n": encode(&rsa.n().to_vec()),
+                "e": encode(&rsa.e().to_vec()),
+            }]
+        }))
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let token = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    Some(token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn new_keys() -> JwtKeys {
+        JwtKeys::new(PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tokens_are_only_accepted_unchanged_and_unexpired() {
+        let keys = new_keys();
+        let claims = Claims {
+            sub: "alice".to_string(),
+            exp: unix_now() + 60,
+        };
+        let token = keys.sign("JWT", &claims).unwrap();
+
+        assert_eq!(keys.verify::<Claims>("JWT", &token), Some(claims));
+        // Another kind of token, another key, a changed payload or an expired token.
+        assert_eq!(keys.verify::<Claims>("at+jwt", &token), None);
+        assert_eq!(new_keys().verify::<Claims>("JWT", &token), None);
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = encode(br#"{"sub":"mallory","exp":9999999999}"#);
+        parts[1] = &forged;
+        assert_eq!(keys.verify::<Claims>("JWT", &parts.join(".")), None);
+        let expired = Claims {
+            sub: "alice".to_string(),
+            exp: unix_now() - 1,
+        };
+        let token = keys.sign("JWT", &expired).unwrap();
+        assert_eq!(keys.verify::<Claims>("JWT", &token), None);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::config::{MagicLinkConfig, MagicLinkMode};
+use crate::errors::MyError;
+use crate::jwt::encode;
+use crate::magic_link_store::MagicLinkStore;
+use crate::models::{unix_now, User};
+use crate::privacy::hmac;
+use anyhow::Result;
+use openssl::pkey::{PKey, Private};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// The body of `/magic_link`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MagicLinkRequest {
+    /// The username, which has to be an email address
+    pub name: String,
+}
+
+/// The body of `/magic_link/verify`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MagicLinkVerification {
+    /// The `token` of the link in the email
+    pub token: String,
+}
+
+/// The answer to a verified magic link.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MagicLinkVerified {
+    #[serde(flatten)]
+    pub user: User,
+    /// False if the server only allows magic links to add a passkey, the session may then
+    /// register one for the user with `/register_start` and `/register_finish`.
+    pub logged_in: bool,
+    /// Where the client should send the browser next, set when the login completes an
+    /// OpenID Connect authorization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Claims {
+    sub: Uuid,
+    nonce: String,
+    exp: i64,
+}
+
+/// Signed, single-use links that log users in without a passkey, for devices without a
+/// platform authenticator.
+///
+/// The token in the link is signed with a key derived from the session key, like the
+/// registration links of [crate::privacy]. Its random nonce is recorded when it is used,
+/// so following the link a second time fails.
+pub struct MagicLinks {
+    config: MagicLinkConfig,
+    key: PKey<Private>,
+    used: Arc<dyn MagicLinkStore>,
+}
+
+impl MagicLinks {
+    pub fn new(
+        config: MagicLinkConfig,
+        secret: &[u8],
+        used: Arc<dyn MagicLinkStore>,
+    ) -> Result<Self> {
+        let derived = hmac(&PKey::hmac(secret)?, &[b"webauthn magic link"])?;
+        Ok(Self {
+            config,
+            key: PKey::hmac(&derived)?,
+            used,
+        })
+    }
+
+    /// `None` unless `magic_link.enabled` is set.
+    pub fn from_config(
+        config: &MagicLinkConfig,
+        secret: &[u8],
+        used: Arc<dyn MagicLinkStore>,
+    ) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(config.clone(), secret, used)?))
+    }
+
+    pub fn mode(&self) -> MagicLinkMode {
+        self.config.mode
+    }
+
+    /// A token for the link mailed to the user.
+    pub fn issue(&self, user_id: Uuid) -> Result<String> {
+        let mut nonce = [0; 16];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let claims = Claims {
+            sub: user_id,
+            nonce: encode(&nonce),
+            exp: unix_now() + self.config.lifetime,
+        };
+        let payload = serde_json::to_vec(&claims)?;
+        let signature = hmac(&self.key, &[b"magic link", &payload])?;
+        Ok(format!("{}.{}", encode(&payload), encode(&signature)))
+    }
+
+    /// The user of the link, if `token` is valid, has not expired and was not used before.
+    pub async fn redeem(&self, token: &str) -> Result<Uuid, MyError> {
+        let claims = self.verify(token).ok_or(MyError::InvalidToken)?;
+        if !self.used.use_nonce(&claims.nonce, claims.exp).await? {
+            return Err(MyError::InvalidToken);
+        }
+        Ok(claims.sub)
+    }
+
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let (payload, signature) = token.split_once('.')?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let expected = hmac(&self.key, &[b"magic link", &payload]).ok()?;
+        if signature.len() != expected.len() || !openssl::memcmp::eq(&signature, &expected) {
+            return None;
+        }
+        let claims: Claims = serde_json::from_slice(&payload).ok()?;
+        (claims.exp > unix_now()).then_some(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic_link_store::MemoryMagicLinkStore;
+
+    fn magic_links(secret: &[u8], lifetime: i64) -> MagicLinks {
+        let config = MagicLinkConfig {
+            enabled: true,
+            lifetime,
+            ..MagicLinkConfig::default()
+        };
+        MagicLinks::new(config, secret, Arc::new(MemoryMagicLinkStore::default())).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn links_work_once_until_they_expire() {
+        let links = magic_links(b"secret", 60);
+        let user_id = Uuid::new_v4();
+
+        let token = links.issue(user_id).unwrap();
+        let other = magic_links(b"another secret", 60);
+        assert!(matches!(
+            other.redeem(&token).await,
+            Err(MyError::InvalidToken)
+        ));
+        assert_eq!(links.redeem(&token).await.unwrap(), user_id);
+        assert!(matches!(
+            links.redeem(&token).await,
+            Err(MyError::InvalidToken)
+        ));
+
+        let expired = magic_links(b"secret", -1).issue(user_id).unwrap();
+        assert!(matches!(
+            links.redeem(&expired).await,
+            Err(MyError::InvalidToken)
+        ));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::models::unix_now;
+use anyhow::Result;
+use async_std::sync::RwLock;
+use std::collections::HashMap;
+
+/// Remembers which magic links have been used, so each one logs in only once. A link is
+/// known by the nonce it carries and forgotten once it has expired anyway.
+#[async_trait::async_trait]
+pub trait MagicLinkStore: Send + Sync {
+    /// Mark the nonce as used, false if it was used before.
+    async fn use_nonce(&self, nonce: &str, expires_at: i64) -> Result<bool>;
+}
+
+/// Keeps the nonces in memory next to a [crate::user_store::MemoryUserStore], a link
+/// used before a restart works once more afterwards.
+#[derive(Default)]
+pub struct MemoryMagicLinkStore {
+    used: RwLock<HashMap<String, i64>>,
+}
+
+#[async_trait::async_trait]
+impl MagicLinkStore for MemoryMagicLinkStore {
+    async fn use_nonce(&self, nonce: &str, expires_at: i64) -> Result<bool> {
+        let now = unix_now();
+        let mut used = self.used.write().await;
+        used.retain(|_, expires_at| *expires_at > now);
+        Ok(used.insert(nonce.to_string(), expires_at).is_none())
+    }
+}
@@ -4,90 +4,262 @@ use actix_cors::Cors;
 use actix_identity::IdentityMiddleware;
 use actix_session::config::CookieContentSecurity;
 use actix_session::SessionMiddleware;
-use actix_web::cookie::{Key, SameSite};
+use actix_web::body::MessageBody;
+use actix_web::cookie::SameSite;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
-use async_std::sync::Mutex;
-use config::Config;
+use api_tokens::ApiTokens;
+use audit_log::{AuditLog, MemoryAuditLog};
+use config::{Command, Config, SessionStorage};
+use magic_link::MagicLinks;
+use magic_link_store::{MagicLinkStore, MemoryMagicLinkStore};
+use metrics::{Metrics, RequestMetrics};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use session_store::MemorySessionStore;
-use std::collections::HashMap;
+use privacy::Privacy;
+use rate_limit::RateLimiter;
+use refresh_token_store::{MemoryRefreshTokenStore, RefreshTokenStore};
+use session_key::SessionKeys;
+use session_store::{MemorySessionStore, SessionBackend};
+use sqlite_audit_log::SqliteAuditLog;
+use sqlite_magic_link_store::SqliteMagicLinkStore;
+use sqlite_refresh_token_store::SqliteRefreshTokenStore;
+use sqlite_session_store::SqliteSessionStore;
+use sqlite_user_store::SqliteUserStore;
 use std::sync::Arc;
+use user_store::{MemoryUserStore, UserStore};
 use webauthn_rs::prelude::Url;
 use webauthn_rs::WebauthnBuilder;
 mod actions;
+mod admin;
+mod api_tokens;
+mod attestation;
+mod audit_log;
 mod auth;
 mod config;
+mod db;
 mod errors;
+mod jwt;
+mod magic_link;
+mod magic_link_store;
+mod mailer;
+mod metrics;
 mod models;
+mod oidc;
+mod openapi;
+mod privacy;
+mod rate_limit;
+mod recovery;
+mod refresh_token_store;
+mod registration_policy;
+mod roles;
+mod session_key;
 mod session_store;
+mod sqlite_audit_log;
+mod sqlite_magic_link_store;
+mod sqlite_refresh_token_store;
+mod sqlite_session_store;
+mod sqlite_user_store;
+#[cfg(test)]
+mod tests;
+mod user_store;
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=debug");
-    env_logger::init();
+async fn main() -> anyhow::Result<()> {
+    let (config, command) = Config::load()?;
+    let config = Arc::new(config);
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    if let Some(command) = command {
+        return run_command(&config, command).await;
+    }
+
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-    builder.set_private_key_file("certs/server.key", SslFiletype::PEM)?;
-    builder.set_certificate_chain_file("certs/server.crt")?;
+    builder.set_private_key_file(&config.tls.private_key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.tls.certificate)?;
 
-    let config = Arc::new(Config {
-        endpoint: "localhost:443".to_string(),
-        rp_id: "localhost".to_string(),
-        rp_origin: "https://localhost:8443".to_string(),
-        redirect_logout: "".to_string(),
-    });
+    let webauthn = {
+        let rp_origin = Url::parse(&config.rp_origin)?;
+        WebauthnBuilder::new(&config.rp_id, &rp_origin)?
+            .rp_name(config.rp_name())
+            .build()?
+    };
+    let webauthn = Arc::new(webauthn);
 
-    let webauthn = Arc::new(
-        {
-            let rp_origin = Url::parse(&config.rp_origin).expect("Invalid URL");
-            let builder =
-                WebauthnBuilder::new(&config.rp_id, &rp_origin).expect("Invalid configuration");
-            builder.build()
-        }
-        .expect("Invalid configuration"),
-    );
+    let (users, audit_log, refresh_tokens): (
+        Arc<dyn UserStore>,
+        Arc<dyn AuditLog>,
+        Arc<dyn RefreshTokenStore>,
+    ) = match &config.database {
+        Some(path) => (
+            Arc::new(SqliteUserStore::open(path)?),
+            Arc::new(SqliteAuditLog::open(path)?),
+            Arc::new(SqliteRefreshTokenStore::open(path)?),
+        ),
+        None => (
+            Arc::new(MemoryUserStore::default()),
+            Arc::new(MemoryAuditLog::default()),
+            Arc::new(MemoryRefreshTokenStore::default()),
+        ),
+    };
+    roles::promote_admins(&config, users.as_ref()).await?;
+    let used_magic_links: Arc<dyn MagicLinkStore> = match &config.database {
+        Some(path) => Arc::new(SqliteMagicLinkStore::open(path)?),
+        None => Arc::new(MemoryMagicLinkStore::default()),
+    };
 
-    let session_key = {
-        let secret: &Vec<u8> = &(0..64).collect();
-        Key::from(secret)
+    let sessions = match &config.session_storage {
+        SessionStorage::Memory => {
+            SessionBackend::Memory(MemorySessionStore::new(config.max_sessions))
+        }
+        SessionStorage::Sqlite { path } => SessionBackend::Sqlite(SqliteSessionStore::open(path)?),
     };
+    sessions.spawn_reaper(std::time::Duration::from_secs(60));
+
+    let session_keys = SessionKeys::load(&config.session_key, config.dev_mode)?;
+
+    // Built once and shared by all workers, including the session store. Constructing it
+    // inside the `HttpServer::new` closure would give every worker thread its own copy.
+    let state = web::Data::new(AppState {
+        config: config.clone(),
+        webauthn,
+        users,
+        sessions,
+        rate_limiter: RateLimiter::new(&config.rate_limit),
+        privacy: Privacy::new(session_keys.current.master())?,
+        mailer: mailer::from_config(&config.mailer, &config.mail_from)?,
+        attestation: attestation::ca_list(&config.attestation)?,
+        audit_log,
+        metrics: Metrics::default(),
+        oidc: oidc::Oidc::from_config(&config.oidc)?,
+        api_tokens: ApiTokens::from_config(&config.api_tokens, &config.rp_origin, refresh_tokens)?,
+        magic_links: MagicLinks::from_config(
+            &config.magic_link,
+            session_keys.current.master(),
+            used_magic_links,
+        )?,
+    });
+
+    HttpServer::new(move || app(state.clone(), session_keys.clone()))
+        .bind_openssl(&config.endpoint, builder)?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Run a maintenance task against the database of the server, instead of the server.
+async fn run_command(config: &Config, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Invite => {
+            // An in-memory store would be gone as soon as the command exits.
+            let path = config.database.as_deref().ok_or_else(|| {
+                anyhow::Error::msg("Invites are stored in the database, set `database` first")
+            })?;
+            let users = SqliteUserStore::open(path)?;
+            let invite =
+                registration_policy::create_invite(&users, config.registration.invite_lifetime)
+                    .await?;
+            println!("{}", invite.code);
+        }
+    }
+    Ok(())
+}
 
-    let config2 = config.clone();
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin(&config.rp_origin)
-            .allow_any_header()
-            .allow_any_method()
-            .supports_credentials();
-        App::new()
-            .wrap(IdentityMiddleware::default())
-            .wrap(
-                SessionMiddleware::builder(MemorySessionStore::default(), session_key.clone())
-                    .cookie_content_security(CookieContentSecurity::Private)
-                    .cookie_http_only(true)
-                    .cookie_secure(true)
-                    .cookie_same_site(SameSite::None)
-                    .build(),
-            )
-            .wrap(cors)
-            .wrap(Logger::default())
-            .app_data(web::Data::new(AppState {
-                config: config.clone(),
-                webauthn: webauthn.clone(),
-                users: Mutex::new(Users {
-                    name_to_id: HashMap::new(),
-                    keys: HashMap::new(),
-                }),
-            }))
-            .service(index)
-            .service(get_identity)
-            .service(logout)
-            .service(register_start)
-            .service(register_finish)
-            .service(login_start)
-            .service(login_finish)
-    })
-    .bind_openssl(&config2.endpoint, builder)?
-    .run()
-    .await
+pub fn app(
+    state: web::Data<AppState>,
+    session_keys: SessionKeys,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let cors = state
+        .config
+        .cors_origins()
+        .into_iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allow_any_header()
+        .allow_any_method()
+        // The client tells rate limited users how long to wait.
+        .expose_headers([RETRY_AFTER])
+        .supports_credentials();
+    let cookie = &state.config.cookie;
+    let same_site = state.config.same_site().unwrap_or(SameSite::None);
+    let cookie_name = cookie.name.clone();
+    let oidc_enabled = state.oidc.is_some();
+    let magic_links_enabled = state.magic_links.is_some();
+    App::new()
+        .wrap(IdentityMiddleware::default())
+        .wrap(
+            SessionMiddleware::builder(state.sessions.clone(), session_keys.current.clone())
+                .cookie_name(cookie.name.clone())
+                .cookie_domain(cookie.domain.clone())
+                .cookie_content_security(CookieContentSecurity::Private)
+                .cookie_http_only(cookie.http_only)
+                .cookie_secure(cookie.secure)
+                .cookie_same_site(same_site)
+                .build(),
+        )
+        // Runs before the session middleware so it only ever sees the current key.
+        .wrap_fn(move |mut req, srv| {
+            session_keys.reseal(&mut req, &cookie_name);
+            srv.call(req)
+        })
+        .wrap(cors)
+        .wrap(RequestMetrics)
+        .wrap(Logger::default())
+        .app_data(state)
+        .app_data(
+            web::JsonConfig::default()
+                .error_handler(|err, _| errors::MyError::InvalidInput(err.to_string()).into()),
+        )
+        .service(index)
+        .service(get_session_stats)
+        .service(get_metrics)
+        .service(get_openapi)
+        .service(get_identity)
+        .service(update_user_profile)
+        .service(get_credentials)
+        .service(rename_credential)
+        .service(delete_credential)
+        .service(get_security_events)
+        .service(logout)
+        .service(register_start)
+        .service(register_verify)
+        .service(register_finish)
+        .service(login_start)
+        .service(login_finish)
+        .service(login_discoverable_start)
+        .service(login_discoverable_finish)
+        .service(recover)
+        .service(get_recovery_codes)
+        .service(regenerate_recovery_codes)
+        .service(issue_api_tokens)
+        .service(refresh_api_tokens)
+        .service(revoke_api_token)
+        .service(admin_list_users)
+        .service(admin_get_user)
+        .service(admin_disable_user)
+        .service(admin_enable_user)
+        .service(admin_revoke_credential)
+        .service(admin_logout_user)
+        .service(admin_create_invite)
+        .configure(|cfg| {
+            if oidc_enabled {
+                cfg.service(oidc_discovery)
+                    .service(oidc_jwks)
+                    .service(oidc_authorize)
+                    .service(oidc_token)
+                    .service(oidc_userinfo);
+            }
+            if magic_links_enabled {
+                cfg.service(request_magic_link).service(verify_magic_link);
+            }
+        })
 }
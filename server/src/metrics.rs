@@ -0,0 +1,161 @@
+use crate::errors::MyError;
+use crate::models::{AppState, AUDIT_SUCCESS};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::web;
+use anyhow::Result;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// The metrics served at `/metrics` in the Prometheus text format. Every [AppState] has its
+/// own registry instead of the global one, so the servers of the tests do not share counts.
+pub struct Metrics {
+    registry: Registry,
+    ceremonies: IntCounterVec,
+    request_duration: HistogramVec,
+    active_sessions: IntGauge,
+    users: IntGauge,
+    credentials: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let ceremonies = IntCounterVec::new(
+            Opts::new(
+                "webauthn_ceremonies_total",
+                "Registration and login steps by outcome, the error code if they failed",
+            ),
+            &["ceremony", "step", "outcome"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "webauthn_http_request_duration_seconds",
+                "Time taken to answer a request",
+            ),
+            &["method", "path", "status"],
+        )
+        .unwrap();
+        let active_sessions =
+            IntGauge::new("webauthn_active_sessions", "Sessions that have not expired").unwrap();
+        let users = IntGauge::new("webauthn_users", "Registered users").unwrap();
+        let credentials =
+            IntGauge::new("webauthn_credentials", "Registered passkeys of all users").unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(ceremonies.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_sessions.clone()))
+            .unwrap();
+        registry.register(Box::new(users.clone())).unwrap();
+        registry.register(Box::new(credentials.clone())).unwrap();
+        Self {
+            registry,
+            ceremonies,
+            request_duration,
+            active_sessions,
+            users,
+            credentials,
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a step of a ceremony, e.g. `("login", "finish")`.
+    pub fn ceremony<T>(&self, ceremony: &str, step: &str, result: &Result<T, MyError>) {
+        let outcome = match result {
+            Ok(_) => AUDIT_SUCCESS,
+            Err(e) => e.code(),
+        };
+        self.ceremonies
+            .with_label_values(&[ceremony, step, outcome])
+            .inc();
+    }
+
+    fn observe_request(&self, method: &str, path: &str, status: StatusCode, started: Instant) {
+        self.request_duration
+            .with_label_values(&[method, path, status.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Refresh the gauges and encode all metrics.
+    pub async fn render(&self, state: &AppState) -> Result<String> {
+        let sessions = state.sessions.stats().await?;
+        let counts = state.users.counts().await?;
+        self.active_sessions.set(sessions.active as i64);
+        self.users.set(counts.users as i64);
+        self.credentials.set(counts.credentials as i64);
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+/// Middleware timing every request, labelled with the route pattern rather than the path
+/// so ids in the path do not create a new series each.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let state = req.app_data::<web::Data<AppState>>().cloned();
+        let method = req.method().to_string();
+        let path = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let service = self.service.clone();
+        Box::pin(async move {
+            let result = service.call(req).await;
+            if let Some(state) = state {
+                // Errors of route middleware, e.g. the rate limit, are only turned into a
+                // response further out.
+                let status = match &result {
+                    Ok(res) => res.status(),
+                    Err(e) => e.as_response_error().status_code(),
+                };
+                state
+                    .metrics
+                    .observe_request(&method, &path, status, started);
+            }
+            result
+        })
+    }
+}
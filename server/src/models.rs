@@ -1,13 +1,17 @@
 use std::{collections::HashMap, sync::Arc};
 
-use async_std::sync::Mutex;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use uuid::Uuid;
 use webauthn_rs::prelude::*;
 
-use crate::config::Config;
+use crate::{
+    api_tokens::ApiTokens, audit_log::AuditLog, config::Config, magic_link::MagicLinks,
+    mailer::Mailer, metrics::Metrics, oidc::Oidc, privacy::Privacy, rate_limit::RateLimiter,
+    session_store::SessionBackend, user_store::UserStore,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct User {
     /// Since a user's username could change at anytime, we need to bind to a unique id.
     /// We use uuid's for this purpose, and you should generate these randomly. If the
@@ -17,16 +21,216 @@ pub struct User {
     pub unique_id: Uuid,
     pub name: String,
     pub display_name: String,
+    /// Unix seconds, missing in registrations started before it was recorded.
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Disabled users cannot log in or use the API, only an administrator can enable
+    /// them again.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl User {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+}
+
+impl Role {
+    /// As serialized, e.g. `admin`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// What a user may do beyond managing their own account, see [crate::roles].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Manages other users with the `/admin` endpoints.
+    Admin,
 }
 
 #[derive(Debug)]
 pub struct Users {
     pub name_to_id: HashMap<String, Uuid>,
-    pub keys: HashMap<Uuid, Vec<Passkey>>,
+    pub profiles: HashMap<Uuid, User>,
+    pub keys: HashMap<Uuid, Vec<StoredCredential>>,
+    /// Hashes of the unused recovery codes.
+    pub recovery_codes: HashMap<Uuid, Vec<String>>,
+    /// Hashes of the unused invite codes and when they expire.
+    pub invites: HashMap<String, i64>,
+}
+
+/// Outcome of changing the name or display name of a user.
+#[derive(Debug)]
+pub enum ProfileUpdate {
+    Updated(User),
+    NotFound,
+    /// Another user already has the requested name.
+    NameTaken,
+}
+
+/// A passkey together with the details its owner gets to manage. Times are unix seconds.
+#[derive(Debug, Clone)]
+pub struct StoredCredential {
+    pub passkey: Passkey,
+    pub nickname: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    /// The model of the authenticator, if it told when registering.
+    pub aaguid: Option<Uuid>,
+}
+
+/// Nickname of a freshly registered passkey until the user renames it.
+pub const DEFAULT_NICKNAME: &str = "Passkey";
+
+impl StoredCredential {
+    pub fn new(passkey: Passkey, aaguid: Option<Uuid>) -> Self {
+        Self {
+            passkey,
+            nickname: DEFAULT_NICKNAME.to_string(),
+            created_at: unix_now(),
+            last_used_at: None,
+            aaguid,
+        }
+    }
+}
+
+/// What the credential endpoints reveal about a passkey, never the key material itself.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CredentialInfo {
+    /// The credential id, base64url encoded like in the WebAuthn API.
+    pub id: String,
+    pub nickname: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    /// Whether the passkey may be synced to other devices.
+    pub backup_eligible: bool,
+    /// Whether the passkey is currently synced to other devices.
+    pub backup_state: bool,
+    pub aaguid: Option<Uuid>,
+}
+
+impl From<&StoredCredential> for CredentialInfo {
+    fn from(credential: &StoredCredential) -> Self {
+        let cred = Credential::from(credential.passkey.clone());
+        Self {
+            id: credential.passkey.cred_id().to_string(),
+            nickname: credential.nickname.clone(),
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+            backup_eligible: cred.backup_eligible,
+            backup_state: cred.backup_state,
+            aaguid: credential.aaguid,
+        }
+    }
+}
+
+/// Outcome of deleting a credential.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialRemoval {
+    Removed,
+    NotFound,
+    /// Refused, without a passkey the user could never log in again.
+    LastCredential,
+}
+
+/// The answer to a successful login.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LoggedIn {
+    #[serde(flatten)]
+    pub user: User,
+    /// Where the client should send the browser next, set when the login completes an
+    /// OpenID Connect authorization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+}
+
+/// The answer to a successful registration.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Registered {
+    #[serde(flatten)]
+    pub user: User,
+    /// Set when the registration created the account. They are only shown this once, the
+    /// user should write them down.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+/// How many accounts and passkeys are registered, for monitoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserCounts {
+    pub users: usize,
+    pub credentials: usize,
+}
+
+/// What a user did, as recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// Registered an account or added a passkey to it.
+    Register,
+    Login,
+    Logout,
+    /// Used a recovery code to add a passkey.
+    Recovery,
+    /// Followed a magic link from an email.
+    MagicLink,
+    /// An administrator disabled the account.
+    Disable,
+    /// An administrator enabled the account again.
+    Enable,
+    /// An administrator removed a passkey of the user.
+    RevokeCredential,
+    /// An administrator ended all sessions of the user.
+    ForceLogout,
+}
+
+/// An entry of the audit log, see [crate::audit_log::AuditLog].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct AuditEvent {
+    /// Unix seconds
+    pub timestamp: i64,
+    pub action: AuditAction,
+    /// `None` if the request failed before the user was known.
+    pub user_id: Option<Uuid>,
+    /// The administrator who acted on the user, `None` if it was the user themselves.
+    pub actor: Option<Uuid>,
+    /// The credential id, base64url encoded like in the WebAuthn API.
+    pub credential_id: Option<String>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// `success`, or the [code](crate::errors::MyError::code) of the error the request
+    /// failed with.
+    pub outcome: String,
+}
+
+pub const AUDIT_SUCCESS: &str = "success";
+
+pub fn unix_now() -> i64 {
+    actix_web::cookie::time::OffsetDateTime::now_utc().unix_timestamp()
 }
 
 pub struct AppState {
     pub config: Arc<Config>,
     pub webauthn: Arc<Webauthn>,
-    pub users: Mutex<Users>,
+    pub users: Arc<dyn UserStore>,
+    pub sessions: SessionBackend,
+    pub rate_limiter: RateLimiter,
+    pub privacy: Privacy,
+    pub mailer: Arc<dyn Mailer>,
+    /// The CAs registrations have to be attested by, `None` accepts any authenticator.
+    pub attestation: Option<AttestationCaList>,
+    pub audit_log: Arc<dyn AuditLog>,
+    pub metrics: Metrics,
+    /// The OpenID Connect provider, `None` unless `oidc.enabled` is set.
+    pub oidc: Option<Oidc>,
+    pub api_tokens: ApiTokens,
+    /// Login by email, `None` unless `magic_link.enabled` is set.
+    pub magic_links: Option<MagicLinks>,
 }
@@ -0,0 +1,530 @@
+use crate::auth::require_enabled;
+use crate::config::OidcConfig;
+use crate::errors::MyError;
+use crate::jwt::{bearer_token, encode, JwtKeys, ACCESS_TOKEN};
+use crate::models::{unix_now, AppState, User};
+use actix_session::Session;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use anyhow::Result;
+use log::error;
+use openssl::hash::{hash, MessageDigest};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use webauthn_rs::prelude::Url;
+
+/// Seconds an authorization code can be exchanged for tokens.
+const CODE_LIFETIME: i64 = 60;
+
+/// The `typ` of ID tokens.
+pub const ID_TOKEN: &str = "JWT";
+
+const PENDING_AUTHORIZATION: &str = "oidc_authorization";
+
+/// The query of the authorization endpoint.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct AuthorizationRequest {
+    /// Only `code` is supported
+    pub response_type: String,
+    pub client_id: String,
+    /// One of the URLs registered for the client
+    pub redirect_uri: String,
+    /// Space separated, has to contain `openid`, `profile` adds the names to the ID token
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    /// base64url of the SHA-256 of the code verifier
+    pub code_challenge: Option<String>,
+    /// Only `S256` is supported
+    pub code_challenge_method: Option<String>,
+}
+
+/// An authorization request that passed validation, kept in the session while the user
+/// logs in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingAuthorization {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+}
+
+impl PendingAuthorization {
+    /// The redirect URI of the client with `params` and the `state` of the request added.
+    fn redirect(&self, params: &[(&str, &str)]) -> String {
+        // Only registered redirect URIs get here, and those are URLs.
+        let mut url = Url::parse(&self.redirect_uri).expect("redirect_uri is a URL");
+        {
+            let mut query = url.query_pairs_mut();
+            query.extend_pairs(params);
+            if let Some(state) = &self.state {
+                query.append_pair("state", state);
+            }
+        }
+        url.to_string()
+    }
+
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split(' ').any(|s| s == scope)
+    }
+}
+
+/// What the authorization endpoint does with a valid client and redirect URI.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Authorization {
+    /// Issue a code once the user is logged in.
+    Pending(PendingAuthorization),
+    /// Send the user back to the client with this error.
+    Refused(String),
+}
+
+struct Grant {
+    authorization: PendingAuthorization,
+    user_id: Uuid,
+    expires_at: i64,
+}
+
+/// The form posted to the token endpoint.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TokenRequest {
+    /// Only `authorization_code` is supported
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    /// Unless the client authenticates with HTTP Basic
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenResponse {
+    pub access_token: String,
+    /// Always `Bearer`
+    pub token_type: String,
+    /// Seconds
+    pub expires_in: i64,
+    pub id_token: String,
+    pub scope: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub scope: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// The answer of the userinfo endpoint, the names only with the `profile` scope.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+}
+
+/// An error of the token endpoint in the format of RFC 6749, which OAuth clients expect
+/// instead of a problem details object.
+#[derive(Debug, thiserror::Error)]
+#[error("{error} {description}")]
+pub struct TokenError {
+    error: &'static str,
+    description: String,
+}
+
+impl TokenError {
+    fn new(error: &'static str, description: &str) -> Self {
+        Self {
+            error,
+            description: description.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for TokenError {
+    fn from(err: anyhow::Error) -> Self {
+        error!("{:#}", err);
+        Self::new("server_error", "Internal server error")
+    }
+}
+
+impl ResponseError for TokenError {
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(serde_json::json!({
+                "error": self.error,
+                "error_description": self.description,
+            }))
+    }
+}
+
+/// An OpenID Connect provider for the clients in the configuration. Users log in with
+/// their passkey as usual, the authorization code flow then hands the result to the client.
+///
+/// Codes live in memory for a minute, so they do not survive a restart and a code has to
+/// be redeemed at the instance that issued it.
+pub struct Oidc {
+    config: OidcConfig,
+    keys: JwtKeys,
+    codes: Mutex<HashMap<String, Grant>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig, keys: JwtKeys) -> Self {
+        Self {
+            config,
+            keys,
+            codes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `None` unless `oidc.enabled` is set.
+    pub fn from_config(config: &OidcConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let keys = JwtKeys::read_or_generate(Path::new(&config.signing_key))?;
+        Ok(Some(Self::new(config.clone(), keys)))
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.config.issuer.trim_end_matches('/'), path)
+    }
+
+    /// The discovery document served at `/.well-known/openid-configuration`.
+    pub fn discovery(&self) -> serde_json::Value {
+        serde_json::json!({
+            "issuer": self.config.issuer,
+            "authorization_endpoint": self.endpoint("/oidc/authorize"),
+            "token_endpoint": self.endpoint("/oidc/token"),
+            "userinfo_endpoint": self.endpoint("/oidc/userinfo"),
+            "jwks_uri": self.endpoint("/oidc/jwks"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": ["openid", "profile"],
+            "claims_supported": ["sub", "name", "preferred_username"],
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+        })
+    }
+
+    pub fn jwks(&self) -> Result<serde_json::Value> {
+        self.keys.jwks()
+    }
+
+    /// Check an authorization request. Errors about the client or redirect URI are shown
+    /// to the user, since sending them to an unverified redirect URI would make this an
+    /// open redirect; all others go back to the client.
+    pub fn authorize(&self, request: &AuthorizationRequest) -> Result<Authorization, MyError> {
+        let client = self
+            .config
+            .clients
+            .iter()
+            .find(|client| client.client_id == request.client_id)
+            .ok_or_else(|| MyError::InvalidInput("Unknown client_id".to_string()))?;
+        if !client.redirect_uris.contains(&request.redirect_uri) {
+            return Err(MyError::InvalidInput(
+                "The redirect_uri is not registered for this client".to_string(),
+            ));
+        }
+        let authorization = PendingAuthorization {
+            client_id: request.client_id.clone(),
+            redirect_uri: request.redirect_uri.clone(),
+            scope: request.scope.clone(),
+            state: request.state.clone(),
+            nonce: request.nonce.clone(),
+            code_challenge: request.code_challenge.clone().unwrap_or_default(),
+        };
+        let refuse = |error: &str, description: &str| {
+            Ok(Authorization::Refused(authorization.redirect(&[
+                ("error", error),
+                ("error_description", description),
+            ])))
+        };
+        if request.response_type != "code" {
+            return refuse("unsupported_response_type", "Only code is supported");
+        }
+        if !authorization.has_scope("openid") {
+            return refuse("invalid_scope", "The openid scope is required");
+        }
+        if authorization.code_challenge.is_empty()
+            || request.code_challenge_method.as_deref() != Some("S256")
+        {
+            return refuse("invalid_request", "PKCE with S256 is required");
+        }
+        Ok(Authorization::Pending(authorization))
+    }
+
+    /// Issue a code for the logged in user and return where to send them with it.
+    pub fn issue_code(&self, authorization: PendingAuthorization, user_id: Uuid) -> Result<String> {
+        let mut code = [0; 32];
+        openssl::rand::rand_bytes(&mut code)?;
+        let code = encode(&code);
+        let redirect = authorization.redirect(&[("code", &code)]);
+        let now = unix_now();
+        let mut codes = self.codes.lock().unwrap();
+        codes.retain(|_, grant| grant.expires_at > now);
+        codes.insert(
+            code,
+            Grant {
+                authorization,
+                user_id,
+                expires_at: now + CODE_LIFETIME,
+            },
+        );
+        Ok(redirect)
+    }
+
+    /// Redeem a code for an ID and an access token. Every code can be used only once.
+    pub async fn exchange(
+        &self,
+        state: &AppState,
+        request: &TokenRequest,
+        headers: &HeaderMap,
+    ) -> Result<TokenResponse, TokenError> {
+        let (client_id, client_secret) = match basic_credentials(headers) {
+            Some((client_id, client_secret)) => (Some(client_id), Some(client_secret)),
+            None => (request.client_id.clone(), request.client_secret.clone()),
+        };
+        let client = client_id
+            .and_then(|client_id| {
+                self.config
+                    .clients
+                    .iter()
+                    .find(|client| client.client_id == client_id)
+            })
+            .ok_or_else(|| TokenError::new("invalid_client", "Unknown client"))?;
+        let authenticated = match (&client.client_secret, &client_secret) {
+            (None, _) => true,
+            (Some(expected), Some(secret)) => {
+                expected.len() == secret.len()
+                    && openssl::memcmp::eq(expected.as_bytes(), secret.as_bytes())
+            }
+            (Some(_), None) => false,
+        };
+        if !authenticated {
+            return Err(TokenError::new("invalid_client", "Wrong client_secret"));
+        }
+        if request.grant_type != "authorization_code" {
+            return Err(TokenError::new(
+                "unsupported_grant_type",
+                "Only authorization_code is supported",
+            ));
+        }
+
+        let invalid_grant = || TokenError::new("invalid_grant", "The code is invalid");
+        let grant = self
+            .codes
+            .lock()
+            .unwrap()
+            .remove(&request.code)
+            .filter(|grant| grant.expires_at > unix_now())
+            .ok_or_else(invalid_grant)?;
+        let authorization = grant.authorization;
+        let challenge = encode(
+            &hash(MessageDigest::sha256(), request.code_verifier.as_bytes())
+                .map_err(anyhow::Error::from)?,
+        );
+        if authorization.client_id != client.client_id
+            || authorization.redirect_uri != request.redirect_uri
+            || challenge != authorization.code_challenge
+        {
+            return Err(invalid_grant());
+        }
+        let user = state
+            .users
+            .get_user(grant.user_id)
+            .await?
+            .ok_or_else(invalid_grant)?;
+        // The account may have been disabled since the code was issued.
+        require_enabled(&user)
+            .map_err(|_| TokenError::new("invalid_grant", "The account has been disabled"))?;
+
+        let now = unix_now();
+        let exp = now + self.config.token_lifetime;
+        let profile = authorization.has_scope("profile");
+        let id_token = IdTokenClaims {
+            iss: self.config.issuer.clone(),
+            sub: user.unique_id.to_string(),
+            aud: client.client_id.clone(),
+            iat: now,
+            exp,
+            nonce: authorization.nonce.clone(),
+            name: profile.then(|| user.display_name.clone()),
+            preferred_username: profile.then(|| user.name.clone()),
+        };
+        let access_token = AccessTokenClaims {
+            iss: self.config.issuer.clone(),
+            sub: user.unique_id.to_string(),
+            aud: client.client_id.clone(),
+            client_id: client.client_id.clone(),
+            scope: authorization.scope.clone(),
+            iat: now,
+            exp,
+        };
+        Ok(TokenResponse {
+            access_token: self.keys.sign(ACCESS_TOKEN, &access_token)?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.config.token_lifetime,
+            id_token: self.keys.sign(ID_TOKEN, &id_token)?,
+            scope: authorization.scope,
+        })
+    }
+
+    /// The claims of an access token from the `Authorization: Bearer` header.
+    pub fn access_token(&self, headers: &HeaderMap) -> Option<AccessTokenClaims> {
+        self.keys
+            .verify::<AccessTokenClaims>(ACCESS_TOKEN, bearer_token(headers)?)
+            .filter(|claims| claims.iss == self.config.issuer)
+    }
+
+    pub fn user_info(&self, claims: &AccessTokenClaims, user: User) -> UserInfo {
+        let profile = claims.scope.split(' ').any(|s| s == "profile");
+        UserInfo {
+            sub: user.unique_id.to_string(),
+            name: profile.then_some(user.display_name),
+            preferred_username: profile.then_some(user.name),
+        }
+    }
+}
+
+/// The client id and secret of an `Authorization: Basic` header.
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(value.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((client_id.to_string(), client_secret.to_string()))
+}
+
+/// Remember the authorization while the user logs in.
+pub fn insert_pending_authorization(
+    session: &Session,
+    authorization: &PendingAuthorization,
+) -> Result<(), MyError> {
+    session
+        .insert(PENDING_AUTHORIZATION, authorization)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to store the authorization {}", e)).into())
+}
+
+/// After a login, issue the code for an authorization waiting in the session and return
+/// where the client should send the browser.
+pub fn complete_pending_authorization(
+    state: &AppState,
+    session: &Session,
+    user_id: Uuid,
+) -> Result<Option<String>, MyError> {
+    let authorization = session
+        .remove_as::<PendingAuthorization>(PENDING_AUTHORIZATION)
+        .and_then(Result::ok);
+    match (&state.oidc, authorization) {
+        (Some(oidc), Some(authorization)) => Ok(Some(oidc.issue_code(authorization, user_id)?)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OidcClient;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    fn oidc() -> Oidc {
+        let config = OidcConfig {
+            enabled: true,
+            clients: vec![OidcClient {
+                client_id: "wiki".to_string(),
+                client_secret: None,
+                redirect_uris: vec!["https://wiki.example.com/callback".to_string()],
+            }],
+            ..OidcConfig::default()
+        };
+        let keys = JwtKeys::new(PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()).unwrap();
+        Oidc::new(config, keys)
+    }
+
+    fn request() -> AuthorizationRequest {
+        AuthorizationRequest {
+            response_type: "code".to_string(),
+            client_id: "wiki".to_string(),
+            redirect_uri: "https://wiki.example.com/callback".to_string(),
+            scope: "openid profile".to_string(),
+            state: Some("xyz".to_string()),
+            nonce: None,
+            code_challenge: Some("challenge".to_string()),
+            code_challenge_method: Some("S256".to_string()),
+        }
+    }
+
+    #[test]
+    fn only_registered_redirect_uris_receive_errors() {
+        let oidc = oidc();
+        assert!(matches!(
+            oidc.authorize(&request()),
+            Ok(Authorization::Pending(_))
+        ));
+        let unregistered = AuthorizationRequest {
+            redirect_uri: "https://evil.example.com/callback".to_string(),
+            ..request()
+        };
+        assert!(matches!(
+            oidc.authorize(&unregistered),
+            Err(MyError::InvalidInput(_))
+        ));
+        let without_pkce = AuthorizationRequest {
+            code_challenge_method: Some("plain".to_string()),
+            ..request()
+        };
+        assert_eq!(
+            oidc.authorize(&without_pkce).unwrap(),
+            Authorization::Refused(
+                "https://wiki.example.com/callback?error=invalid_request\
+                &error_description=PKCE+with+S256+is+required&state=xyz"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::actions::{self, CredentialRename, RegistrationVerification, UserRegistration};
+use crate::admin::{UserDetails, UserPage};
+use crate::api_tokens::{BearerTokens, RefreshTokenRequest};
+use crate::errors::Problem;
+use crate::magic_link::{MagicLinkRequest, MagicLinkVerification, MagicLinkVerified};
+use crate::models::{AuditAction, AuditEvent, CredentialInfo, LoggedIn, Registered, Role, User};
+use crate::oidc::{TokenRequest, TokenResponse, UserInfo};
+use crate::recovery::{RecoveryCodes, RecoveryRequest};
+use crate::registration_policy::Invite;
+use crate::session_store::SessionStats;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+/// The contract between the server and the client, served at `/openapi.json`. A copy is
+/// checked in as `openapi.json`, the tests fail when it no longer matches the handlers.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "perseus-actix-webauthn-rs"),
+    paths(
+        actions::index,
+        actions::get_identity,
+        actions::update_user_profile,
+        actions::get_credentials,
+        actions::rename_credential,
+        actions::delete_credential,
+        actions::get_session_stats,
+        actions::get_metrics,
+        actions::get_openapi,
+        actions::logout,
+        actions::register_start,
+        actions::register_verify,
+        actions::register_finish,
+        actions::login_start,
+        actions::login_finish,
+        actions::login_discoverable_start,
+        actions::login_discoverable_finish,
+        actions::get_security_events,
+        actions::recover,
+        actions::get_recovery_codes,
+        actions::regenerate_recovery_codes,
+        actions::request_magic_link,
+        actions::verify_magic_link,
+        actions::issue_api_tokens,
+        actions::refresh_api_tokens,
+        actions::revoke_api_token,
+        actions::admin_list_users,
+        actions::admin_get_user,
+        actions::admin_disable_user,
+        actions::admin_enable_user,
+        actions::admin_revoke_credential,
+        actions::admin_logout_user,
+        actions::admin_create_invite,
+        actions::oidc_discovery,
+        actions::oidc_jwks,
+        actions::oidc_authorize,
+        actions::oidc_token,
+        actions::oidc_userinfo,
+    ),
+    components(schemas(
+        User,
+        Role,
+        LoggedIn,
+        Registered,
+        RecoveryRequest,
+        RecoveryCodes,
+        MagicLinkRequest,
+        MagicLinkVerification,
+        MagicLinkVerified,
+        UserRegistration,
+        RegistrationVerification,
+        CredentialInfo,
+        CredentialRename,
+        AuditAction,
+        AuditEvent,
+        SessionStats,
+        UserPage,
+        UserDetails,
+        Invite,
+        BearerTokens,
+        RefreshTokenRequest,
+        Problem,
+        TokenRequest,
+        TokenResponse,
+        UserInfo,
+        webauthn::CreationChallengeResponse,
+        webauthn::PublicKeyCredentialCreationOptions,
+        webauthn::RelyingParty,
+        webauthn::UserEntity,
+        webauthn::PubKeyCredParams,
+        webauthn::PublicKeyCredentialDescriptor,
+        webauthn::AuthenticatorSelectionCriteria,
+        webauthn::RequestChallengeResponse,
+        webauthn::PublicKeyCredentialRequestOptions,
+        webauthn::RegisterPublicKeyCredential,
+        webauthn::AuthenticatorAttestationResponse,
+        webauthn::PublicKeyCredential,
+        webauthn::AuthenticatorAssertionResponse,
+    )),
+    modifiers(&SessionCookie)
+)]
+pub struct ApiDoc;
+
+/// Logged in users are identified by the session cookie, `cookie.name` in the configuration.
+/// API clients and clients of the OpenID Connect provider send their access token instead.
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "session_cookie",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("id"))),
+            );
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// The JSON of the webauthn-rs challenges and credentials, which do not describe
+/// themselves. The tests compare these against what webauthn-rs actually sends, so they
+/// notice when an upgrade changes the format. Binary values are base64url encoded.
+pub mod webauthn {
+    use serde::Serialize;
+    use utoipa::ToSchema;
+
+    /// Passed to `navigator.credentials.create()`.
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CreationChallengeResponse {
+        pub public_key: PublicKeyCredentialCreationOptions,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PublicKeyCredentialCreationOptions {
+        pub rp: RelyingParty,
+        pub user: UserEntity,
+        /// base64url
+        pub challenge: String,
+        pub pub_key_cred_params: Vec<PubKeyCredParams>,
+        /// Milliseconds
+        pub timeout: Option<u32>,
+        /// `none`, `indirect` or `direct`
+        pub attestation: Option<String>,
+        pub exclude_credentials: Option<Vec<PublicKeyCredentialDescriptor>>,
+        pub authenticator_selection: Option<AuthenticatorSelectionCriteria>,
+        #[schema(value_type = Option<Object>)]
+        pub extensions: Option<serde_json::Value>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct RelyingParty {
+        pub name: String,
+        pub id: String,
+    }
+
+    /// The user the passkey is created for.
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct UserEntity {
+        /// base64url of the user's unique id
+        pub id: String,
+        pub name: String,
+        pub display_name: String,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct PubKeyCredParams {
+        #[serde(rename = "type")]
+        pub type_: String,
+        /// COSE algorithm identifier
+        pub alg: i64,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct PublicKeyCredentialDescriptor {
+        #[serde(rename = "type")]
+        pub type_: String,
+        /// base64url
+        pub id: String,
+        /// e.g. `usb`, `nfc`, `ble`, `internal` or `hybrid`
+        pub transports: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AuthenticatorSelectionCriteria {
+        /// `platform` or `cross-platform`
+        pub authenticator_attachment: Option<String>,
+        pub require_resident_key: bool,
+        /// `required`, `preferred` or `discouraged`
+        pub user_verification: String,
+    }
+
+    /// Passed to `navigator.credentials.get()`.
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RequestChallengeResponse {
+        pub public_key: PublicKeyCredentialRequestOptions,
+        /// `conditional` for passkey autofill
+        pub mediation: Option<String>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PublicKeyCredentialRequestOptions {
+        /// base64url
+        pub challenge: String,
+        /// Milliseconds
+        pub timeout: Option<u32>,
+        pub rp_id: String,
+        pub allow_credentials: Vec<PublicKeyCredentialDescriptor>,
+        /// `required`, `preferred` or `discouraged`
+        pub user_verification: String,
+        #[schema(value_type = Option<Object>)]
+        pub extensions: Option<serde_json::Value>,
+    }
+
+    /// The result of `navigator.credentials.create()`.
+    #[derive(Serialize, ToSchema)]
+    pub struct RegisterPublicKeyCredential {
+        /// base64url
+        pub id: String,
+        /// base64url
+        #[serde(rename = "rawId")]
+        pub raw_id: String,
+        pub response: AuthenticatorAttestationResponse,
+        #[serde(rename = "type")]
+        pub type_: String,
+        #[schema(value_type = Option<Object>)]
+        pub extensions: Option<serde_json::Value>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct AuthenticatorAttestationResponse {
+        /// base64url
+        #[serde(rename = "attestationObject")]
+        pub attestation_object: String,
+        /// base64url
+        #[serde(rename = "clientDataJSON")]
+        pub client_data_json: String,
+        pub transports: Option<Vec<String>>,
+    }
+
+    /// The result of `navigator.credentials.get()`.
+    #[derive(Serialize, ToSchema)]
+    pub struct PublicKeyCredential {
+        /// base64url
+        pub id: String,
+        /// base64url
+        #[serde(rename = "rawId")]
+        pub raw_id: String,
+        pub response: AuthenticatorAssertionResponse,
+        #[serde(rename = "type")]
+        pub type_: String,
+        #[schema(value_type = Option<Object>)]
+        pub extensions: Option<serde_json::Value>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct AuthenticatorAssertionResponse {
+        /// base64url
+        #[serde(rename = "authenticatorData")]
+        pub authenticator_data: String,
+        /// base64url
+        #[serde(rename = "clientDataJSON")]
+        pub client_data_json: String,
+        /// base64url
+        pub signature: String,
+        /// base64url of the user's unique id, set by discoverable credentials
+        #[serde(rename = "userHandle")]
+        pub user_handle: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run with `UPDATE_OPENAPI=1` to write the current document after changing the API.
+    #[test]
+    fn checked_in_spec_is_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+        let spec = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(path, &spec).unwrap();
+        }
+        let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(
+            checked_in == spec,
+            "openapi.json does not match the handlers, rerun the tests with UPDATE_OPENAPI=1 \
+            and review the changes"
+        );
+    }
+}
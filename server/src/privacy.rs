@@ -0,0 +1,128 @@
+use crate::models::unix_now;
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{Base64UrlSafeData, Passkey};
+
+/// A passkey registered by a software authenticator. Only its id is replaced, so a decoy
+/// has the shape of a real passkey and goes through the same code paths.
+const DECOY_PASSKEY: &str = r#"{"cred":{"cred_id":"","cred":{"type_":"ES256","key":{"EC_EC2":{"curve":"SECP256R1","x":"8r5lknxNfwEQTrHj4txOwKWmWIUaOzSoak5_NYZukK4","y":"F7wUqFTHgiy_PIga1n8caX0MeuJ_Ra_uvd3QaBkbr5E"}}},"counter":0,"transports":null,"user_verified":true,"backup_eligible":false,"backup_state":false,"registration_policy":"preferred","extensions":{"cred_protect":"NotRequested","hmac_create_secret":"NotRequested","appid":"NotRequested","cred_props":"Ignored"},"attestation":{"data":"Self_","metadata":"None"},"attestation_format":"Packed"}}"#;
+
+/// How long the link verifying an email address before registration stays valid.
+pub const REGISTRATION_TOKEN_LIFETIME: i64 = 30 * 60;
+
+/// A registration waiting for its email address to be verified, carried by a signed token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRegistration {
+    pub name: String,
+    pub display_name: String,
+    /// Invite code given with the registration, checked again once the address is verified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
+    /// Unix seconds
+    pub expires_at: i64,
+}
+
+/// Keeps login and registration from revealing which usernames have an account when
+/// `privacy_mode` is enabled.
+pub struct Privacy {
+    key: PKey<Private>,
+}
+
+impl Privacy {
+    /// Derive the key from the session key, so decoys and pending registrations survive a
+    /// restart without another secret to manage.
+    pub fn new(secret: &[u8]) -> Result<Self> {
+        let derived = hmac(&PKey::hmac(secret)?, &[b"webauthn privacy"])?;
+        Ok(Self {
+            key: PKey::hmac(&derived)?,
+        })
+    }
+
+    /// A passkey nobody holds, with an id derived from `username` so that every login
+    /// attempt for the same unknown user offers the same credential.
+    pub fn decoy_passkey(&self, username: &str) -> Result<Passkey> {
+        let cred_id = hmac(&self.key, &[b"decoy credential", username.as_bytes()])?;
+        let mut passkey: serde_json::Value = serde_json::from_str(DECOY_PASSKEY)?;
+        passkey["cred"]["cred_id"] = serde_json::to_value(Base64UrlSafeData(cred_id))?;
+        Ok(serde_json::from_value(passkey)?)
+    }
+
+    pub fn sign(&self, registration: &PendingRegistration) -> Result<String> {
+        let payload = serde_json::to_vec(registration)?;
+        let signature = hmac(&self.key, &[b"registration", &payload])?;
+        Ok(format!(
+            "{}.{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// The registration in `token` if the signature is valid and it has not expired.
+    pub fn verify(&self, token: &str) -> Option<PendingRegistration> {
+        let (payload, signature) = token.split_once('.')?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let expected = hmac(&self.key, &[b"registration", &payload]).ok()?;
+        if signature.len() != expected.len() || !openssl::memcmp::eq(&signature, &expected) {
+            return None;
+        }
+        let registration: PendingRegistration = serde_json::from_slice(&payload).ok()?;
+        (registration.expires_at > unix_now()).then_some(registration)
+    }
+}
+
+pub fn hmac(key: &PKey<Private>, parts: &[&[u8]]) -> Result<Vec<u8>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    Ok(signer.sign_to_vec()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(expires_at: i64) -> PendingRegistration {
+        PendingRegistration {
+            name: "alice@example.com".to_string(),
+            display_name: "Alice".to_string(),
+            invite: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn tokens_are_verified() {
+        let privacy = Privacy::new(b"secret").unwrap();
+        let valid = registration(unix_now() + 60);
+
+        let token = privacy.sign(&valid).unwrap();
+
+        assert_eq!(privacy.verify(&token), Some(valid));
+        let other = Privacy::new(b"another secret").unwrap();
+        assert_eq!(other.verify(&token), None);
+        let expired = privacy.sign(&registration(unix_now() - 1)).unwrap();
+        assert_eq!(privacy.verify(&expired), None);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = privacy.sign(&registration(unix_now() + 3600)).unwrap();
+        let (payload, _) = forged.split_once('.').unwrap();
+        assert_eq!(privacy.verify(&format!("{}.{}", payload, signature)), None);
+    }
+
+    #[test]
+    fn decoys_are_stable_per_username() {
+        let privacy = Privacy::new(b"secret").unwrap();
+
+        let first = privacy.decoy_passkey("bob@example.com").unwrap();
+        let again = privacy.decoy_passkey("bob@example.com").unwrap();
+        let other = privacy.decoy_passkey("carol@example.com").unwrap();
+
+        assert_eq!(first.cred_id(), again.cred_id());
+        assert_ne!(first.cred_id(), other.cred_id());
+        assert_eq!(first.cred_id().0.len(), 32);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::errors::MyError;
+use crate::models::AppState;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::{self, Bytes};
+use actix_web::HttpRequest;
+use log::warn;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets are only pruned once there are this many, so a flood of distinct keys can
+/// not grow the map without bound.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How many buckets are left after pruning, so the next new keys don't prune again.
+const PRUNED_BUCKETS: usize = MAX_BUCKETS * 9 / 10;
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// One token bucket per key, refilled continuously at `per_minute` up to `burst`.
+struct Buckets {
+    config: BucketConfig,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Buckets {
+    fn new(config: BucketConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn per_second(&self) -> f64 {
+        f64::from(self.config.per_minute) / 60.0
+    }
+
+    fn tokens(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second()).min(self.burst())
+    }
+
+    fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+    }
+
+    /// Drop the buckets that refilled completely, they behave like new ones. If the keys
+    /// are all in use, e.g. during a flood, the least recently used ones go instead.
+    fn prune(&self, buckets: &mut HashMap<String, TokenBucket>, now: Instant) {
+        buckets.retain(|_, bucket| self.tokens(bucket, now) < self.burst());
+        if buckets.len() > PRUNED_BUCKETS {
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, &mut oldest_kept, _) =
+                updated.select_nth_unstable(buckets.len() - PRUNED_BUCKETS);
+            buckets.retain(|_, bucket| bucket.updated >= oldest_kept);
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        f64::from(self.config.burst)
+    }
+
+    /// Take a token for `key`, or return how long it takes until the next one is available.
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: self.burst(),
+            updated: now,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second(),
+            ))
+        }
+    }
+}
+
+/// The limits shared by all workers, applied to a route with [RateLimit].
+pub struct RateLimiter {
+    enabled: bool,
+    trust_forwarded_for: bool,
+    per_ip: Buckets,
+    per_username: Buckets,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            trust_forwarded_for: config.trust_forwarded_for,
+            per_ip: Buckets::new(config.per_ip.clone()),
+            per_username: Buckets::new(config.per_username.clone()),
+        }
+    }
+
+    async fn check(&self, req: &mut ServiceRequest) -> Result<(), actix_web::Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let ip = client_ip(req.request(), self.trust_forwarded_for).unwrap_or_default();
+        limit(&self.per_ip, &ip, now)?;
+
+        // The username is in the body, which has to be put back for the handler.
+        let body = req.extract::<Bytes>().await?;
+        if let Some(username) = username(&body) {
+            limit(&self.per_username, &username, now)?;
+        }
+        req.set_payload(Payload::from(body));
+        Ok(())
+    }
+}
+
+/// The address of the client, taken from the `Forwarded` or `X-Forwarded-For` header only
+/// if the reverse proxy setting it is trusted.
+pub fn client_ip(request: &HttpRequest, trust_forwarded_for: bool) -> Option<String> {
+    if trust_forwarded_for {
+        request
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string)
+    } else {
+        request.peer_addr().map(|addr| addr.ip().to_string())
+    }
+}
+
+fn limit(buckets: &Buckets, key: &str, now: Instant) -> Result<(), MyError> {
+    buckets.take(key, now).map_err(|wait| {
+        warn!("rate limited {}", key);
+        MyError::RateLimited {
+            retry_after: wait.as_secs_f64().ceil() as u64,
+        }
+    })
+}
+
+/// The username a ceremony request is about: the `name` of a registration, or the
+/// plain text or JSON string body of a login. `None` for credentials.
+fn username(body: &[u8]) -> Option<String> {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(object)) => object.get("name")?.as_str().map(str::to_string),
+        Ok(serde_json::Value::String(name)) => Some(name),
+        Ok(_) => None,
+        Err(_) => std::str::from_utf8(body)
+            .ok()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string),
+    }
+}
+
+/// Middleware answering with `429 Too Many Requests` once the client IP or the username
+/// in the request ran out of tokens, e.g. `#[post("/login_start", wrap = "RateLimit")]`.
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Some(state) = req.app_data::<web::Data<AppState>>().cloned() {
+                state.rate_limiter.check(&mut req).await?;
+            }
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets(burst: u32, per_minute: u32) -> Buckets {
+        Buckets::new(BucketConfig { burst, per_minute })
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let buckets = buckets(2, 60);
+        let start = Instant::now();
+
+        assert!(buckets.take("a", start).is_ok());
+        assert!(buckets.take("a", start).is_ok());
+        assert_eq!(buckets.take("a", start), Err(Duration::from_secs(1)));
+        // Other keys have their own bucket.
+        assert!(buckets.take("b", start).is_ok());
+
+        assert!(buckets.take("a", start + Duration::from_secs(1)).is_ok());
+        assert!(buckets.take("a", start + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let buckets = buckets(1, 60);
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            buckets.take(&i.to_string(), start).unwrap();
+        }
+
+        buckets
+            .take("new", start + Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(buckets.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_buckets_are_evicted() {
+        let buckets = buckets(1, 1);
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let now = start + Duration::from_millis(i as u64);
+            buckets.take(&i.to_string(), now).unwrap();
+        }
+        let now = start + Duration::from_secs(20);
+
+        buckets.take("new", now).unwrap();
+
+        let map = buckets.buckets.lock().unwrap();
+        assert!(map.len() <= PRUNED_BUCKETS + 1);
+        assert!(map.contains_key("new"));
+        assert!(!map.contains_key("0"));
+        assert!(map.contains_key(&(MAX_BUCKETS - 1).to_string()));
+        drop(map);
+        // Keys that are still around keep their empty bucket.
+        assert!(buckets.take(&(MAX_BUCKETS - 1).to_string(), now).is_err());
+    }
+
+    #[test]
+    fn username_is_read_from_ceremony_bodies() {
+        assert_eq!(
+            username(br#"{"name": "alice", "display_name": "Alice"}"#),
+            Some("alice".to_string())
+        );
+        assert_eq!(username(b"bob"), Some("bob".to_string()));
+        assert_eq!(username(br#""carol""#), Some("carol".to_string()));
+        assert_eq!(username(br#"{"id": "credential"}"#), None);
+        assert_eq!(username(b""), None);
+    }
+}
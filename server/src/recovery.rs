@@ -0,0 +1,142 @@
+use crate::errors::MyError;
+use crate::models::{unix_now, AppState};
+use actix_session::Session;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Codes handed out at once, each one can be used once.
+const RECOVERY_CODES: usize = 10;
+
+/// Seconds a recovery session has to register a new passkey.
+const RECOVERY_LIFETIME: i64 = 10 * 60;
+
+const RECOVERY: &str = "recovery";
+
+/// Crockford's base32, without the letters that are easily confused with digits.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The body of `/recover`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryRequest {
+    pub name: String,
+    /// One of the recovery codes, case and dashes do not matter
+    pub code: String,
+}
+
+/// The answer of `/recovery_codes`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// Only set right after generating them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+    /// Unused codes left
+    pub remaining: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Recovery {
+    user_id: Uuid,
+    expires_at: i64,
+}
+
+/// 80 random bits in four groups, e.g. `7KQ2-M9XD-0RTB-4HZN`.
+pub fn generate_code() -> Result<String> {
+    let mut bytes = [0; 10];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    let bits = bytes.iter().fold(0u128, |bits, &b| bits << 8 | b as u128);
+    let chars: Vec<char> = (0..16)
+        .rev()
+        .map(|i| ALPHABET[(bits >> (i * 5)) as usize & 31] as char)
+        .collect();
+    Ok(chars
+        .chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-"))
+}
+
+/// Codes are stored as the SHA-256 of the user and the code, they have enough entropy
+/// that a slow hash would not add anything.
+fn hash_code(user_id: Uuid, code: &str) -> String {
+    let mut data = user_id.as_bytes().to_vec();
+    data.extend_from_slice(normalize_code(code).as_bytes());
+    base64::encode_config(openssl::sha::sha256(&data), base64::URL_SAFE_NO_PAD)
+}
+
+/// The code as generated, however it was typed.
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Replace the recovery codes of the user with new ones and return them.
+pub async fn regenerate_codes(state: &AppState, user_id: Uuid) -> Result<Vec<String>> {
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| generate_code())
+        .collect::<Result<Vec<_>>>()?;
+    let hashes: Vec<String> = codes.iter().map(|code| hash_code(user_id, code)).collect();
+    state.users.replace_recovery_codes(user_id, &hashes).await?;
+    Ok(codes)
+}
+
+/// Use up the code, false if the user does not have it.
+pub async fn use_code(state: &AppState, user_id: Uuid, code: &str) -> Result<bool> {
+    state
+        .users
+        .use_recovery_code(user_id, &hash_code(user_id, code))
+        .await
+}
+
+/// Let the session register a passkey for the user, without logging it in.
+pub fn insert_recovery(session: &Session, user_id: Uuid) -> Result<(), MyError> {
+    let recovery = Recovery {
+        user_id,
+        expires_at: unix_now() + RECOVERY_LIFETIME,
+    };
+    session
+        .insert(RECOVERY, recovery)
+        .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)).into())
+}
+
+/// The user the session recovers, unless the recovery has expired or the account has been
+/// disabled since it started.
+pub async fn recovering_user(state: &AppState, session: &Session) -> Result<Option<Uuid>> {
+    let user_id = session
+        .get::<Recovery>(RECOVERY)
+        .ok()
+        .flatten()
+        .filter(|recovery| recovery.expires_at > unix_now())
+        .map(|recovery| recovery.user_id);
+    let user = match user_id {
+        Some(user_id) => state.users.get_user(user_id).await?,
+        None => None,
+    };
+    Ok(user
+        .filter(|user| !user.disabled)
+        .map(|user| user.unique_id))
+}
+
+pub fn clear_recovery(session: &Session) {
+    session.remove(RECOVERY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_checked_regardless_of_case_and_dashes() {
+        let user_id = Uuid::new_v4();
+        let code = generate_code().unwrap();
+        assert_eq!(code.len(), 19);
+        assert_ne!(code, generate_code().unwrap());
+
+        let typed = code.replace('-', " ").to_lowercase();
+        assert_eq!(hash_code(user_id, &typed), hash_code(user_id, &code));
+        assert_ne!(hash_code(Uuid::new_v4(), &code), hash_code(user_id, &code));
+    }
+}
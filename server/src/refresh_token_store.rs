@@ -0,0 +1,52 @@
+use crate::models::unix_now;
+use anyhow::Result;
+use async_std::sync::RwLock;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// The refresh tokens that have been issued and not used yet. Tokens are looked up by
+/// their SHA-256, so whoever reads the store cannot use them.
+#[async_trait::async_trait]
+pub trait RefreshTokenStore: Send + Sync {
+    async fn insert(&self, token_hash: &str, user_id: Uuid, expires_at: i64) -> Result<()>;
+
+    /// Remove the token and return its user, `None` if it is unknown or has expired.
+    async fn take(&self, token_hash: &str) -> Result<Option<Uuid>>;
+
+    /// Remove every token of the user.
+    async fn revoke_user(&self, user_id: Uuid) -> Result<()>;
+}
+
+/// Keeps the tokens in memory next to a [crate::user_store::MemoryUserStore], API
+/// clients have to log in again after a restart.
+#[derive(Default)]
+pub struct MemoryRefreshTokenStore {
+    tokens: RwLock<HashMap<String, (Uuid, i64)>>,
+}
+
+#[async_trait::async_trait]
+impl RefreshTokenStore for MemoryRefreshTokenStore {
+    async fn insert(&self, token_hash: &str, user_id: Uuid, expires_at: i64) -> Result<()> {
+        let now = unix_now();
+        let mut tokens = self.tokens.write().await;
+        tokens.retain(|_, (_, expires_at)| *expires_at > now);
+        tokens.insert(token_hash.to_string(), (user_id, expires_at));
+        Ok(())
+    }
+
+    async fn take(&self, token_hash: &str) -> Result<Option<Uuid>> {
+        Ok(self
+            .tokens
+            .write()
+            .await
+            .remove(token_hash)
+            .filter(|(_, expires_at)| *expires_at > unix_now())
+            .map(|(user_id, _)| user_id))
+    }
+
+    async fn revoke_user(&self, user_id: Uuid) -> Result<()> {
+        let mut tokens = self.tokens.write().await;
+        tokens.retain(|_, (token_user_id, _)| *token_user_id != user_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use crate::config::{RegistrationConfig, RegistrationPolicy};
+use crate::errors::MyError;
+use crate::models::{unix_now, AppState};
+use crate::recovery::{generate_code, normalize_code};
+use crate::user_store::UserStore;
+use actix_session::Session;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+const INVITE: &str = "invite";
+
+/// A new invite code.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Invite {
+    /// Passed as `invite` to `/register_start`, works once
+    pub code: String,
+    /// Unix seconds
+    pub expires_at: i64,
+}
+
+/// Invite codes look like recovery codes and are stored as their SHA-256 as well.
+fn hash_invite(code: &str) -> String {
+    base64::encode_config(
+        openssl::sha::sha256(normalize_code(code).as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Create a single-use invite code valid for `lifetime` seconds.
+pub async fn create_invite(users: &dyn UserStore, lifetime: i64) -> Result<Invite> {
+    let code = generate_code()?;
+    let expires_at = unix_now() + lifetime;
+    users.insert_invite(&hash_invite(&code), expires_at).await?;
+    Ok(Invite { code, expires_at })
+}
+
+/// Whether a new account named `name` may be registered. The invite is only checked
+/// here, it is used up along with creating the account, see [take_invite].
+pub async fn check(state: &AppState, name: &str, invite: Option<&str>) -> Result<(), MyError> {
+    let registration = &state.config.registration;
+    match registration.policy {
+        RegistrationPolicy::Open => Ok(()),
+        RegistrationPolicy::Closed => Err(MyError::RegistrationClosed),
+        RegistrationPolicy::Invite => {
+            let invite = invite.ok_or(MyError::InvalidInvite)?;
+            if state.users.is_invite_valid(&hash_invite(invite)).await? {
+                Ok(())
+            } else {
+                Err(MyError::InvalidInvite)
+            }
+        }
+        RegistrationPolicy::Domain => check_domain(registration, name),
+    }
+}
+
+/// Whether `name` is in one of the `allowed_domains` of the `domain` policy. Unlike the
+/// other policies this one also applies when users rename their account.
+pub fn check_domain(registration: &RegistrationConfig, name: &str) -> Result<(), MyError> {
+    if registration.policy != RegistrationPolicy::Domain {
+        return Ok(());
+    }
+    let domain = name
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .ok_or(MyError::DomainNotAllowed)?;
+    if registration
+        .allowed_domains
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+    {
+        Ok(())
+    } else {
+        Err(MyError::DomainNotAllowed)
+    }
+}
+
+/// Remember the checked invite until the registration finishes.
+pub fn insert_invite(session: &Session, invite: Option<&str>) -> Result<(), MyError> {
+    match invite {
+        Some(invite) => session
+            .insert(INVITE, hash_invite(invite))
+            .map_err(|e| anyhow::Error::msg(format!("session update failed {}", e)).into()),
+        None => {
+            clear_invite(session);
+            Ok(())
+        }
+    }
+}
+
+/// The hash of the session's invite, which the new account uses up when it is stored.
+/// `None` unless the policy requires invites.
+pub fn take_invite(state: &AppState, session: &Session) -> Result<Option<String>, MyError> {
+    if state.config.registration.policy != RegistrationPolicy::Invite {
+        return Ok(None);
+    }
+    session
+        .remove_as::<String>(INVITE)
+        .and_then(Result::ok)
+        .map(Some)
+        .ok_or(MyError::InvalidInvite)
+}
+
+pub fn clear_invite(session: &Session) {
+    session.remove(INVITE);
+}
@@ -0,0 +1,92 @@
+use crate::api_tokens::{app_state, AuthenticatedUser};
+use crate::auth::require_enabled;
+use crate::config::Config;
+use crate::errors::MyError;
+use crate::models::{Role, User};
+use crate::user_store::UserStore;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use anyhow::Result;
+use log::info;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+/// A role a handler can require with [RequireRole].
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+/// Requires [Role::Admin].
+pub struct Admin;
+
+impl RequiredRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Extracts the logged in user if they have the role `R`, e.g. `RequireRole<Admin>`.
+/// Anonymous requests are refused with `401`, users without the role with `403`.
+pub struct RequireRole<R: RequiredRole> {
+    pub user: User,
+    role: PhantomData<R>,
+}
+
+impl<R: RequiredRole> FromRequest for RequireRole<R> {
+    type Error = MyError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, MyError>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let authenticated = AuthenticatedUser::authenticate(req);
+        let state = app_state(req);
+        Box::pin(async move {
+            let unique_id = authenticated?.unique_id;
+            let user = state?
+                .users
+                .get_user(unique_id)
+                .await?
+                .ok_or(MyError::Unauthenticated)?;
+            require_enabled(&user)?;
+            if !user.has_role(R::ROLE) {
+                return Err(MyError::MissingRole(R::ROLE.name()));
+            }
+            Ok(Self {
+                user,
+                role: PhantomData,
+            })
+        })
+    }
+}
+
+/// The roles of a new account: users named in `admins` become administrators. Without
+/// any named the first user to register does, which [UserStore::insert_user] decides as
+/// it stores them, see [first_is_admin].
+pub fn initial_roles(config: &Config, name: &str) -> Vec<Role> {
+    if config.admins.iter().any(|admin| admin == name) {
+        vec![Role::Admin]
+    } else {
+        vec![]
+    }
+}
+
+pub fn first_is_admin(config: &Config) -> bool {
+    config.admins.is_empty()
+}
+
+/// Give the users named in `admins` that already have an account the admin role.
+pub async fn promote_admins(config: &Config, users: &dyn UserStore) -> Result<()> {
+    for name in &config.admins {
+        let Some(unique_id) = users.name_to_id(name).await? else {
+            continue;
+        };
+        let Some(user) = users.get_user(unique_id).await? else {
+            continue;
+        };
+        if !user.has_role(Role::Admin) {
+            let mut roles = user.roles.clone();
+            roles.push(Role::Admin);
+            users.set_roles(unique_id, &roles).await?;
+            info!("{} is an admin now", name);
+        }
+    }
+    Ok(())
+}
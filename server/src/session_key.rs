@@ -0,0 +1,227 @@
+use crate::config::SessionKeyConfig;
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderValue, COOKIE};
+use anyhow::Result;
+use log::info;
+use std::io::Write;
+use std::path::Path;
+
+/// The key encrypting session cookies together with the keys it replaced.
+///
+/// `SessionMiddleware` only knows a single key, so cookies encrypted with a previous key
+/// are decrypted and sealed again with the current one by [SessionKeys::reseal] before
+/// the middleware sees them. The browser keeps sending the old cookie until the session
+/// changes or expires, after which the previous key can be dropped.
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub current: Key,
+    pub previous: Vec<Key>,
+}
+
+/// The key the server used to ship with. It is public, so anybody could forge cookies
+/// for a deployment using it.
+fn demo_key() -> Key {
+    let secret: Vec<u8> = (0..64).collect();
+    Key::from(&secret)
+}
+
+fn decode(name: &str, value: &str) -> Result<Key> {
+    let secret = base64::decode(value.trim())
+        .map_err(|e| anyhow::Error::msg(format!("{} is not valid base64 {}", name, e)))?;
+    Key::try_from(secret.as_slice())
+        .map_err(|e| anyhow::Error::msg(format!("{} is not a valid key {}", name, e)))
+}
+
+/// Read the key from `path`, or generate one and store it there if the file is missing.
+fn read_or_generate(path: &Path) -> Result<Key> {
+    if path.exists() {
+        let value = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to read {} {}", path.display(), e)))?;
+        return decode(&path.display().to_string(), &value);
+    }
+    let key = Key::generate();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", base64::encode(key.master())))
+        .map_err(|e| anyhow::Error::msg(format!("Failed to write {} {}", path.display(), e)))?;
+    info!("generated a new session key in {}", path.display());
+    Ok(key)
+}
+
+impl SessionKeys {
+    pub fn new(current: Key) -> Self {
+        Self {
+            current,
+            previous: vec![],
+        }
+    }
+
+    /// Load the keys from the configuration, refusing the demo key unless `dev_mode` is set.
+    pub fn load(config: &SessionKeyConfig, dev_mode: bool) -> Result<Self> {
+        let current = match &config.key {
+            Some(value) => decode("session_key.key", value)?,
+            None => read_or_generate(Path::new(&config.file))?,
+        };
+        let previous = config
+            .previous
+            .iter()
+            .map(|value| decode("session_key.previous", value))
+            .collect::<Result<Vec<_>>>()?;
+        let keys = Self { current, previous };
+        let demo_key = demo_key();
+        let uses_demo_key = std::iter::once(&keys.current)
+            .chain(&keys.previous)
+            .any(|key| key.master() == demo_key.master());
+        if uses_demo_key && !dev_mode {
+            return Err(anyhow::Error::msg(
+                "Refusing to use the public demo session key outside of dev mode",
+            ));
+        }
+        Ok(keys)
+    }
+
+    /// Seal the cookie called `name` with the current key if it was sealed with a previous one.
+    pub fn reseal(&self, req: &mut ServiceRequest, name: &str) {
+        if self.previous.is_empty() {
+            return;
+        }
+        let header = match req.headers().get(COOKIE).and_then(|h| h.to_str().ok()) {
+            Some(header) => header.to_string(),
+            None => return,
+        };
+        let mut resealed = false;
+        let cookies = header
+            .split(';')
+            .map(str::trim)
+            .map(|raw| match Cookie::parse_encoded(raw.to_string()) {
+                Ok(cookie) if cookie.name() == name => match self.reseal_cookie(cookie) {
+                    Some(cookie) => {
+                        resealed = true;
+                        cookie.encoded().to_string()
+                    }
+                    None => raw.to_string(),
+                },
+                _ => raw.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        if resealed {
+            if let Ok(value) = HeaderValue::from_str(&cookies) {
+                req.headers_mut().insert(COOKIE, value);
+            }
+        }
+    }
+
+    fn reseal_cookie(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        let name = cookie.name().to_string();
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        if jar.private(&self.current).get(&name).is_some() {
+            return None;
+        }
+        let decrypted = self
+            .previous
+            .iter()
+            .find_map(|key| jar.private(key).get(&name))?;
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.current).add(decrypted);
+        jar.get(&name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn sealed(key: &Key, name: &str, value: &str) -> Cookie<'static> {
+        let mut jar = CookieJar::new();
+        jar.private_mut(key)
+            .add(Cookie::new(name.to_string(), value.to_string()));
+        jar.get(name).cloned().unwrap()
+    }
+
+    fn opened(key: &Key, cookie: Cookie<'static>) -> Option<String> {
+        let name = cookie.name().to_string();
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        jar.private(key).get(&name).map(|c| c.value().to_string())
+    }
+
+    #[test]
+    fn cookies_sealed_with_a_previous_key_are_resealed() {
+        let old = Key::generate();
+        let keys = SessionKeys {
+            current: Key::generate(),
+            previous: vec![old.clone()],
+        };
+        let mut req = TestRequest::default()
+            .cookie(sealed(&old, "id", "session"))
+            .cookie(Cookie::new("other", "untouched"))
+            .to_srv_request();
+
+        keys.reseal(&mut req, "id");
+
+        let header = req.headers().get(COOKIE).unwrap().to_str().unwrap();
+        let cookies: HashMap<_, _> = header
+            .split("; ")
+            .map(|raw| Cookie::parse_encoded(raw.to_string()).unwrap())
+            .map(|cookie| (cookie.name().to_string(), cookie))
+            .collect();
+        assert_eq!(
+            opened(&keys.current, cookies["id"].clone()).unwrap(),
+            "session"
+        );
+        assert_eq!(cookies["other"].value(), "untouched");
+    }
+
+    #[test]
+    fn cookies_sealed_with_the_current_key_are_left_alone() {
+        let keys = SessionKeys {
+            current: Key::generate(),
+            previous: vec![Key::generate()],
+        };
+        let cookie = sealed(&keys.current, "id", "session");
+        let mut req = TestRequest::default()
+            .cookie(cookie.clone())
+            .to_srv_request();
+
+        keys.reseal(&mut req, "id");
+
+        let header = req.headers().get(COOKIE).unwrap().to_str().unwrap();
+        assert_eq!(header, cookie.encoded().to_string());
+    }
+
+    #[test]
+    fn key_is_generated_on_first_run_and_reused() {
+        let path = std::env::temp_dir().join(format!("webauthn-session-{}.key", Uuid::new_v4()));
+        let config = SessionKeyConfig {
+            file: path.to_str().unwrap().to_string(),
+            ..SessionKeyConfig::default()
+        };
+
+        let first = SessionKeys::load(&config, false).unwrap();
+        let second = SessionKeys::load(&config, false).unwrap();
+
+        assert_eq!(first.current.master(), second.current.master());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn demo_key_requires_dev_mode() {
+        let config = SessionKeyConfig {
+            key: Some(base64::encode(demo_key().master())),
+            ..SessionKeyConfig::default()
+        };
+
+        assert!(SessionKeys::load(&config, false).is_err());
+        assert!(SessionKeys::load(&config, true).is_ok());
+    }
+}
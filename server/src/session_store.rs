@@ -1,14 +1,230 @@
+use crate::sqlite_session_store::SqliteSessionStore;
 use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
 use actix_web::cookie::time::Duration;
 use log::debug;
-use std::{cell::RefCell, collections::HashMap};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::Instant,
+};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 pub(crate) type SessionState = HashMap<String, String>;
 
-#[derive(Default)]
+/// Where actix-identity keeps the id of the logged in user, JSON encoded.
+const IDENTITY_KEY: &str = "actix_identity.user_id";
+
+/// The user the session is logged in as.
+pub(crate) fn logged_in_user(state: &SessionState) -> Option<Uuid> {
+    state
+        .get(IDENTITY_KEY)
+        .and_then(|id| serde_json::from_str::<String>(id).ok())
+        .and_then(|id| Uuid::parse_str(&id).ok())
+}
+
+/// Whether the session is logged in as the user.
+pub(crate) fn is_logged_in_as(state: &SessionState, user_id: Uuid) -> bool {
+    logged_in_user(state) == Some(user_id)
+}
+
+/// Number of sessions kept before the least recently used one is evicted.
+pub const DEFAULT_MAX_SESSIONS: usize = 10_000;
+
+struct Entry {
+    state: SessionState,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Sessions plus their access order. `lru` maps a monotonically increasing access tick
+/// to the session key, so its first entry is always the least recently used session.
+struct Sessions {
+    entries: HashMap<String, Entry>,
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    max_sessions: usize,
+    evicted_expired: u64,
+    evicted_lru: u64,
+}
+
+/// Counters describing the current state of the store, e.g. for monitoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SessionStats {
+    pub active: usize,
+    pub evicted_expired: u64,
+    pub evicted_lru: u64,
+}
+
+impl Sessions {
+    fn new(max_sessions: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            max_sessions,
+            evicted_expired: 0,
+            evicted_lru: 0,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<SessionState> {
+        let expired = self.entries.get(key)?.expires_at <= now;
+        if expired {
+            self.remove(key);
+            self.evicted_expired += 1;
+            return None;
+        }
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(tick, key.to_string());
+        entry.last_used = tick;
+        Some(entry.state.clone())
+    }
+
+    fn insert(&mut self, key: String, state: SessionState, expires_at: Instant) {
+        self.remove(&key);
+        while self.entries.len() >= self.max_sessions {
+            match self.lru.pop_first() {
+                Some((_, oldest)) => {
+                    self.entries.remove(&oldest);
+                    self.evicted_lru += 1;
+                }
+                None => break,
+            }
+        }
+        let tick = self.next_tick();
+        self.lru.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                state,
+                expires_at,
+                last_used: tick,
+            },
+        );
+    }
+
+    fn set_expiry(&mut self, key: &str, expires_at: Instant) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = expires_at;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.last_used);
+        Some(entry)
+    }
+
+    fn evict_expired(&mut self, now: Instant) -> usize {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired.iter().for_each(|key| {
+            self.remove(key);
+        });
+        self.evicted_expired += expired.len() as u64;
+        expired.len()
+    }
+
+    fn remove_logged_in_as(&mut self, user_id: Uuid) -> usize {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| is_logged_in_as(&entry.state, user_id))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().for_each(|key| {
+            self.remove(key);
+        });
+        keys.len()
+    }
+
+    fn stats(&self) -> SessionStats {
+        SessionStats {
+            active: self.entries.len(),
+            evicted_expired: self.evicted_expired,
+            evicted_lru: self.evicted_lru,
+        }
+    }
+}
+
+/// Server side session storage shared by all workers. Clones refer to the same sessions,
+/// so build it once and hand a clone to every worker's `SessionMiddleware`.
+///
+/// Sessions expire after the TTL requested by actix-session. Expired sessions are never
+/// returned and get dropped by [MemorySessionStore::spawn_reaper]. Once `max_sessions`
+/// is reached the least recently used session is evicted to make room.
+#[derive(Clone)]
 pub struct MemorySessionStore {
-    store: RefCell<HashMap<String, SessionState>>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Default for MemorySessionStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SESSIONS)
+    }
+}
+
+fn expires_at(now: Instant, ttl: &Duration) -> Instant {
+    now + std::time::Duration::from_secs(ttl.whole_seconds().max(0) as u64)
+}
+
+impl MemorySessionStore {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(Sessions::new(max_sessions.max(1)))),
+        }
+    }
+
+    fn lock(&self) -> anyhow::Result<MutexGuard<'_, Sessions>> {
+        self.sessions
+            .lock()
+            .map_err(|e| anyhow::Error::msg(format!("Session store lock poisoned {}", e)))
+    }
+
+    pub fn stats(&self) -> anyhow::Result<SessionStats> {
+        Ok(self.lock()?.stats())
+    }
+
+    /// Log the user out everywhere, returns how many sessions were deleted.
+    pub fn delete_user_sessions(&self, user_id: Uuid) -> anyhow::Result<usize> {
+        Ok(self.lock()?.remove_logged_in_as(user_id))
+    }
+
+    /// Periodically drop expired sessions. The task ends once the last clone of the
+    /// store has been dropped.
+    pub fn spawn_reaper(&self, interval: std::time::Duration) {
+        let sessions: Weak<Mutex<Sessions>> = Arc::downgrade(&self.sessions);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(store) = sessions.upgrade() else {
+                    break;
+                };
+                if let Ok(mut sessions) = store.lock() {
+                    let evicted = sessions.evict_expired(Instant::now());
+                    debug!("reaped {} sessions {:?}", evicted, sessions.stats());
+                };
+            }
+        });
+    }
 }
 
 #[async_trait::async_trait(?Send)]
@@ -16,7 +232,10 @@ impl SessionStore for MemorySessionStore {
     async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
         debug!("load {:?}", session_key);
         let key: String = session_key.as_ref().to_string();
-        let session_state = self.store.borrow().get(&key).map(|x| x.to_owned());
+        let session_state = self
+            .lock()
+            .map_err(LoadError::Other)?
+            .get(&key, Instant::now());
         Ok(session_state)
     }
 
@@ -31,10 +250,10 @@ impl SessionStore for MemorySessionStore {
             .clone()
             .try_into()
             .map_err(|e| SaveError::Other(anyhow::Error::from(e)))?;
-        self.store
-            .try_borrow_mut()
-            .map_err(|e| SaveError::Other(anyhow::Error::from(e)))?
-            .insert(key, session_state);
+        let now = Instant::now();
+        self.lock()
+            .map_err(SaveError::Other)?
+            .insert(key, session_state, expires_at(now, ttl));
         Ok(session_key)
     }
 
@@ -46,10 +265,10 @@ impl SessionStore for MemorySessionStore {
     ) -> Result<SessionKey, UpdateError> {
         debug!("update {:?} {:?} ttl {}", session_key, session_state, ttl);
         let key: String = session_key.as_ref().to_string();
-        self.store
-            .try_borrow_mut()
-            .map_err(|e| UpdateError::Other(anyhow::Error::from(e)))?
-            .insert(key, session_state);
+        let now = Instant::now();
+        self.lock()
+            .map_err(UpdateError::Other)?
+            .insert(key, session_state, expires_at(now, ttl));
         Ok(session_key)
     }
 
@@ -59,19 +278,166 @@ impl SessionStore for MemorySessionStore {
         ttl: &Duration,
     ) -> Result<(), anyhow::Error> {
         debug!("update_ttl {:?} ttl {}", session_key, ttl);
+        let key: String = session_key.as_ref().to_string();
+        let now = Instant::now();
+        self.lock()?.set_expiry(&key, expires_at(now, ttl));
         Ok(())
     }
 
     async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
         debug!("delete {:?}", session_key);
         let key: String = session_key.as_ref().to_string();
-        self.store
-            .try_borrow_mut()
-            .map_err(|e| UpdateError::Other(anyhow::Error::from(e)))?
+        self.lock()?
             .remove(&key)
-            .ok_or_else(|| {
-                UpdateError::Other(anyhow::Error::msg("Could not remove session_key"))
-            })?;
+            .ok_or_else(|| anyhow::Error::msg("Could not remove session_key"))?;
         Ok(())
     }
 }
+
+/// The session store selected in the configuration. Delegating through an enum keeps the
+/// type of `SessionMiddleware`, and therefore of the whole `App`, independent of the choice.
+#[derive(Clone)]
+pub enum SessionBackend {
+    Memory(MemorySessionStore),
+    Sqlite(SqliteSessionStore),
+}
+
+impl SessionBackend {
+    pub async fn stats(&self) -> anyhow::Result<SessionStats> {
+        match self {
+            SessionBackend::Memory(store) => store.stats(),
+            SessionBackend::Sqlite(store) => store.stats().await,
+        }
+    }
+
+    pub fn spawn_reaper(&self, interval: std::time::Duration) {
+        match self {
+            SessionBackend::Memory(store) => store.spawn_reaper(interval),
+            SessionBackend::Sqlite(store) => store.spawn_reaper(interval),
+        }
+    }
+
+    /// Log the user out everywhere, returns how many sessions were deleted.
+    pub async fn delete_user_sessions(&self, user_id: Uuid) -> anyhow::Result<usize> {
+        match self {
+            SessionBackend::Memory(store) => store.delete_user_sessions(user_id),
+            SessionBackend::Sqlite(store) => store.delete_user_sessions(user_id).await,
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            SessionBackend::Memory(store) => store.load(session_key).await,
+            SessionBackend::Sqlite(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Memory(store) => store.save(session_state, ttl).await,
+            SessionBackend::Sqlite(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Memory(store) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Sqlite(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Memory(store) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Sqlite(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Memory(store) => store.delete(session_key).await,
+            SessionBackend::Sqlite(store) => store.delete(session_key).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(value: &str) -> SessionState {
+        HashMap::from([("key".to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn expired_sessions_are_not_returned() {
+        let now = Instant::now();
+        let mut sessions = Sessions::new(10);
+        sessions.insert("a".to_string(), state("a"), now);
+        sessions.insert(
+            "b".to_string(),
+            state("b"),
+            now + std::time::Duration::from_secs(60),
+        );
+
+        assert_eq!(sessions.get("a", now), None);
+        assert_eq!(sessions.get("b", now), Some(state("b")));
+        assert_eq!(sessions.stats().active, 1);
+        assert_eq!(sessions.stats().evicted_expired, 1);
+    }
+
+    #[test]
+    fn reaper_evicts_expired_sessions() {
+        let now = Instant::now();
+        let later = now + std::time::Duration::from_secs(60);
+        let mut sessions = Sessions::new(10);
+        sessions.insert("a".to_string(), state("a"), now);
+        sessions.insert("b".to_string(), state("b"), now);
+        sessions.insert("c".to_string(), state("c"), later);
+
+        assert_eq!(sessions.evict_expired(now), 2);
+        assert_eq!(sessions.stats().active, 1);
+        assert!(sessions.set_expiry("c", now));
+        assert_eq!(sessions.evict_expired(now), 1);
+        assert_eq!(sessions.stats().active, 0);
+    }
+
+    #[test]
+    fn least_recently_used_session_is_evicted_at_capacity() {
+        let later = Instant::now() + std::time::Duration::from_secs(60);
+        let mut sessions = Sessions::new(2);
+        sessions.insert("a".to_string(), state("a"), later);
+        sessions.insert("b".to_string(), state("b"), later);
+        // Touch "a" so "b" becomes the least recently used session.
+        sessions.get("a", Instant::now());
+        sessions.insert("c".to_string(), state("c"), later);
+
+        assert_eq!(sessions.get("b", Instant::now()), None);
+        assert_eq!(sessions.get("a", Instant::now()), Some(state("a")));
+        assert_eq!(sessions.get("c", Instant::now()), Some(state("c")));
+        assert_eq!(
+            sessions.stats(),
+            SessionStats {
+                active: 2,
+                evicted_expired: 0,
+                evicted_lru: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use crate::audit_log::AuditLog;
+use crate::db::DbPool;
+use crate::models::*;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use uuid::Uuid;
+
+/// Keeps the audit log in the `audit_events` table of the user database.
+#[derive(Clone)]
+pub struct SqliteAuditLog {
+    pool: DbPool,
+}
+
+impl SqliteAuditLog {
+    pub fn open(path: &str) -> Result<Self> {
+        let pool = crate::db::pool(path)?;
+        Ok(Self { pool })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        crate::db::with_conn(&self.pool, f).await
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for SqliteAuditLog {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        let action = serde_json::to_value(event.action)?
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let event = event.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO audit_events
+                (timestamp, action, user_id, actor, credential_id, client_ip, user_agent, outcome)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    event.timestamp,
+                    action,
+                    event.user_id.map(|id| id.to_string()),
+                    event.actor.map(|id| id.to_string()),
+                    event.credential_id,
+                    event.client_ip,
+                    event.user_agent,
+                    event.outcome
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn recent_events(&self, user_id: Uuid, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT timestamp, action, actor, credential_id, client_ip, user_agent, outcome
+                FROM audit_events WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string(), limit], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, String>(6)?,
+                ))
+            })?;
+            rows.map(|row| {
+                let (timestamp, action, actor, credential_id, client_ip, user_agent, outcome) =
+                    row?;
+                Ok(AuditEvent {
+                    timestamp,
+                    action: serde_json::from_value(serde_json::Value::String(action))?,
+                    user_id: Some(user_id),
+                    actor: actor.as_deref().map(Uuid::parse_str).transpose()?,
+                    credential_id,
+                    client_ip,
+                    user_agent,
+                    outcome,
+                })
+            })
+            .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+
+    #[actix_web::test]
+    async fn recent_events_are_newest_first_per_user() {
+        let db = TempDb::new("audit");
+        let log = SqliteAuditLog::open(&db.path).unwrap();
+        let alice = Uuid::new_v4();
+        let event = |action, user_id, outcome: &str| AuditEvent {
+            timestamp: unix_now(),
+            action,
+            user_id,
+            actor: None,
+            credential_id: Some("credential".to_string()),
+            client_ip: Some("127.0.0.1".to_string()),
+            user_agent: None,
+            outcome: outcome.to_string(),
+        };
+        let events = [
+            event(AuditAction::Register, Some(alice), AUDIT_SUCCESS),
+            event(AuditAction::Login, Some(Uuid::new_v4()), AUDIT_SUCCESS),
+            event(AuditAction::Login, None, "session_state_missing"),
+            event(AuditAction::Login, Some(alice), "authentication_failed"),
+            AuditEvent {
+                actor: Some(Uuid::new_v4()),
+                ..event(AuditAction::Disable, Some(alice), AUDIT_SUCCESS)
+            },
+        ];
+        for event in &events {
+            log.record(event).await.unwrap();
+        }
+
+        let recent = log.recent_events(alice, 2).await.unwrap();
+
+        assert_eq!(recent, vec![events[4].clone(), events[3].clone()]);
+    }
+}
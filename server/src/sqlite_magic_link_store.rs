@@ -0,0 +1,64 @@
+use crate::db::DbPool;
+use crate::magic_link_store::MagicLinkStore;
+use crate::models::unix_now;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+/// Keeps the used nonces in the `used_magic_links` table of the user database.
+#[derive(Clone)]
+pub struct SqliteMagicLinkStore {
+    pool: DbPool,
+}
+
+impl SqliteMagicLinkStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let pool = crate::db::pool(path)?;
+        Ok(Self { pool })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        crate::db::with_conn(&self.pool, f).await
+    }
+}
+
+#[async_trait::async_trait]
+impl MagicLinkStore for SqliteMagicLinkStore {
+    async fn use_nonce(&self, nonce: &str, expires_at: i64) -> Result<bool> {
+        let nonce = nonce.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM used_magic_links WHERE expires_at <= ?1",
+                params![unix_now()],
+            )?;
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO used_magic_links (nonce, expires_at) VALUES (?1, ?2)",
+                params![nonce, expires_at],
+            )?;
+            tx.commit()?;
+            Ok(inserted == 1)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+
+    #[actix_web::test]
+    async fn nonces_can_be_used_once() {
+        let db = TempDb::new("links");
+        let store = SqliteMagicLinkStore::open(&db.path).unwrap();
+
+        let expires_at = unix_now() + 60;
+        assert!(store.use_nonce("first", expires_at).await.unwrap());
+        assert!(!store.use_nonce("first", expires_at).await.unwrap());
+        assert!(store.use_nonce("second", expires_at).await.unwrap());
+    }
+}
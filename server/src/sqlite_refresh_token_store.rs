@@ -0,0 +1,113 @@
+use crate::db::DbPool;
+use crate::models::unix_now;
+use crate::refresh_token_store::RefreshTokenStore;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+/// Keeps the refresh tokens in the `refresh_tokens` table of the user database, they are
+/// deleted along with their user.
+#[derive(Clone)]
+pub struct SqliteRefreshTokenStore {
+    pool: DbPool,
+}
+
+impl SqliteRefreshTokenStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let pool = crate::db::pool(path)?;
+        Ok(Self { pool })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        crate::db::with_conn(&self.pool, f).await
+    }
+}
+
+#[async_trait::async_trait]
+impl RefreshTokenStore for SqliteRefreshTokenStore {
+    async fn insert(&self, token_hash: &str, user_id: Uuid, expires_at: i64) -> Result<()> {
+        let token_hash = token_hash.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM refresh_tokens WHERE expires_at <= ?1",
+                params![unix_now()],
+            )?;
+            tx.execute(
+                "INSERT INTO refresh_tokens (token_hash, user_id, expires_at)
+                VALUES (?1, ?2, ?3)",
+                params![token_hash, user_id.to_string(), expires_at],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take(&self, token_hash: &str) -> Result<Option<Uuid>> {
+        let token_hash = token_hash.to_string();
+        self.with_conn(move |conn| {
+            let user_id: Option<String> = conn
+                .query_row(
+                    "DELETE FROM refresh_tokens WHERE token_hash = ?1 AND expires_at > ?2
+                    RETURNING user_id",
+                    params![token_hash, unix_now()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            user_id
+                .map(|id| Uuid::parse_str(&id))
+                .transpose()
+                .map_err(|e| anyhow::Error::msg(format!("Invalid user id {}", e)))
+        })
+        .await
+    }
+
+    async fn revoke_user(&self, user_id: Uuid) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM refresh_tokens WHERE user_id = ?1",
+                params![user_id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+
+    #[actix_web::test]
+    async fn refresh_tokens_can_be_taken_once_until_they_expire() {
+        let db = TempDb::new("tokens");
+        let store = SqliteRefreshTokenStore::open(&db.path).unwrap();
+        let user_id = Uuid::new_v4();
+        let conn = crate::db::open(&db.path).unwrap();
+        conn.execute(
+            "INSERT INTO users (unique_id, name) VALUES (?1, 'ines')",
+            params![user_id.to_string()],
+        )
+        .unwrap();
+
+        store
+            .insert("valid", user_id, unix_now() + 60)
+            .await
+            .unwrap();
+        store
+            .insert("expired", user_id, unix_now() - 1)
+            .await
+            .unwrap();
+
+        assert_eq!(store.take("valid").await.unwrap(), Some(user_id));
+        assert_eq!(store.take("valid").await.unwrap(), None);
+        assert_eq!(store.take("expired").await.unwrap(), None);
+        assert_eq!(store.take("unknown").await.unwrap(), None);
+    }
+}
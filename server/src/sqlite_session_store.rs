@@ -0,0 +1,284 @@
+use crate::db::DbPool;
+use crate::models::unix_now;
+use crate::session_store::{logged_in_user, SessionState, SessionStats};
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Result;
+use log::debug;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use uuid::Uuid;
+
+/// Keeps sessions, including the in-flight `reg_state`/`auth_state` of a ceremony, in
+/// SQLite so they survive a restart. Like the in-memory store the data never leaves the
+/// server, the cookie only carries the session key.
+///
+/// Expiry is enforced on every load, [SqliteSessionStore::spawn_reaper] additionally
+/// deletes expired rows so the table does not grow forever.
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    pool: DbPool,
+    evicted_expired: Arc<AtomicU64>,
+}
+
+fn expires_at(ttl: &Duration) -> i64 {
+    unix_now() + ttl.whole_seconds()
+}
+
+fn delete_expired(conn: &Connection) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM sessions WHERE expires_at <= ?1",
+        params![unix_now()],
+    )?)
+}
+
+impl SqliteSessionStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let pool = crate::db::pool(path)?;
+        Ok(Self {
+            pool,
+            evicted_expired: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        crate::db::with_conn(&self.pool, f).await
+    }
+
+    pub async fn stats(&self) -> Result<SessionStats> {
+        let active: i64 = self
+            .with_conn(|conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM sessions WHERE expires_at > ?1",
+                    params![unix_now()],
+                    |row| row.get(0),
+                )?)
+            })
+            .await?;
+        Ok(SessionStats {
+            active: active as usize,
+            evicted_expired: self.evicted_expired.load(Ordering::Relaxed),
+            evicted_lru: 0,
+        })
+    }
+
+    /// Periodically delete expired sessions.
+    pub fn spawn_reaper(&self, interval: std::time::Duration) {
+        let store = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match store.with_conn(|conn| delete_expired(conn)).await {
+                    Ok(evicted) => {
+                        store
+                            .evicted_expired
+                            .fetch_add(evicted as u64, Ordering::Relaxed);
+                        debug!("reaped {} sessions", evicted);
+                    }
+                    Err(e) => debug!("reaping sessions failed {}", e),
+                }
+            }
+        });
+    }
+
+    /// Log the user out everywhere, returns how many sessions were deleted.
+    pub async fn delete_user_sessions(&self, user_id: Uuid) -> Result<usize> {
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM sessions WHERE user_id = ?1",
+                params![user_id.to_string()],
+            )?)
+        })
+        .await
+    }
+
+    async fn upsert(&self, key: String, session_state: SessionState, ttl: &Duration) -> Result<()> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to serialize session {}", e)))?;
+        let expires_at = expires_at(ttl);
+        // Kept next to the opaque state, so logging a user out everywhere is one lookup.
+        let user_id = logged_in_user(&session_state).map(|id| id.to_string());
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (session_key, state, expires_at, user_id)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (session_key) DO UPDATE SET
+                    state = excluded.state,
+                    expires_at = excluded.expires_at,
+                    user_id = excluded.user_id",
+                params![key, state, expires_at, user_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        debug!("load {:?}", session_key);
+        let key: String = session_key.as_ref().to_string();
+        let row = self
+            .with_conn(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT state, expires_at FROM sessions WHERE session_key = ?1",
+                        params![key],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(LoadError::Other)?;
+        match row {
+            Some((state, expires_at)) if expires_at > unix_now() => serde_json::from_str(&state)
+                .map(Some)
+                .map_err(|e| LoadError::Deserialization(anyhow::Error::from(e))),
+            Some(_) => {
+                // Expired but not reaped yet, treat it as gone.
+                self.delete(session_key).await.map_err(LoadError::Other)?;
+                self.evicted_expired.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        debug!("save ttl {}", ttl);
+        let key = Uuid::new_v4().to_string();
+        let session_key: SessionKey = key
+            .clone()
+            .try_into()
+            .map_err(|e| SaveError::Other(anyhow::Error::from(e)))?;
+        self.upsert(key, session_state, ttl)
+            .await
+            .map_err(SaveError::Other)?;
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        debug!("update {:?} ttl {}", session_key, ttl);
+        let key: String = session_key.as_ref().to_string();
+        self.upsert(key, session_state, ttl)
+            .await
+            .map_err(UpdateError::Other)?;
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        debug!("update_ttl {:?} ttl {}", session_key, ttl);
+        let key: String = session_key.as_ref().to_string();
+        let expires_at = expires_at(ttl);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET expires_at = ?1 WHERE session_key = ?2",
+                params![expires_at, key],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        debug!("delete {:?}", session_key);
+        let key: String = session_key.as_ref().to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE session_key = ?1", params![key])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+    use std::collections::HashMap;
+
+    #[actix_web::test]
+    async fn sessions_survive_reopening_the_store() {
+        let db = TempDb::new("sessions");
+        let state = HashMap::from([("reg_state".to_string(), "\"pending\"".to_string())]);
+
+        let session_key = SqliteSessionStore::open(&db.path)
+            .unwrap()
+            .save(state.clone(), &Duration::minutes(5))
+            .await
+            .unwrap();
+        let loaded = SqliteSessionStore::open(&db.path)
+            .unwrap()
+            .load(&session_key)
+            .await
+            .unwrap();
+
+        assert_eq!(loaded, Some(state));
+    }
+
+    #[actix_web::test]
+    async fn expired_sessions_are_not_loaded() {
+        let db = TempDb::new("sessions");
+        let store = SqliteSessionStore::open(&db.path).unwrap();
+
+        let session_key = store
+            .save(HashMap::new(), &Duration::seconds(-1))
+            .await
+            .unwrap();
+
+        assert_eq!(store.load(&session_key).await.unwrap(), None);
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.active, 0);
+        assert_eq!(stats.evicted_expired, 1);
+    }
+
+    #[actix_web::test]
+    async fn only_the_sessions_of_the_user_are_deleted() {
+        let db = TempDb::new("sessions");
+        let store = SqliteSessionStore::open(&db.path).unwrap();
+        let user_id = Uuid::new_v4();
+        let logged_in = |user_id: Uuid| {
+            let id = serde_json::to_string(&user_id.to_string()).unwrap();
+            HashMap::from([("actix_identity.user_id".to_string(), id)])
+        };
+        let ttl = Duration::minutes(5);
+        let own = store.save(logged_in(user_id), &ttl).await.unwrap();
+        let other = store.save(logged_in(Uuid::new_v4()), &ttl).await.unwrap();
+        let anonymous = store.save(HashMap::new(), &ttl).await.unwrap();
+        // A state that cannot be read does not get in the way.
+        let conn = crate::db::open(&db.path).unwrap();
+        conn.execute(
+            "INSERT INTO sessions (session_key, state, expires_at) VALUES ('broken', '{', ?1)",
+            params![unix_now() + 60],
+        )
+        .unwrap();
+
+        assert_eq!(store.delete_user_sessions(user_id).await.unwrap(), 1);
+
+        assert_eq!(store.load(&own).await.unwrap(), None);
+        assert!(store.load(&other).await.unwrap().is_some());
+        assert!(store.load(&anonymous).await.unwrap().is_some());
+    }
+}
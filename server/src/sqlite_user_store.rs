@@ -0,0 +1,516 @@
+use crate::db::DbPool;
+use crate::models::*;
+use crate::user_store::UserStore;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use webauthn_rs::prelude::*;
+
+/// Persists users and passkeys in a SQLite database. Passkeys are stored as JSON since
+/// webauthn-rs explicitly supports serialising them for this purpose.
+///
+/// Every query checks out its own connection from a pool and runs on the blocking thread
+/// pool. With the database in WAL mode readers never wait for a writer, so lookups during
+/// `login_start` do not serialise behind registrations.
+#[derive(Clone)]
+pub struct SqliteUserStore {
+    pool: DbPool,
+}
+
+impl SqliteUserStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let pool = crate::db::pool(path)?;
+        Ok(Self { pool })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        crate::db::with_conn(&self.pool, f).await
+    }
+}
+
+fn parse_uuid(value: String) -> Result<Uuid> {
+    Uuid::parse_str(&value)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to parse user unique identity {}", e)))
+}
+
+fn parse_passkey(json: &str) -> Result<Passkey> {
+    serde_json::from_str(json)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to deserialize passkey {}", e)))
+}
+
+fn parse_roles(json: &str) -> Result<Vec<Role>> {
+    serde_json::from_str(json)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to deserialize roles {}", e)))
+}
+
+const USER_COLUMNS: &str = "unique_id, name, display_name, created_at, roles, disabled";
+
+/// A row with the [USER_COLUMNS].
+fn user_from_row(row: &rusqlite::Row) -> Result<User> {
+    Ok(User {
+        unique_id: parse_uuid(row.get(0)?)?,
+        name: row.get(1)?,
+        display_name: row.get(2)?,
+        created_at: row.get(3)?,
+        roles: parse_roles(&row.get::<_, String>(4)?)?,
+        disabled: row.get(5)?,
+    })
+}
+
+fn load_user(conn: &Connection, user_unique_id: Uuid) -> Result<Option<User>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM users WHERE unique_id = ?1",
+        USER_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![user_unique_id.to_string()])?;
+    rows.next()?.map(user_from_row).transpose()
+}
+
+/// `search` as a LIKE pattern matching it anywhere, with its wildcards escaped.
+fn contains_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn load_credentials(conn: &Connection, user_unique_id: Uuid) -> Result<Vec<Passkey>> {
+    let mut stmt = conn.prepare_cached("SELECT passkey FROM credentials WHERE user_id = ?1")?;
+    let rows = stmt.query_map(params![user_unique_id.to_string()], |row| {
+        row.get::<_, String>(0)
+    })?;
+    rows.map(|row| parse_passkey(&row?)).collect()
+}
+
+#[async_trait::async_trait]
+impl UserStore for SqliteUserStore {
+    async fn name_to_id(&self, username: &str) -> Result<Option<Uuid>> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT unique_id FROM users WHERE name = ?1",
+                params![username],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(parse_uuid)
+            .transpose()
+        })
+        .await
+    }
+
+    async fn get_user(&self, user_unique_id: Uuid) -> Result<Option<User>> {
+        self.with_conn(move |conn| load_user(conn, user_unique_id))
+            .await
+    }
+
+    async fn get_credentials(&self, user_unique_id: Uuid) -> Result<Vec<Passkey>> {
+        self.with_conn(move |conn| load_credentials(conn, user_unique_id))
+            .await
+    }
+
+    async fn insert_user(
+        &self,
+        user: &User,
+        credential: StoredCredential,
+        invite: Option<&str>,
+        first_is_admin: bool,
+    ) -> Result<bool> {
+        let passkey = serde_json::to_string(&credential.passkey)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to serialize passkey {}", e)))?;
+        let roles = serde_json::to_string(&user.roles)?;
+        let mut admin_roles = user.roles.clone();
+        if !user.has_role(Role::Admin) {
+            admin_roles.push(Role::Admin);
+        }
+        let admin_roles = serde_json::to_string(&admin_roles)?;
+        let user = user.clone();
+        let user_unique_id = user.unique_id.to_string();
+        let invite = invite.map(str::to_string);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let is_new = tx
+                .query_row(
+                    "SELECT 1 FROM users WHERE unique_id = ?1",
+                    params![user_unique_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_none();
+            if let (true, Some(code_hash)) = (is_new, invite) {
+                let used = tx.execute(
+                    "DELETE FROM invites WHERE code_hash = ?1 AND expires_at > ?2",
+                    params![code_hash, unix_now()],
+                )?;
+                // Dropping the transaction rolls it back.
+                if used == 0 {
+                    return Ok(false);
+                }
+            }
+            // `WHERE true` keeps SQLite from taking `ON CONFLICT` for a join constraint.
+            tx.execute(
+                "INSERT INTO users (unique_id, name, display_name, created_at, roles, disabled)
+                SELECT ?1, ?2, ?3, ?4,
+                    CASE WHEN ?7 AND NOT EXISTS (SELECT 1 FROM users) THEN ?8 ELSE ?5 END, ?6
+                WHERE true
+                ON CONFLICT (unique_id) DO NOTHING",
+                params![
+                    user_unique_id,
+                    user.name,
+                    user.display_name,
+                    user.created_at,
+                    roles,
+                    user.disabled,
+                    first_is_admin,
+                    admin_roles
+                ],
+            )?;
+            tx.execute(
+                "INSERT INTO credentials
+                (cred_id, user_id, passkey, nickname, created_at, last_used_at, aaguid)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    credential.passkey.cred_id().to_string(),
+                    user_unique_id,
+                    passkey,
+                    credential.nickname,
+                    credential.created_at,
+                    credential.last_used_at,
+                    credential.aaguid.map(|aaguid| aaguid.to_string())
+                ],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn update_profile(
+        &self,
+        user_unique_id: Uuid,
+        name: &str,
+        display_name: &str,
+    ) -> Result<ProfileUpdate> {
+        let name = name.to_string();
+        let display_name = display_name.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let taken = tx
+                .query_row(
+                    "SELECT 1 FROM users WHERE name = ?1 AND unique_id != ?2",
+                    params![name, user_unique_id.to_string()],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if taken {
+                return Ok(ProfileUpdate::NameTaken);
+            }
+            let updated = tx.execute(
+                "UPDATE users SET name = ?1, display_name = ?2 WHERE unique_id = ?3",
+                params![name, display_name, user_unique_id.to_string()],
+            )?;
+            if updated == 0 {
+                return Ok(ProfileUpdate::NotFound);
+            }
+            let user = load_user(&tx, user_unique_id)?
+                .ok_or_else(|| anyhow::Error::msg("User vanished during the update"))?;
+            tx.commit()?;
+            Ok(ProfileUpdate::Updated(user))
+        })
+        .await
+    }
+
+    async fn update_credential(
+        &self,
+        user_unique_id: Uuid,
+        auth_result: &AuthenticationResult,
+    ) -> Result<()> {
+        let auth_result = auth_result.clone();
+        self.with_conn(move |conn| {
+            // Take the write lock up front, upgrading a read transaction fails outright
+            // when another connection is writing.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let keys = load_credentials(&tx, user_unique_id)?;
+            if keys.is_empty() {
+                return Err(anyhow::Error::msg("User has no credentials"));
+            }
+            for mut sk in keys {
+                // Only the matching credential reports a change, all others are left untouched.
+                match sk.update_credential(&auth_result) {
+                    Some(true) => {
+                        let passkey = serde_json::to_string(&sk).map_err(|e| {
+                            anyhow::Error::msg(format!("Failed to serialize passkey {}", e))
+                        })?;
+                        tx.execute(
+                            "UPDATE credentials SET passkey = ?1, last_used_at = ?2
+                            WHERE cred_id = ?3",
+                            params![passkey, unix_now(), sk.cred_id().to_string()],
+                        )?;
+                    }
+                    Some(false) => {
+                        tx.execute(
+                            "UPDATE credentials SET last_used_at = ?1 WHERE cred_id = ?2",
+                            params![unix_now(), sk.cred_id().to_string()],
+                        )?;
+                    }
+                    None => {}
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_credentials(&self, user_unique_id: Uuid) -> Result<Vec<StoredCredential>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT passkey, nickname, created_at, last_used_at, aaguid FROM credentials
+                WHERE user_id = ?1 ORDER BY created_at, rowid",
+            )?;
+            let rows = stmt.query_map(params![user_unique_id.to_string()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+            rows.map(|row| {
+                let (passkey, nickname, created_at, last_used_at, aaguid) = row?;
+                Ok(StoredCredential {
+                    passkey: parse_passkey(&passkey)?,
+                    nickname,
+                    created_at,
+                    last_used_at,
+                    aaguid: aaguid.map(parse_uuid).transpose()?,
+                })
+            })
+            .collect()
+        })
+        .await
+    }
+
+    async fn rename_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+        nickname: &str,
+    ) -> Result<bool> {
+        let cred_id = cred_id.to_string();
+        let nickname = nickname.to_string();
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE credentials SET nickname = ?1 WHERE cred_id = ?2 AND user_id = ?3",
+                params![nickname, cred_id, user_unique_id.to_string()],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn delete_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+    ) -> Result<CredentialRemoval> {
+        let cred_id = cred_id.to_string();
+        self.with_conn(move |conn| {
+            // Count and delete in one write transaction, so two concurrent deletes cannot
+            // both see two credentials and remove the last one together.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let user_id = user_unique_id.to_string();
+            let exists = tx
+                .query_row(
+                    "SELECT 1 FROM credentials WHERE cred_id = ?1 AND user_id = ?2",
+                    params![cred_id, user_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !exists {
+                return Ok(CredentialRemoval::NotFound);
+            }
+            let count: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM credentials WHERE user_id = ?1",
+                params![user_id],
+                |row| row.get(0),
+            )?;
+            if count <= 1 {
+                return Ok(CredentialRemoval::LastCredential);
+            }
+            tx.execute(
+                "DELETE FROM credentials WHERE cred_id = ?1",
+                params![cred_id],
+            )?;
+            tx.commit()?;
+            Ok(CredentialRemoval::Removed)
+        })
+        .await
+    }
+
+    async fn counts(&self) -> Result<UserCounts> {
+        self.with_conn(|conn| {
+            let (users, credentials): (i64, i64) = conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM users), (SELECT COUNT(*) FROM credentials)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            Ok(UserCounts {
+                users: users as usize,
+                credentials: credentials as usize,
+            })
+        })
+        .await
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_unique_id: Uuid,
+        code_hashes: &[String],
+    ) -> Result<()> {
+        let code_hashes = code_hashes.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let user_id = user_unique_id.to_string();
+            tx.execute(
+                "DELETE FROM recovery_codes WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            for code_hash in code_hashes {
+                tx.execute(
+                    "INSERT INTO recovery_codes (code_hash, user_id) VALUES (?1, ?2)",
+                    params![code_hash, user_id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn use_recovery_code(&self, user_unique_id: Uuid, code_hash: &str) -> Result<bool> {
+        let code_hash = code_hash.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM recovery_codes WHERE code_hash = ?1 AND user_id = ?2",
+                params![code_hash, user_unique_id.to_string()],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn count_recovery_codes(&self, user_unique_id: Uuid) -> Result<usize> {
+        self.with_conn(move |conn| {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?1",
+                params![user_unique_id.to_string()],
+                |row| row.get(0),
+            )?;
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    async fn insert_invite(&self, code_hash: &str, expires_at: i64) -> Result<()> {
+        let code_hash = code_hash.to_string();
+        self.with_conn(move |conn| {
+            // Expired invites are only kept until the next one is created.
+            conn.execute(
+                "DELETE FROM invites WHERE expires_at <= ?1",
+                params![unix_now()],
+            )?;
+            conn.execute(
+                "INSERT INTO invites (code_hash, expires_at) VALUES (?1, ?2)",
+                params![code_hash, expires_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_invite_valid(&self, code_hash: &str) -> Result<bool> {
+        let code_hash = code_hash.to_string();
+        self.with_conn(move |conn| {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM invites WHERE code_hash = ?1 AND expires_at > ?2",
+                params![code_hash, unix_now()],
+                |row| row.get(0),
+            )?;
+            Ok(count > 0)
+        })
+        .await
+    }
+
+    async fn search_users(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<User>, usize)> {
+        let pattern = contains_pattern(search);
+        let offset = i64::try_from(offset)?;
+        let limit = i64::try_from(limit)?;
+        self.with_conn(move |conn| {
+            let filter = "WHERE name LIKE ?1 ESCAPE '\\' OR display_name LIKE ?1 ESCAPE '\\'";
+            let total: i64 = conn.query_row(
+                &format!("SELECT COUNT(*) FROM users {}", filter),
+                params![pattern],
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM users {} ORDER BY created_at, name LIMIT ?2 OFFSET ?3",
+                USER_COLUMNS, filter
+            ))?;
+            let mut rows = stmt.query(params![pattern, limit, offset])?;
+            let mut users = vec![];
+            while let Some(row) = rows.next()? {
+                users.push(user_from_row(row)?);
+            }
+            Ok((users, total as usize))
+        })
+        .await
+    }
+
+    async fn set_roles(&self, user_unique_id: Uuid, roles: &[Role]) -> Result<bool> {
+        let roles = serde_json::to_string(roles)?;
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE users SET roles = ?1 WHERE unique_id = ?2",
+                params![roles, user_unique_id.to_string()],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn set_disabled(&self, user_unique_id: Uuid, disabled: bool) -> Result<bool> {
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE users SET disabled = ?1 WHERE unique_id = ?2",
+                params![disabled, user_unique_id.to_string()],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn revoke_credential(&self, user_unique_id: Uuid, cred_id: &str) -> Result<bool> {
+        let cred_id = cred_id.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM credentials WHERE cred_id = ?1 AND user_id = ?2",
+                params![cred_id, user_unique_id.to_string()],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
@@ -0,0 +1,1995 @@
+use crate::admin::{UserDetails, UserPage};
+use crate::api_tokens::{ApiTokens, BearerTokens};
+use crate::app;
+use crate::attestation;
+use crate::audit_log::MemoryAuditLog;
+use crate::config::{
+    AttestationConfig, BucketConfig, Config, MagicLinkConfig, MagicLinkMode, MailerConfig,
+    OidcClient, OidcConfig, RateLimitConfig, RegistrationConfig, RegistrationPolicy,
+    SessionStorage,
+};
+use crate::db::TempDb;
+use crate::jwt::JwtKeys;
+use crate::magic_link::MagicLinks;
+use crate::magic_link_store::MemoryMagicLinkStore;
+use crate::mailer;
+use crate::metrics::Metrics;
+use crate::models::{AppState, AuditAction, AuditEvent, CredentialInfo, Role, User};
+use crate::oidc::Oidc;
+use crate::openapi::ApiDoc;
+use crate::privacy::Privacy;
+use crate::rate_limit::RateLimiter;
+use crate::refresh_token_store::MemoryRefreshTokenStore;
+use crate::registration_policy::{self, Invite};
+use crate::session_key::SessionKeys;
+use crate::session_store::{MemorySessionStore, SessionBackend};
+use crate::sqlite_session_store::SqliteSessionStore;
+use crate::sqlite_user_store::SqliteUserStore;
+use crate::user_store::{MemoryUserStore, UserStore};
+use actix_web::cookie::Key;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, LOCATION};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpServer};
+use futures::future::join_all;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use utoipa::OpenApi;
+use webauthn_authenticator_rs::error::WebauthnCError;
+use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+use webauthn_authenticator_rs::softtoken::{self, SoftToken};
+use webauthn_authenticator_rs::{AuthenticatorBackend, WebauthnAuthenticator};
+use webauthn_rs::prelude::*;
+use webauthn_rs::WebauthnBuilder;
+use webauthn_rs_proto::{
+    AllowCredentials, PublicKeyCredentialCreationOptions, PublicKeyCredentialRequestOptions,
+    UserVerificationPolicy,
+};
+
+const RP_ID: &str = "localhost";
+const RP_ORIGIN: &str = "https://localhost:8443";
+const WORKERS: usize = 4;
+const USERS: usize = 16;
+
+/// Every test client connects from 127.0.0.1, so rate limiting is off unless a test
+/// turns it on.
+fn test_config() -> Config {
+    Config {
+        endpoint: "127.0.0.1:0".to_string(),
+        rp_id: RP_ID.to_string(),
+        rp_origin: RP_ORIGIN.to_string(),
+        database: None,
+        session_storage: SessionStorage::Memory,
+        rate_limit: RateLimitConfig {
+            enabled: false,
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+fn test_state(users: Arc<dyn UserStore>, sessions: SessionBackend) -> web::Data<AppState> {
+    test_state_with_config(test_config(), users, sessions)
+}
+
+fn test_state_with_config(
+    config: Config,
+    users: Arc<dyn UserStore>,
+    sessions: SessionBackend,
+) -> web::Data<AppState> {
+    let rate_limiter = RateLimiter::new(&config.rate_limit);
+    let mailer = mailer::from_config(&config.mailer, &config.mail_from).unwrap();
+    let attestation = attestation::ca_list(&config.attestation).unwrap();
+    let oidc = config
+        .oidc
+        .enabled
+        .then(|| Oidc::new(config.oidc.clone(), signing_keys()));
+    let api_tokens = ApiTokens::new(
+        config.api_tokens.clone(),
+        &config.rp_origin,
+        signing_keys(),
+        Arc::new(MemoryRefreshTokenStore::default()),
+    );
+    let session_key = Key::generate();
+    let magic_links = MagicLinks::from_config(
+        &config.magic_link,
+        session_key.master(),
+        Arc::new(MemoryMagicLinkStore::default()),
+    )
+    .unwrap();
+    let config = Arc::new(config);
+    let rp_origin = Url::parse(RP_ORIGIN).unwrap();
+    let webauthn = WebauthnBuilder::new(RP_ID, &rp_origin)
+        .unwrap()
+        .build()
+        .unwrap();
+    web::Data::new(AppState {
+        config,
+        webauthn: Arc::new(webauthn),
+        users,
+        sessions,
+        rate_limiter,
+        privacy: Privacy::new(session_key.master()).unwrap(),
+        mailer,
+        attestation,
+        audit_log: Arc::new(MemoryAuditLog::default()),
+        metrics: Metrics::default(),
+        oidc,
+        api_tokens,
+        magic_links,
+    })
+}
+
+/// Generating an RSA key takes a while, so all servers of the tests share one.
+fn signing_keys() -> JwtKeys {
+    static KEY: OnceLock<PKey<Private>> = OnceLock::new();
+    let key = KEY.get_or_init(|| PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap());
+    JwtKeys::new(key.clone()).unwrap()
+}
+
+/// Start a plain HTTP server with several workers on an ephemeral port.
+fn start_server(state: web::Data<AppState>) -> String {
+    let session_keys = SessionKeys::new(Key::generate());
+    let server = HttpServer::new(move || app(state.clone(), session_keys.clone()))
+        .workers(WORKERS)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}", addr)
+}
+
+/// A client with its own connection pool and cookie jar, so every instance is likely
+/// to be served by a different worker.
+struct Browser {
+    base_url: String,
+    client: awc::Client,
+    cookies: HashMap<String, String>,
+}
+
+/// Redirects are not followed, `/logout` points to the client which is not running.
+fn client() -> awc::Client {
+    awc::Client::builder()
+        .disable_redirects()
+        .add_default_header((actix_web::http::header::USER_AGENT, "webauthn-tests"))
+        .finish()
+}
+
+impl Browser {
+    fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            client: client(),
+            cookies: HashMap::new(),
+        }
+    }
+
+    /// Same cookies, but a new connection and therefore most likely another worker.
+    fn reconnect(&self) -> Self {
+        Self {
+            base_url: self.base_url.clone(),
+            client: client(),
+            cookies: self.cookies.clone(),
+        }
+    }
+
+    async fn post_json<B: Serialize, R: DeserializeOwned>(&mut self, path: &str, body: &B) -> R {
+        self.post(path, serde_json::to_string(body).unwrap()).await
+    }
+
+    async fn post<R: DeserializeOwned>(&mut self, path: &str, body: String) -> R {
+        let (status, body) = self.send(Method::POST, path, body).await;
+        assert!(
+            status.is_success(),
+            "POST {} failed with {} {:?}",
+            path,
+            status,
+            body
+        );
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn send(&mut self, method: Method, path: &str, body: String) -> (StatusCode, Bytes) {
+        let (status, _, body) = self.send_with_headers(method, path, body).await;
+        (status, body)
+    }
+
+    /// The `Location` of a `303 See Other` answer to `GET path`.
+    async fn redirect(&mut self, path: &str) -> String {
+        let (status, headers, body) = self
+            .send_with_headers(Method::GET, path, String::new())
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER, "GET {} {:?}", path, body);
+        headers.get(LOCATION).unwrap().to_str().unwrap().to_string()
+    }
+
+    async fn send_with_headers(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: String,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        let cookie = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut resp = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path))
+            .insert_header(("Cookie", cookie))
+            .insert_header(("Content-Type", "application/json"))
+            .send_body(body)
+            .await
+            .unwrap();
+        let body = resp.body().await.unwrap();
+        for cookie in resp.cookies().unwrap().iter() {
+            self.cookies
+                .insert(cookie.name().to_string(), cookie.value().to_string());
+        }
+        (resp.status(), resp.headers().clone(), body)
+    }
+}
+
+/// The soft authenticators refuse to create resident keys and always need to be told which
+/// credential to use. This keeps the resident keys for them, like a platform authenticator
+/// would: it picks the credential when a discoverable login leaves the choice to it and
+/// returns the user handle stored with it.
+struct ResidentKeys<T> {
+    backend: T,
+    /// The relying party id, credential id and user handle of every resident key.
+    keys: Vec<(String, Base64UrlSafeData, Base64UrlSafeData)>,
+}
+
+fn with_resident_keys<T: AuthenticatorBackend>(
+    backend: T,
+) -> WebauthnAuthenticator<ResidentKeys<T>> {
+    WebauthnAuthenticator::new(ResidentKeys {
+        backend,
+        keys: Vec::new(),
+    })
+}
+
+/// The soft authenticators also refuse requests demanding user verification, although the
+/// soft token always performs it.
+fn without_required_verification(policy: &mut UserVerificationPolicy) {
+    if *policy == UserVerificationPolicy::Required {
+        *policy = UserVerificationPolicy::Preferred;
+    }
+}
+
+impl<T: AuthenticatorBackend> AuthenticatorBackend for ResidentKeys<T> {
+    fn perform_register(
+        &mut self,
+        origin: Url,
+        mut options: PublicKeyCredentialCreationOptions,
+        timeout_ms: u32,
+    ) -> Result<RegisterPublicKeyCredential, WebauthnCError> {
+        let resident = match options.authenticator_selection.as_mut() {
+            Some(selection) => {
+                without_required_verification(&mut selection.user_verification);
+                std::mem::take(&mut selection.require_resident_key)
+            }
+            None => false,
+        };
+        let rp_id = options.rp.id.clone();
+        let user_handle = options.user.id.clone();
+        let credential = self.backend.perform_register(origin, options, timeout_ms)?;
+        if resident {
+            self.keys
+                .push((rp_id, credential.raw_id.clone(), user_handle));
+        }
+        Ok(credential)
+    }
+
+    fn perform_auth(
+        &mut self,
+        origin: Url,
+        mut options: PublicKeyCredentialRequestOptions,
+        timeout_ms: u32,
+    ) -> Result<PublicKeyCredential, WebauthnCError> {
+        if options.allow_credentials.is_empty() {
+            let (_, id, _) = self
+                .keys
+                .iter()
+                .find(|(rp_id, _, _)| *rp_id == options.rp_id)
+                .ok_or(WebauthnCError::Internal)?;
+            options.allow_credentials.push(AllowCredentials {
+                type_: "public-key".to_string(),
+                id: id.clone(),
+                transports: None,
+            });
+        }
+        without_required_verification(&mut options.user_verification);
+        let mut credential = self.backend.perform_auth(origin, options, timeout_ms)?;
+        credential.response.user_handle = self
+            .keys
+            .iter()
+            .find(|(_, id, _)| *id == credential.raw_id)
+            .map(|(_, _, user_handle)| user_handle.clone());
+        Ok(credential)
+    }
+}
+
+async fn register_then_login_elsewhere(base_url: &str, name: String) {
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut registering = Browser::new(base_url);
+    let ccr: CreationChallengeResponse = registering
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": name, "display_name": name }),
+        )
+        .await;
+    let rpkc = authenticator
+        .do_registration(origin.clone(), ccr)
+        .expect("Failed to register with the soft passkey");
+    // The ceremony state lives in the session, which has to follow us to another worker.
+    let mut registering = registering.reconnect();
+    let _: serde_json::Value = registering.post_json("/register_finish", &rpkc).await;
+
+    // A fresh browser opens a new connection, which lands on another worker.
+    let mut logging_in = Browser::new(base_url);
+    let rcr: RequestChallengeResponse = logging_in.post("/login_start", name.clone()).await;
+    let pkc = authenticator
+        .do_authentication(origin, rcr)
+        .expect("Failed to authenticate with the soft passkey");
+    let mut logging_in = logging_in.reconnect();
+    let user: serde_json::Value = logging_in.post_json("/login_finish", &pkc).await;
+    assert_eq!(user["name"], name.as_str());
+}
+
+/// Only the soft token verifies the user, which usernameless logins require.
+#[actix_web::test]
+async fn discoverable_login_identifies_the_user() {
+    let state = test_state(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state.clone());
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftToken::new().unwrap().0);
+
+    let mut registering = Browser::new(&base_url);
+    let ccr: CreationChallengeResponse = registering
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "alice", "display_name": "Alice" }),
+        )
+        .await;
+    let selection = ccr.public_key.authenticator_selection.as_ref().unwrap();
+    assert!(selection.require_resident_key);
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let _: serde_json::Value = registering.post_json("/register_finish", &rpkc).await;
+
+    let mut logging_in = Browser::new(&base_url);
+    let rcr: RequestChallengeResponse = logging_in
+        .post("/login_discoverable_start", String::new())
+        .await;
+    assert!(rcr.public_key.allow_credentials.is_empty());
+    let pkc = authenticator
+        .do_authentication(origin.clone(), rcr)
+        .unwrap();
+    let user: serde_json::Value = logging_in
+        .post_json("/login_discoverable_finish", &pkc)
+        .await;
+    assert_eq!(user["name"], "alice");
+
+    // A failed login claiming to be bob is not recorded on his account.
+    let mut registering = Browser::new(&base_url);
+    let ccr: CreationChallengeResponse = registering
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "bob", "display_name": "Bob" }),
+        )
+        .await;
+    let mut other = with_resident_keys(SoftToken::new().unwrap().0);
+    let rpkc = other.do_registration(origin.clone(), ccr).unwrap();
+    let bob: serde_json::Value = registering.post_json("/register_finish", &rpkc).await;
+    let bob = Uuid::parse_str(bob["unique_id"].as_str().unwrap()).unwrap();
+    let mut logging_in = Browser::new(&base_url);
+    let rcr: RequestChallengeResponse = logging_in
+        .post("/login_discoverable_start", String::new())
+        .await;
+    let mut pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    pkc.response.user_handle = Some(Base64UrlSafeData(bob.as_bytes().to_vec()));
+    let (status, _) = logging_in
+        .send(
+            Method::POST,
+            "/login_discoverable_finish",
+            serde_json::to_string(&pkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let events = state.audit_log.recent_events(bob, 10).await.unwrap();
+    let actions: Vec<_> = events.iter().map(|event| event.action).collect();
+    assert_eq!(actions, [AuditAction::Register]);
+}
+
+/// The `code` of a problem details response.
+fn problem_code(body: &[u8]) -> String {
+    let problem: serde_json::Value = serde_json::from_slice(body).unwrap();
+    problem["code"].as_str().unwrap().to_string()
+}
+
+async fn credentials_can_be_listed_renamed_and_deleted(users: Arc<dyn UserStore>) {
+    let state = test_state(users, SessionBackend::Memory(MemorySessionStore::default()));
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut browser = Browser::new(&base_url);
+
+    let (status, body) = browser
+        .send(Method::GET, "/credentials", String::new())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(problem_code(&body), "unauthenticated");
+
+    // Register a passkey, then add a second one while logged in.
+    for _ in 0..2 {
+        let mut authenticator = with_resident_keys(SoftPasskey::new());
+        let ccr: CreationChallengeResponse = browser
+            .post_json(
+                "/register_start",
+                &serde_json::json!({ "name": "bob", "display_name": "Bob" }),
+            )
+            .await;
+        let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+        let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    }
+
+    let (status, body) = browser
+        .send(Method::GET, "/credentials", String::new())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials.len(), 2);
+    assert!(credentials.iter().all(|c| c.nickname == "Passkey"));
+    let (first, second) = (&credentials[0].id, &credentials[1].id);
+
+    let rename = serde_json::json!({ "nickname": "Laptop" }).to_string();
+    let path = format!("/credentials/{}", first);
+    let (status, _) = browser.send(Method::PUT, &path, rename.clone()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = browser
+        .send(Method::PUT, "/credentials/unknown", rename)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = browser.send(Method::DELETE, &path, String::new()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let path = format!("/credentials/{}", second);
+    let (status, body) = browser.send(Method::DELETE, &path, String::new()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(problem_code(&body), "last_credential");
+
+    let (_, body) = browser
+        .send(Method::GET, "/credentials", String::new())
+        .await;
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials.len(), 1);
+    assert_eq!(&credentials[0].id, second);
+}
+
+#[actix_web::test]
+async fn memory_store_manages_credentials() {
+    credentials_can_be_listed_renamed_and_deleted(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_manages_credentials() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    credentials_can_be_listed_renamed_and_deleted(Arc::new(users)).await;
+}
+
+async fn profile_can_be_changed(users: Arc<dyn UserStore>) {
+    let state = test_state(users, SessionBackend::Memory(MemorySessionStore::default()));
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    for name in ["carol", "dave"] {
+        browser = Browser::new(&base_url);
+        let ccr: CreationChallengeResponse = browser
+            .post_json(
+                "/register_start",
+                &serde_json::json!({ "name": name, "display_name": "Dave Doe" }),
+            )
+            .await;
+        let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+        let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    }
+    let (_, body) = browser.send(Method::GET, "/identity", String::new()).await;
+    let user: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(user["name"], "dave");
+    assert_eq!(user["display_name"], "Dave Doe");
+    assert!(user["created_at"].as_i64().unwrap() > 0);
+
+    let profile = serde_json::json!({ "name": "carol", "display_name": "Dave" }).to_string();
+    let (status, body) = browser.send(Method::PUT, "/profile", profile).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(problem_code(&body), "username_taken");
+
+    let (status, body) = browser
+        .send(Method::PUT, "/profile", "{}".to_string())
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_input");
+
+    let profile = serde_json::json!({ "name": "david", "display_name": "David" }).to_string();
+    let (status, body) = browser.send(Method::PUT, "/profile", profile).await;
+    assert_eq!(status, StatusCode::OK);
+    let renamed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(renamed["unique_id"], user["unique_id"]);
+    assert_eq!(renamed["display_name"], "David");
+
+    // The passkey keeps working under the new name, the old one is free again.
+    let mut logging_in = Browser::new(&base_url);
+    let rcr: RequestChallengeResponse = logging_in.post("/login_start", "david".to_string()).await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let user: serde_json::Value = logging_in.post_json("/login_finish", &pkc).await;
+    assert_eq!(user["name"], "david");
+    let (status, body) = logging_in
+        .send(Method::POST, "/login_start", "dave".to_string())
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(problem_code(&body), "user_not_found");
+}
+
+#[actix_web::test]
+async fn memory_store_changes_profiles() {
+    profile_can_be_changed(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_changes_profiles() {
+    let db = TempDb::new("test");
+    profile_can_be_changed(Arc::new(SqliteUserStore::open(&db.path).unwrap())).await;
+}
+
+async fn registrations_are_visible_across_workers(
+    users: Arc<dyn UserStore>,
+    sessions: SessionBackend,
+) {
+    let state = test_state(users, sessions);
+    let base_url = start_server(state.clone());
+    join_all(
+        (0..USERS).map(|i| {
+            let base_url = base_url.clone();
+            async move {
+                register_then_login_elsewhere(&base_url, format!("user{}@example.com", i)).await
+            }
+        }),
+    )
+    .await;
+    // Every ceremony used its own session, all of which are still alive.
+    assert_eq!(state.sessions.stats().await.unwrap().active, 2 * USERS);
+}
+
+#[actix_web::test]
+async fn memory_store_registrations_are_visible_across_workers() {
+    registrations_are_visible_across_workers(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_registrations_are_visible_across_workers() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    let sessions = SqliteSessionStore::open(&db.path).unwrap();
+    registrations_are_visible_across_workers(Arc::new(users), SessionBackend::Sqlite(sessions))
+        .await;
+}
+
+#[actix_web::test]
+async fn ceremonies_are_rate_limited_per_username() {
+    let config = Config {
+        rate_limit: RateLimitConfig {
+            per_username: BucketConfig {
+                burst: 2,
+                per_minute: 1,
+            },
+            ..RateLimitConfig::default()
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let mut browser = Browser::new(&base_url);
+
+    for _ in 0..2 {
+        let (status, _) = browser
+            .send(Method::POST, "/login_start", "erin".to_string())
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+    let (status, body) = browser
+        .send(Method::POST, "/login_start", "erin".to_string())
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(problem_code(&body), "rate_limited");
+
+    // Another username still has its tokens, and the body reaches the handler intact.
+    let (status, body) = browser
+        .send(Method::POST, "/login_start", "frank".to_string())
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(problem_code(&body), "user_not_found");
+}
+
+/// Remove the only email the file mailer wrote to `dir`, and return it along with the
+/// token of the link in it.
+/// The emails in `dir`, leaving out those still being written.
+fn emails(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+        .collect()
+}
+
+/// Wait for the only email in `dir`, which may be sent in the background, and remove it.
+async fn take_email(dir: &std::path::Path) -> (String, Option<String>) {
+    let mut paths = emails(dir);
+    for _ in 0..100 {
+        if !paths.is_empty() {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+        paths = emails(dir);
+    }
+    assert_eq!(paths.len(), 1);
+    let email = std::fs::read_to_string(&paths[0]).unwrap();
+    std::fs::remove_file(&paths[0]).unwrap();
+    let token = email
+        .split_once("token=")
+        .map(|(_, rest)| rest.split_whitespace().next().unwrap().to_string());
+    (email, token)
+}
+
+#[actix_web::test]
+async fn privacy_mode_hides_which_usernames_exist() {
+    let dir = std::env::temp_dir().join(format!("webauthn-mail-{}", Uuid::new_v4()));
+    let config = Config {
+        privacy_mode: true,
+        mailer: MailerConfig::File {
+            dir: dir.to_string_lossy().to_string(),
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+    let registration = serde_json::json!({ "name": "alice@example.com", "display_name": "Alice" });
+
+    // Registration only continues with the token mailed to the address.
+    let mut browser = Browser::new(&base_url);
+    let (status, body) = browser
+        .send(Method::POST, "/register_start", registration.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(body.is_empty());
+    let (_, token) = take_email(&dir).await;
+    let token = token.expect("The email has no link");
+    let (status, body) = browser
+        .send(
+            Method::POST,
+            "/register_verify",
+            serde_json::json!({ "token": format!("{}x", token) }).to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_token");
+    let ccr: CreationChallengeResponse = browser
+        .post_json("/register_verify", &serde_json::json!({ "token": token }))
+        .await;
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+
+    // Registering a taken name looks the same, but its owner is told instead.
+    let mut stranger = Browser::new(&base_url);
+    let (status, _) = stranger
+        .send(Method::POST, "/register_start", registration.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (email, token) = take_email(&dir).await;
+    assert!(email.contains("To: alice@example.com"));
+    assert_eq!(token, None);
+
+    // Unknown users are offered a stable decoy passkey that never verifies.
+    let first: RequestChallengeResponse = stranger
+        .post("/login_start", "bob@example.com".to_string())
+        .await;
+    let again: RequestChallengeResponse = stranger
+        .post("/login_start", "bob@example.com".to_string())
+        .await;
+    assert_eq!(first.public_key.allow_credentials.len(), 1);
+    assert_eq!(
+        first.public_key.allow_credentials[0].id,
+        again.public_key.allow_credentials[0].id
+    );
+    // Even a valid assertion of another user does not match the decoy.
+    let mut rcr: RequestChallengeResponse = Browser::new(&base_url)
+        .post("/login_start", "alice@example.com".to_string())
+        .await;
+    rcr.public_key.challenge = again.public_key.challenge;
+    let pkc = authenticator
+        .do_authentication(origin.clone(), rcr)
+        .unwrap();
+    let (status, body) = stranger
+        .send(
+            Method::POST,
+            "/login_finish",
+            serde_json::to_string(&pkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(problem_code(&body), "authentication_failed");
+
+    // The real user still logs in.
+    let mut logging_in = Browser::new(&base_url);
+    let rcr: RequestChallengeResponse = logging_in
+        .post("/login_start", "alice@example.com".to_string())
+        .await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let user: serde_json::Value = logging_in.post_json("/login_finish", &pkc).await;
+    assert_eq!(user["name"], "alice@example.com");
+
+    // Renames would neither be verified nor hide whether the new address is taken.
+    let rename = serde_json::json!({ "name": "bob@example.com", "display_name": "Bob" });
+    let (status, body) = logging_in
+        .send(Method::PUT, "/profile", rename.to_string())
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_input");
+    let profile = serde_json::json!({ "name": "alice@example.com", "display_name": "Ali" });
+    let (status, _) = logging_in
+        .send(Method::PUT, "/profile", profile.to_string())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Register "dave" with `authenticator` in a new browser, which stays logged in on success.
+/// Every soft token attests its credentials with a CA of its own.
+#[actix_web::test]
+async fn attestation_is_required_from_trusted_cas() {
+    let (token, ca) = SoftToken::new().unwrap();
+    let ca_file = std::env::temp_dir().join(format!("webauthn-ca-{}.pem", Uuid::new_v4()));
+    std::fs::write(&ca_file, ca.to_pem().unwrap()).unwrap();
+    let db = TempDb::new("test");
+    let config = Config {
+        attestation: AttestationConfig {
+            required: true,
+            ca_files: vec![ca_file.to_str().unwrap().to_string()],
+            ..AttestationConfig::default()
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(SqliteUserStore::open(&db.path).unwrap()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+
+    let mut other_token = with_resident_keys(SoftToken::new().unwrap().0);
+    let mut browser = Browser::new(&base_url);
+    let (status, body) = try_register(&mut browser, "dave", &mut other_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "untrusted_authenticator");
+
+    let mut token = with_resident_keys(token);
+    let mut browser = Browser::new(&base_url);
+    let (status, _) = try_register(&mut browser, "dave", &mut token).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = browser
+        .send(Method::GET, "/credentials", String::new())
+        .await;
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials[0].aaguid, Some(softtoken::AAGUID));
+
+    std::fs::remove_file(ca_file).unwrap();
+}
+
+#[actix_web::test]
+async fn security_events_are_recorded() {
+    let state = test_state(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "erin", "display_name": "Erin" }),
+        )
+        .await;
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let (status, _) = browser.send(Method::GET, "/logout", String::new()).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+
+    // A forged assertion fails, then the real one logs in.
+    let rcr: RequestChallengeResponse = browser.post("/login_start", "erin".to_string()).await;
+    let mut pkc = authenticator
+        .do_authentication(origin.clone(), rcr)
+        .unwrap();
+    pkc.response.signature.0[10] ^= 1;
+    let (status, _) = browser
+        .send(
+            Method::POST,
+            "/login_finish",
+            serde_json::to_string(&pkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let rcr: RequestChallengeResponse = browser.post("/login_start", "erin".to_string()).await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let _: serde_json::Value = browser.post_json("/login_finish", &pkc).await;
+
+    let (status, body) = browser
+        .send(Method::GET, "/security_events", String::new())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let events: Vec<AuditEvent> = serde_json::from_slice(&body).unwrap();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| (event.action, event.outcome.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (AuditAction::Login, "success"),
+            (AuditAction::Login, "authentication_failed"),
+            (AuditAction::Logout, "success"),
+            (AuditAction::Register, "success"),
+        ]
+    );
+    assert_eq!(events[0].credential_id.as_deref(), Some(pkc.id.as_str()));
+    assert_eq!(events[0].client_ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(events[0].user_agent.as_deref(), Some("webauthn-tests"));
+
+    let (_, body) = browser
+        .send(Method::GET, "/security_events?limit=1", String::new())
+        .await;
+    let events: Vec<AuditEvent> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(events.len(), 1);
+    browser.send(Method::GET, "/logout", String::new()).await;
+    let (status, _) = browser
+        .send(Method::GET, "/security_events", String::new())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn metrics_count_ceremonies_and_accounts() {
+    let db = TempDb::new("test");
+    let state = test_state(
+        Arc::new(SqliteUserStore::open(&db.path).unwrap()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "gina", "display_name": "Gina" }),
+        )
+        .await;
+    let rpkc = authenticator.do_registration(origin, ccr).unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let (status, _) = browser
+        .send(Method::POST, "/login_start", "nobody".to_string())
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Only the first user, who is an administrator, sees the statistics.
+    let mut anonymous = Browser::new(&base_url);
+    for path in ["/metrics", "/stats/sessions"] {
+        let (status, _) = anonymous.send(Method::GET, path, String::new()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+    }
+    let (status, _) = browser
+        .send(Method::GET, "/stats/sessions", String::new())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = browser.send(Method::GET, "/metrics", String::new()).await;
+    assert_eq!(status, StatusCode::OK);
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    for line in [
+        r#"webauthn_ceremonies_total{ceremony="register",outcome="success",step="start"} 1"#,
+        r#"webauthn_ceremonies_total{ceremony="register",outcome="success",step="finish"} 1"#,
+        r#"webauthn_ceremonies_total{ceremony="login",outcome="user_not_found",step="start"} 1"#,
+        r#"webauthn_http_request_duration_seconds_count{method="POST",path="/login_start",status="404"} 1"#,
+        "webauthn_active_sessions 1",
+        "webauthn_users 1",
+        "webauthn_credentials 1",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "{} in {}",
+            line,
+            metrics
+        );
+    }
+}
+
+/// Fails for properties of `value` the schema does not know and for required properties
+/// missing from `value`.
+fn assert_matches_schema(
+    spec: &serde_json::Value,
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    at: &str,
+) {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/components/schemas/");
+        let schema = &spec["components"]["schemas"][name];
+        assert!(
+            schema.is_object(),
+            "{} refers to the unknown schema {}",
+            at,
+            name
+        );
+        return assert_matches_schema(spec, schema, value, at);
+    }
+    if let Some(all_of) = schema["allOf"].as_array() {
+        // Flattened structs, their parts only know some of the properties each.
+        let mut merged = serde_json::json!({ "properties": {}, "required": [] });
+        for schema in all_of {
+            let schema = match schema["$ref"].as_str() {
+                Some(reference) => {
+                    &spec["components"]["schemas"]
+                        [reference.trim_start_matches("#/components/schemas/")]
+                }
+                None => schema,
+            };
+            for (key, property) in schema["properties"].as_object().into_iter().flatten() {
+                merged["properties"][key] = property.clone();
+            }
+            for key in schema["required"].as_array().into_iter().flatten() {
+                merged["required"].as_array_mut().unwrap().push(key.clone());
+            }
+        }
+        return assert_matches_schema(spec, &merged, value, at);
+    }
+    match value {
+        serde_json::Value::Null => assert!(schema["nullable"] == true, "{} is null", at),
+        serde_json::Value::Object(object) => {
+            let properties = match schema["properties"].as_object() {
+                Some(properties) => properties,
+                // A free-form object
+                None => return,
+            };
+            for (key, value) in object {
+                let at = format!("{}.{}", at, key);
+                let schema = properties
+                    .get(key)
+                    .unwrap_or_else(|| panic!("{} is not in the schema", at));
+                assert_matches_schema(spec, schema, value, &at);
+            }
+            for key in schema["required"].as_array().into_iter().flatten() {
+                let key = key.as_str().unwrap();
+                assert!(object.contains_key(key), "{}.{} is missing", at, key);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                assert_matches_schema(spec, &schema["items"], item, &format!("{}[{}]", at, i));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn schema(name: &str) -> serde_json::Value {
+    serde_json::json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+#[actix_web::test]
+async fn openapi_describes_the_webauthn_json() {
+    let state = test_state(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+    let mut browser = Browser::new(&base_url);
+
+    let (status, body) = browser
+        .send(Method::GET, "/openapi.json", String::new())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(spec, serde_json::to_value(ApiDoc::openapi()).unwrap());
+
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "hank", "display_name": "Hank" }),
+        )
+        .await;
+    let rpkc = authenticator
+        .do_registration(origin.clone(), ccr.clone())
+        .unwrap();
+    let user: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let rcr: RequestChallengeResponse = browser.post("/login_start", "hank".to_string()).await;
+    let pkc = authenticator
+        .do_authentication(origin, rcr.clone())
+        .unwrap();
+    let _: serde_json::Value = browser.post_json("/login_finish", &pkc).await;
+
+    for (name, value) in [
+        (
+            "CreationChallengeResponse",
+            serde_json::to_value(&ccr).unwrap(),
+        ),
+        (
+            "RegisterPublicKeyCredential",
+            serde_json::to_value(&rpkc).unwrap(),
+        ),
+        ("Registered", user),
+        (
+            "RequestChallengeResponse",
+            serde_json::to_value(&rcr).unwrap(),
+        ),
+        ("PublicKeyCredential", serde_json::to_value(&pkc).unwrap()),
+    ] {
+        assert_matches_schema(&spec, &schema(name), &value, name);
+    }
+}
+
+/// Parse the query of a redirect to the OpenID Connect client.
+fn redirect_params(location: &str) -> HashMap<String, String> {
+    Url::parse(location)
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect()
+}
+
+/// Redeem a code as a public client.
+async fn token_request(
+    base_url: &str,
+    code: &str,
+    verifier: &str,
+) -> awc::ClientResponse<impl futures::Stream<Item = Result<Bytes, awc::error::PayloadError>>> {
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", "https://wiki.example.com/callback"),
+        ("client_id", "wiki"),
+        ("code_verifier", verifier),
+    ];
+    client()
+        .post(format!("{}/oidc/token", base_url))
+        .send_form(&form)
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn passkey_login_completes_openid_connect_authorizations() {
+    let config = Config {
+        oidc: OidcConfig {
+            enabled: true,
+            clients: vec![OidcClient {
+                client_id: "wiki".to_string(),
+                client_secret: None,
+                redirect_uris: vec!["https://wiki.example.com/callback".to_string()],
+            }],
+            ..OidcConfig::default()
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state.clone());
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    let (status, body) = browser
+        .send(
+            Method::GET,
+            "/.well-known/openid-configuration",
+            String::new(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let discovery: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(discovery["token_endpoint"], "https://localhost/oidc/token");
+    let (_, body) = browser.send(Method::GET, "/oidc/jwks", String::new()).await;
+    let jwks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(jwks["keys"][0]["alg"], "RS256");
+
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "hana", "display_name": "Hana" }),
+        )
+        .await;
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let user: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let (status, _) = browser.send(Method::GET, "/logout", String::new()).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+
+    // The client sends the browser here, which is not logged in and goes to the login page.
+    let verifier = "a-code-verifier-with-at-least-43-characters-of-entropy";
+    let challenge = base64::encode_config(
+        openssl::sha::sha256(verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let authorize = format!(
+        "/oidc/authorize?response_type=code&client_id=wiki\
+        &redirect_uri=https%3A%2F%2Fwiki.example.com%2Fcallback&scope=openid%20profile\
+        &state=xyz&nonce=n-0S6&code_challenge={}&code_challenge_method=S256",
+        challenge
+    );
+    assert_eq!(
+        browser.redirect(&authorize).await,
+        "https://localhost:8443/"
+    );
+    let unregistered = authorize.replace("wiki.example.com", "evil.example.com");
+    let (status, body) = browser
+        .send(Method::GET, &unregistered, String::new())
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_input");
+
+    let rcr: RequestChallengeResponse = browser.post("/login_start", "hana".to_string()).await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let logged_in: serde_json::Value = browser.post_json("/login_finish", &pkc).await;
+    assert_eq!(logged_in["name"], "hana");
+    let params = redirect_params(logged_in["redirect_to"].as_str().unwrap());
+    assert_eq!(params["state"], "xyz");
+
+    let mut resp = token_request(&base_url, &params["code"], "the-wrong-verifier").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(error["error"], "invalid_grant");
+    // A failed attempt used up the code, so authorize again while logged in.
+    let params = redirect_params(&browser.redirect(&authorize).await);
+    let mut resp = token_request(&base_url, &params["code"], verifier).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let tokens: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(tokens["token_type"], "Bearer");
+    let id_token = tokens["id_token"]
+        .as_str()
+        .unwrap()
+        .split('.')
+        .nth(1)
+        .unwrap();
+    let id_token: serde_json::Value =
+        serde_json::from_slice(&base64::decode_config(id_token, base64::URL_SAFE_NO_PAD).unwrap())
+            .unwrap();
+    assert_eq!(id_token["sub"], user["unique_id"]);
+    assert_eq!(id_token["aud"], "wiki");
+    assert_eq!(id_token["nonce"], "n-0S6");
+    assert_eq!(id_token["preferred_username"], "hana");
+    let resp = token_request(&base_url, &params["code"], verifier).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let access_token = tokens["access_token"].as_str().unwrap();
+    let mut resp = client()
+        .get(format!("{}/oidc/userinfo", base_url))
+        .insert_header((AUTHORIZATION, format!("Bearer {}", access_token)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let user_info: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(user_info["sub"], user["unique_id"]);
+    assert_eq!(user_info["name"], "Hana");
+    let resp = client()
+        .get(format!("{}/oidc/userinfo", base_url))
+        .insert_header((AUTHORIZATION, format!("Bearer {}x", access_token)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    // The access token is for the client, not for the API, although the key is the same.
+    let (status, _) = get_with_token(&base_url, "/identity", access_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Neither codes nor access tokens outlive disabling the account.
+    let params = redirect_params(&browser.redirect(&authorize).await);
+    let hana = Uuid::parse_str(user["unique_id"].as_str().unwrap()).unwrap();
+    state.users.set_disabled(hana, true).await.unwrap();
+    let mut resp = token_request(&base_url, &params["code"], verifier).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(error["error"], "invalid_grant");
+    let resp = client()
+        .get(format!("{}/oidc/userinfo", base_url))
+        .insert_header((AUTHORIZATION, format!("Bearer {}", access_token)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+/// Send `GET path` with an access token instead of the session cookie.
+async fn get_with_token(base_url: &str, path: &str, access_token: &str) -> (StatusCode, Bytes) {
+    let mut resp = client()
+        .get(format!("{}{}", base_url, path))
+        .insert_header((AUTHORIZATION, format!("Bearer {}", access_token)))
+        .send()
+        .await
+        .unwrap();
+    (resp.status(), resp.body().await.unwrap())
+}
+
+#[actix_web::test]
+async fn api_clients_use_bearer_tokens_after_a_ceremony() {
+    let state = test_state(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut script = Browser::new(&base_url);
+    let (status, _) = script.send(Method::POST, "/token", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let ccr: CreationChallengeResponse = script
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "ivan", "display_name": "Ivan" }),
+        )
+        .await;
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let _: serde_json::Value = script.post_json("/register_finish", &rpkc).await;
+    let rcr: RequestChallengeResponse = script.post("/login_start", "ivan".to_string()).await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let _: serde_json::Value = script.post_json("/login_finish", &pkc).await;
+    let tokens: BearerTokens = script.post("/token", String::new()).await;
+    assert_eq!(tokens.token_type, "Bearer");
+    // Every ceremony allows one set of tokens.
+    let (status, _) = script.send(Method::POST, "/token", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // No cookie, only the access token.
+    let (status, body) = get_with_token(&base_url, "/identity", &tokens.access_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let user: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(user["name"], "ivan");
+    let (status, body) = get_with_token(&base_url, "/credentials", &tokens.access_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials.len(), 1);
+    let forged = format!("{}x", tokens.access_token);
+    let (status, body) = get_with_token(&base_url, "/identity", &forged).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(problem_code(&body), "unauthenticated");
+
+    let mut api = Browser::new(&base_url);
+    let refresh = serde_json::json!({ "refresh_token": tokens.refresh_token });
+    let refreshed: BearerTokens = api.post_json("/token/refresh", &refresh).await;
+    let (status, _) = get_with_token(&base_url, "/identity", &refreshed.access_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = api
+        .send(Method::POST, "/token/refresh", refresh.to_string())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(problem_code(&body), "invalid_refresh_token");
+
+    let refresh = serde_json::json!({ "refresh_token": refreshed.refresh_token });
+    let (status, _) = api
+        .send(Method::POST, "/token/revoke", refresh.to_string())
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = api
+        .send(Method::POST, "/token/refresh", refresh.to_string())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+async fn recovery_codes_add_a_passkey(users: Arc<dyn UserStore>) {
+    let state = test_state(users, SessionBackend::Memory(MemorySessionStore::default()));
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut lost = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "judy", "display_name": "Judy" }),
+        )
+        .await;
+    let rpkc = lost.do_registration(origin.clone(), ccr).unwrap();
+    let registered: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    assert_eq!(registered["name"], "judy");
+    let codes: Vec<String> = serde_json::from_value(registered["recovery_codes"].clone()).unwrap();
+    assert_eq!(codes.len(), 10);
+    let (_, body) = browser
+        .send(Method::GET, "/recovery_codes", String::new())
+        .await;
+    let remaining: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(remaining, serde_json::json!({ "remaining": 10 }));
+
+    // Without a code the name stays taken.
+    let mut replacement = with_resident_keys(SoftPasskey::new());
+    let mut other = Browser::new(&base_url);
+    let taken = serde_json::json!({ "name": "judy", "display_name": "Judy" });
+    let (status, _) = other
+        .send(Method::POST, "/register_start", taken.to_string())
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    for (name, code) in [
+        ("judy", "0000-0000-0000-0000"),
+        ("nobody", codes[0].as_str()),
+    ] {
+        let recovery = serde_json::json!({ "name": name, "code": code });
+        let (status, body) = other
+            .send(Method::POST, "/recover", recovery.to_string())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(problem_code(&body), "invalid_recovery_code");
+    }
+
+    let recovery = serde_json::json!({ "name": "judy", "code": codes[0].to_lowercase() });
+    let user: serde_json::Value = other.post_json("/recover", &recovery).await;
+    assert_eq!(user["display_name"], "Judy");
+    // Recovering does not log in.
+    let (status, _) = other.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let ccr: CreationChallengeResponse = other.post_json("/register_start", &taken).await;
+    let rpkc = replacement.do_registration(origin.clone(), ccr).unwrap();
+    let registered: serde_json::Value = other.post_json("/register_finish", &rpkc).await;
+    assert!(registered.get("recovery_codes").is_none());
+    let (_, body) = other.send(Method::GET, "/credentials", String::new()).await;
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials.len(), 2);
+
+    let mut elsewhere = Browser::new(&base_url);
+    let rcr: RequestChallengeResponse = elsewhere.post("/login_start", "judy".to_string()).await;
+    let pkc = replacement.do_authentication(origin, rcr).unwrap();
+    let _: serde_json::Value = elsewhere.post_json("/login_finish", &pkc).await;
+
+    // Every code works once, new codes replace the old ones.
+    let (status, _) = elsewhere
+        .send(Method::POST, "/recover", recovery.to_string())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, body) = elsewhere
+        .send(Method::GET, "/recovery_codes", String::new())
+        .await;
+    let remaining: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(remaining["remaining"], 9);
+    let regenerated: serde_json::Value = elsewhere.post("/recovery_codes", String::new()).await;
+    assert_eq!(regenerated["remaining"], 10);
+    let recovery = serde_json::json!({ "name": "judy", "code": codes[1] });
+    let (status, _) = elsewhere
+        .send(Method::POST, "/recover", recovery.to_string())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+/// Recovery sessions are not logged in, so logging the user out does not end them.
+#[actix_web::test]
+async fn disabling_ends_recovery_and_api_tokens() {
+    let state = test_state(
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let base_url = start_server(state.clone());
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut lost = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    let lena = serde_json::json!({ "name": "lena", "display_name": "Lena" });
+    let ccr: CreationChallengeResponse = browser.post_json("/register_start", &lena).await;
+    let rpkc = lost.do_registration(origin.clone(), ccr).unwrap();
+    let registered: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let lena_id = Uuid::parse_str(registered["unique_id"].as_str().unwrap()).unwrap();
+    let codes: Vec<String> = serde_json::from_value(registered["recovery_codes"].clone()).unwrap();
+    let tokens: BearerTokens = browser.post("/token", String::new()).await;
+
+    let mut recovering = Browser::new(&base_url);
+    let recovery = serde_json::json!({ "name": "lena", "code": codes[0] });
+    let _: serde_json::Value = recovering.post_json("/recover", &recovery).await;
+    let ccr: CreationChallengeResponse = recovering.post_json("/register_start", &lena).await;
+    state.users.set_disabled(lena_id, true).await.unwrap();
+
+    let mut replacement = with_resident_keys(SoftPasskey::new());
+    let rpkc = replacement.do_registration(origin, ccr).unwrap();
+    let (status, body) = recovering
+        .send(
+            Method::POST,
+            "/register_finish",
+            serde_json::to_string(&rpkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "account_disabled");
+    // The recovery no longer counts, so the name is just taken.
+    let (status, _) = recovering
+        .send(Method::POST, "/register_start", lena.to_string())
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let refresh = serde_json::json!({ "refresh_token": tokens.refresh_token });
+    let (status, body) = recovering
+        .send(Method::POST, "/token/refresh", refresh.to_string())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "account_disabled");
+
+    // Refused recoveries keep the code for when the account is enabled again.
+    let recovery = serde_json::json!({ "name": "lena", "code": codes[1] });
+    let (status, _) = recovering
+        .send(Method::POST, "/recover", recovery.to_string())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    state.users.set_disabled(lena_id, false).await.unwrap();
+    let _: serde_json::Value = recovering.post_json("/recover", &recovery).await;
+}
+
+#[actix_web::test]
+async fn memory_store_keeps_recovery_codes() {
+    recovery_codes_add_a_passkey(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_keeps_recovery_codes() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    recovery_codes_add_a_passkey(Arc::new(users)).await;
+}
+
+async fn magic_link_test_server(mode: MagicLinkMode, dir: &std::path::Path) -> String {
+    let config = Config {
+        mailer: MailerConfig::File {
+            dir: dir.to_string_lossy().to_string(),
+        },
+        magic_link: MagicLinkConfig {
+            enabled: true,
+            mode,
+            ..MagicLinkConfig::default()
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    start_server(state)
+}
+
+#[actix_web::test]
+async fn magic_links_log_in_once() {
+    let dir = std::env::temp_dir().join(format!("webauthn-mail-{}", Uuid::new_v4()));
+    let base_url = magic_link_test_server(MagicLinkMode::Login, &dir).await;
+    register_passkey(&mut Browser::new(&base_url), "kate@example.com").await;
+
+    // Unknown users get the same answer, but no email.
+    let mut browser = Browser::new(&base_url);
+    let unknown = serde_json::json!({ "name": "nobody@example.com" });
+    let (status, _) = browser
+        .send(Method::POST, "/magic_link", unknown.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(emails(&dir).is_empty());
+    let request = serde_json::json!({ "name": "kate@example.com" });
+    let (status, _) = browser
+        .send(Method::POST, "/magic_link", request.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (email, token) = take_email(&dir).await;
+    assert!(email.contains("To: kate@example.com"));
+    let verification = serde_json::json!({ "token": token.unwrap() });
+
+    let verified: serde_json::Value = browser.post_json("/magic_link/verify", &verification).await;
+    assert_eq!(verified["name"], "kate@example.com");
+    assert_eq!(verified["logged_in"], true);
+    let (status, _) = browser.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut elsewhere = Browser::new(&base_url);
+    let (status, body) = elsewhere
+        .send(Method::POST, "/magic_link/verify", verification.to_string())
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_token");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[actix_web::test]
+async fn disabled_users_get_no_magic_link() {
+    let dir = std::env::temp_dir().join(format!("webauthn-mail-{}", Uuid::new_v4()));
+    let base_url = magic_link_test_server(MagicLinkMode::Login, &dir).await;
+    let mut kate = Browser::new(&base_url);
+    register_passkey(&mut kate, "kate@example.com").await;
+    let mut lena = Browser::new(&base_url);
+    register_passkey(&mut lena, "lena@example.com").await;
+    let (_, body) = lena.send(Method::GET, "/identity", String::new()).await;
+    let lena: User = serde_json::from_slice(&body).unwrap();
+    let path = format!("/admin/users/{}/disable", lena.unique_id);
+    let _: User = kate.post(&path, String::new()).await;
+
+    // Kate's link is requested last, so Lena's would have been sent by then.
+    let mut browser = Browser::new(&base_url);
+    for name in ["lena@example.com", "kate@example.com"] {
+        let request = serde_json::json!({ "name": name });
+        let (status, _) = browser
+            .send(Method::POST, "/magic_link", request.to_string())
+            .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+    }
+    let (email, _) = take_email(&dir).await;
+    assert!(email.contains("To: kate@example.com"));
+    assert!(emails(&dir).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[actix_web::test]
+async fn magic_links_can_be_restricted_to_adding_a_passkey() {
+    let dir = std::env::temp_dir().join(format!("webauthn-mail-{}", Uuid::new_v4()));
+    let base_url = magic_link_test_server(MagicLinkMode::AddPasskey, &dir).await;
+    register_passkey(&mut Browser::new(&base_url), "kate@example.com").await;
+
+    let mut browser = Browser::new(&base_url);
+    let request = serde_json::json!({ "name": "kate@example.com" });
+    let (status, _) = browser
+        .send(Method::POST, "/magic_link", request.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (_, token) = take_email(&dir).await;
+    let verified: serde_json::Value = browser
+        .post_json("/magic_link/verify", &serde_json::json!({ "token": token }))
+        .await;
+    assert_eq!(verified["logged_in"], false);
+    let (status, _) = browser.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "kate@example.com", "display_name": "Kate" }),
+        )
+        .await;
+    let rpkc = authenticator
+        .do_registration(Url::parse(RP_ORIGIN).unwrap(), ccr)
+        .unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let (_, body) = browser
+        .send(Method::GET, "/credentials", String::new())
+        .await;
+    let credentials: Vec<CredentialInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(credentials.len(), 2);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn registration_policy_test_state(
+    registration: RegistrationConfig,
+    users: Arc<dyn UserStore>,
+) -> web::Data<AppState> {
+    let config = Config {
+        registration,
+        ..test_config()
+    };
+    test_state_with_config(
+        config,
+        users,
+        SessionBackend::Memory(MemorySessionStore::default()),
+    )
+}
+
+async fn invites_are_used_once(users: Arc<dyn UserStore>) {
+    let registration = RegistrationConfig {
+        policy: RegistrationPolicy::Invite,
+        ..RegistrationConfig::default()
+    };
+    let state = registration_policy_test_state(registration, users);
+    let invite = registration_policy::create_invite(state.users.as_ref(), 60)
+        .await
+        .unwrap()
+        .code;
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+
+    let mut browser = Browser::new(&base_url);
+    for registration in [
+        serde_json::json!({ "name": "liam", "display_name": "Liam" }),
+        serde_json::json!({ "name": "liam", "display_name": "Liam", "invite": "0000-0000-0000-0000" }),
+    ] {
+        let (status, body) = browser
+            .send(Method::POST, "/register_start", registration.to_string())
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(problem_code(&body), "invalid_invite");
+    }
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({
+                "name": "liam",
+                "display_name": "Liam",
+                "invite": invite.replace('-', "").to_lowercase(),
+            }),
+        )
+        .await;
+    // Both registrations pass the check, but only the first one to finish gets the invite.
+    let mut stranger = Browser::new(&base_url);
+    let reused = serde_json::json!({ "name": "mia", "display_name": "Mia", "invite": invite });
+    let late_ccr: CreationChallengeResponse = stranger.post_json("/register_start", &reused).await;
+    let rpkc = authenticator.do_registration(origin.clone(), ccr).unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+    let rpkc = with_resident_keys(SoftPasskey::new())
+        .do_registration(origin.clone(), late_ccr)
+        .unwrap();
+    let (status, body) = stranger
+        .send(
+            Method::POST,
+            "/register_finish",
+            serde_json::to_string(&rpkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "invalid_invite");
+    let (status, _) = stranger
+        .send(Method::POST, "/login_start", "mia".to_string())
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Existing users add passkeys without an invite.
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": "liam", "display_name": "Liam" }),
+        )
+        .await;
+    let mut another = with_resident_keys(SoftPasskey::new());
+    let rpkc = another.do_registration(origin, ccr).unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+
+    let (status, body) = stranger
+        .send(Method::POST, "/register_start", reused.to_string())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "invalid_invite");
+}
+
+#[actix_web::test]
+async fn memory_store_uses_invites_once() {
+    invites_are_used_once(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_uses_invites_once() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    invites_are_used_once(Arc::new(users)).await;
+}
+
+#[actix_web::test]
+async fn registration_can_be_closed_or_limited_to_domains() {
+    let closed = RegistrationConfig {
+        policy: RegistrationPolicy::Closed,
+        ..RegistrationConfig::default()
+    };
+    let state = registration_policy_test_state(closed, Arc::new(MemoryUserStore::default()));
+    let mut browser = Browser::new(&start_server(state));
+    let registration = serde_json::json!({ "name": "nina@example.com", "display_name": "Nina" });
+    let (status, body) = browser
+        .send(Method::POST, "/register_start", registration.to_string())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "registration_closed");
+
+    // The domain policy only works with verified addresses.
+    let dir = std::env::temp_dir().join(format!("webauthn-mail-{}", Uuid::new_v4()));
+    let config = Config {
+        privacy_mode: true,
+        mailer: MailerConfig::File {
+            dir: dir.to_string_lossy().to_string(),
+        },
+        registration: RegistrationConfig {
+            policy: RegistrationPolicy::Domain,
+            allowed_domains: vec!["example.com".to_string()],
+            ..RegistrationConfig::default()
+        },
+        ..test_config()
+    };
+    let state = test_state_with_config(
+        config,
+        Arc::new(MemoryUserStore::default()),
+        SessionBackend::Memory(MemorySessionStore::default()),
+    );
+    let mut browser = Browser::new(&start_server(state));
+    for name in ["nina", "nina@example.org", "nina@example.com@evil.com"] {
+        let registration = serde_json::json!({ "name": name, "display_name": "Nina" });
+        let (status, body) = browser
+            .send(Method::POST, "/register_start", registration.to_string())
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", name);
+        assert_eq!(problem_code(&body), "domain_not_allowed");
+    }
+    let registration = serde_json::json!({ "name": "nina@Example.COM", "display_name": "Nina" });
+    let (status, _) = browser
+        .send(Method::POST, "/register_start", registration.to_string())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (_, token) = take_email(&dir).await;
+    let ccr: CreationChallengeResponse = browser
+        .post_json("/register_verify", &serde_json::json!({ "token": token }))
+        .await;
+    let rpkc = with_resident_keys(SoftPasskey::new())
+        .do_registration(Url::parse(RP_ORIGIN).unwrap(), ccr)
+        .unwrap();
+    let _: serde_json::Value = browser.post_json("/register_finish", &rpkc).await;
+
+    // Renaming the account cannot leave the allowed domains either.
+    let rename = serde_json::json!({ "name": "nina@example.org", "display_name": "Nina" });
+    let (status, body) = browser
+        .send(Method::PUT, "/profile", rename.to_string())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "domain_not_allowed");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Register `name`, or add a passkey if the browser is logged in as `name` already.
+async fn register_passkey(
+    browser: &mut Browser,
+    name: &str,
+) -> WebauthnAuthenticator<ResidentKeys<SoftPasskey>> {
+    let mut authenticator = with_resident_keys(SoftPasskey::new());
+    let (status, body) = try_register(browser, name, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK, "{:?}", body);
+    authenticator
+}
+
+/// Register `name` with the authenticator, the answer of `/register_finish` is up to the
+/// test.
+async fn try_register<T: AuthenticatorBackend>(
+    browser: &mut Browser,
+    name: &str,
+    authenticator: &mut WebauthnAuthenticator<T>,
+) -> (StatusCode, Bytes) {
+    let ccr: CreationChallengeResponse = browser
+        .post_json(
+            "/register_start",
+            &serde_json::json!({ "name": name, "display_name": name }),
+        )
+        .await;
+    let rpkc = authenticator
+        .do_registration(Url::parse(RP_ORIGIN).unwrap(), ccr)
+        .unwrap();
+    browser
+        .send(
+            Method::POST,
+            "/register_finish",
+            serde_json::to_string(&rpkc).unwrap(),
+        )
+        .await
+}
+
+async fn admins_manage_users(users: Arc<dyn UserStore>) {
+    let state = test_state(users, SessionBackend::Memory(MemorySessionStore::default()));
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+
+    // The first account administers the others.
+    let mut olga = Browser::new(&base_url);
+    register_passkey(&mut olga, "olga").await;
+    let mut paul = Browser::new(&base_url);
+    register_passkey(&mut paul, "paul").await;
+    register_passkey(&mut paul, "paul").await;
+    let (_, body) = olga.send(Method::GET, "/identity", String::new()).await;
+    let identity: User = serde_json::from_slice(&body).unwrap();
+    assert_eq!(identity.roles, vec![Role::Admin]);
+
+    let (status, body) = paul.send(Method::GET, "/admin/users", String::new()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "missing_role");
+    let (status, _) = Browser::new(&base_url)
+        .send(Method::GET, "/admin/users", String::new())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, body) = olga
+        .send(Method::GET, "/admin/users?limit=1&offset=1", String::new())
+        .await;
+    let page: UserPage = serde_json::from_slice(&body).unwrap();
+    assert_eq!((page.total, page.users.len()), (2, 1));
+    assert_eq!(page.users[0].name, "paul");
+    let paul_id = page.users[0].unique_id;
+    let path = format!("/admin/users?offset={}", usize::MAX);
+    let (status, body) = olga.send(Method::GET, &path, String::new()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem_code(&body), "invalid_input");
+    let (_, body) = olga
+        .send(Method::GET, "/admin/users?search=LG", String::new())
+        .await;
+    let page: UserPage = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page.total, 1);
+    assert_eq!(page.users[0].name, "olga");
+
+    let path = format!("/admin/users/{}", paul_id);
+    let (status, body) = olga.send(Method::GET, &path, String::new()).await;
+    assert_eq!(status, StatusCode::OK);
+    let details: UserDetails = serde_json::from_slice(&body).unwrap();
+    assert_eq!(details.user.name, "paul");
+    assert_eq!(details.credentials.len(), 2);
+    assert!(details
+        .recent_events
+        .iter()
+        .all(|event| event.action == AuditAction::Register));
+
+    // Unlike the user, administrators may remove the last passkey.
+    for credential in &details.credentials {
+        let path = format!("/admin/users/{}/credentials/{}", paul_id, credential.id);
+        let (status, _) = olga.send(Method::DELETE, &path, String::new()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = olga.send(Method::DELETE, &path, String::new()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(problem_code(&body), "credential_not_found");
+    }
+    let mut authenticator = register_passkey(&mut paul, "paul").await;
+
+    let path = format!("/admin/users/{}/disable", identity.unique_id);
+    let (status, _) = olga.send(Method::POST, &path, String::new()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let path = format!("/admin/users/{}/disable", paul_id);
+    let disabled: User = olga.post(&path, String::new()).await;
+    assert!(disabled.disabled);
+    let (status, _) = paul.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let rcr: RequestChallengeResponse = paul.post("/login_start", "paul".to_string()).await;
+    let pkc = authenticator
+        .do_authentication(origin.clone(), rcr)
+        .unwrap();
+    let (status, body) = paul
+        .send(
+            Method::POST,
+            "/login_finish",
+            serde_json::to_string(&pkc).unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem_code(&body), "account_disabled");
+    let (_, body) = olga
+        .send(
+            Method::GET,
+            &format!("/admin/users/{}", paul_id),
+            String::new(),
+        )
+        .await;
+    let details: UserDetails = serde_json::from_slice(&body).unwrap();
+    assert_eq!(details.credentials[0].last_used_at, None);
+
+    let path = format!("/admin/users/{}/enable", paul_id);
+    let enabled: User = olga.post(&path, String::new()).await;
+    assert!(!enabled.disabled);
+    let rcr: RequestChallengeResponse = paul.post("/login_start", "paul".to_string()).await;
+    let pkc = authenticator.do_authentication(origin, rcr).unwrap();
+    let _: serde_json::Value = paul.post_json("/login_finish", &pkc).await;
+    let (status, _) = paul.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let path = format!("/admin/users/{}/logout", paul_id);
+    let (status, _) = olga.send(Method::POST, &path, String::new()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = paul.send(Method::GET, "/identity", String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let path = format!("/admin/users/{}", paul_id);
+    let (_, body) = olga.send(Method::GET, &path, String::new()).await;
+    let details: UserDetails = serde_json::from_slice(&body).unwrap();
+    let actions: Vec<_> = details.recent_events.iter().map(|e| e.action).collect();
+    for action in [
+        AuditAction::RevokeCredential,
+        AuditAction::Disable,
+        AuditAction::Enable,
+        AuditAction::ForceLogout,
+    ] {
+        assert!(
+            actions.contains(&action),
+            "{:?} not in {:?}",
+            action,
+            actions
+        );
+    }
+    assert_eq!(actions[0], AuditAction::ForceLogout);
+    // The administrator is recorded, not only the user they acted on.
+    assert_eq!(details.recent_events[0].actor, Some(identity.unique_id));
+    let register = details.recent_events.last().unwrap();
+    assert_eq!(register.action, AuditAction::Register);
+    assert_eq!(register.actor, None);
+
+    let invite: Invite = olga.post("/admin/invites", String::new()).await;
+    assert!(!invite.code.is_empty());
+}
+
+#[actix_web::test]
+async fn memory_store_lets_admins_manage_users() {
+    admins_manage_users(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_lets_admins_manage_users() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    admins_manage_users(Arc::new(users)).await;
+}
+
+async fn only_the_first_user_becomes_admin(users: Arc<dyn UserStore>) {
+    let state = test_state(users, SessionBackend::Memory(MemorySessionStore::default()));
+    let base_url = start_server(state);
+    let origin = Url::parse(RP_ORIGIN).unwrap();
+
+    // All registrations start before the first one finishes, so they race for the role.
+    let mut registrations = vec![];
+    for i in 0..USERS {
+        let mut browser = Browser::new(&base_url);
+        let ccr: CreationChallengeResponse = browser
+            .post_json(
+                "/register_start",
+                &serde_json::json!({ "name": format!("user{}", i), "display_name": "User" }),
+            )
+            .await;
+        let rpkc = with_resident_keys(SoftPasskey::new())
+            .do_registration(origin.clone(), ccr)
+            .unwrap();
+        registrations.push((browser, rpkc));
+    }
+    let registered: Vec<User> = join_all(
+        registrations
+            .iter_mut()
+            .map(|(browser, rpkc)| browser.post_json("/register_finish", rpkc)),
+    )
+    .await;
+    let admins = registered
+        .iter()
+        .filter(|user| user.has_role(Role::Admin))
+        .count();
+    assert_eq!(admins, 1);
+}
+
+#[actix_web::test]
+async fn memory_store_makes_one_first_admin() {
+    only_the_first_user_becomes_admin(Arc::new(MemoryUserStore::default())).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store_makes_one_first_admin() {
+    let db = TempDb::new("test");
+    let users = SqliteUserStore::open(&db.path).unwrap();
+    only_the_first_user_becomes_admin(Arc::new(users)).await;
+}
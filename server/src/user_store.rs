@@ -0,0 +1,407 @@
+use crate::models::*;
+use anyhow::Result;
+use async_std::sync::RwLock;
+use std::collections::HashMap;
+use webauthn_rs::prelude::*;
+
+/// Storage backend for users and their passkeys. All lookups in `auth` go through this
+/// trait so the server does not care whether accounts live in memory or in a database.
+#[async_trait::async_trait]
+pub trait UserStore: Send + Sync {
+    /// Look up their unique id from the username
+    async fn name_to_id(&self, username: &str) -> Result<Option<Uuid>>;
+
+    async fn get_user(&self, user_unique_id: Uuid) -> Result<Option<User>>;
+
+    /// All passkeys registered for the user, empty if there are none.
+    async fn get_credentials(&self, user_unique_id: Uuid) -> Result<Vec<Passkey>>;
+
+    /// Create the user if it does not exist yet and add the credential to it.
+    /// The profile of an existing user is left untouched. A new user needs the `invite`,
+    /// given by its hash, if there is one: it is used up along with creating the user,
+    /// and nothing is stored if that fails. Returns false then.
+    ///
+    /// With `first_is_admin` a new user also gets [Role::Admin] if there are no users yet.
+    /// This is decided in the same step, so of two first registrations only one wins.
+    async fn insert_user(
+        &self,
+        user: &User,
+        credential: StoredCredential,
+        invite: Option<&str>,
+        first_is_admin: bool,
+    ) -> Result<bool>;
+
+    /// Change the name and display name, the unique id stays the same.
+    async fn update_profile(
+        &self,
+        user_unique_id: Uuid,
+        name: &str,
+        display_name: &str,
+    ) -> Result<ProfileUpdate>;
+
+    /// Apply the result of a successful authentication to the matching credential,
+    /// e.g. to persist the updated counter, and record when it was last used.
+    async fn update_credential(
+        &self,
+        user_unique_id: Uuid,
+        auth_result: &AuthenticationResult,
+    ) -> Result<()>;
+
+    /// All passkeys registered for the user along with their metadata.
+    async fn list_credentials(&self, user_unique_id: Uuid) -> Result<Vec<StoredCredential>>;
+
+    /// Returns false if the user has no credential with this base64url encoded id.
+    async fn rename_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+        nickname: &str,
+    ) -> Result<bool>;
+
+    /// Delete the credential unless it is the last one of the user.
+    async fn delete_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+    ) -> Result<CredentialRemoval>;
+
+    /// How many users and passkeys there are in total.
+    async fn counts(&self) -> Result<UserCounts>;
+
+    /// Replace all recovery codes of the user, given by their hashes.
+    async fn replace_recovery_codes(
+        &self,
+        user_unique_id: Uuid,
+        code_hashes: &[String],
+    ) -> Result<()>;
+
+    /// Delete the recovery code, returns false if the user does not have it.
+    async fn use_recovery_code(&self, user_unique_id: Uuid, code_hash: &str) -> Result<bool>;
+
+    /// How many unused recovery codes the user has left.
+    async fn count_recovery_codes(&self, user_unique_id: Uuid) -> Result<usize>;
+
+    /// Add an invite code, given by its hash, valid until `expires_at` in unix seconds.
+    async fn insert_invite(&self, code_hash: &str, expires_at: i64) -> Result<()>;
+
+    /// Whether the invite code exists and has not expired, without using it up.
+    async fn is_invite_valid(&self, code_hash: &str) -> Result<bool>;
+
+    /// One page of the users whose name or display name contains `search`, ignoring
+    /// case, oldest first, and how many match in total.
+    async fn search_users(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<User>, usize)>;
+
+    /// Returns false if the user does not exist.
+    async fn set_roles(&self, user_unique_id: Uuid, roles: &[Role]) -> Result<bool>;
+
+    /// Returns false if the user does not exist.
+    async fn set_disabled(&self, user_unique_id: Uuid, disabled: bool) -> Result<bool>;
+
+    /// Delete the credential even if it is the last one of the user, returns false if the
+    /// user does not have it.
+    async fn revoke_credential(&self, user_unique_id: Uuid, cred_id: &str) -> Result<bool>;
+}
+
+/// Keeps everything in memory, so all accounts are lost on restart. Useful for tests
+/// and throwaway demo instances. Lookups only take a read lock, so logins do not queue
+/// up behind registrations.
+pub struct MemoryUserStore {
+    users: RwLock<Users>,
+}
+
+impl Default for MemoryUserStore {
+    fn default() -> Self {
+        Self {
+            users: RwLock::new(Users {
+                name_to_id: HashMap::new(),
+                profiles: HashMap::new(),
+                keys: HashMap::new(),
+                recovery_codes: HashMap::new(),
+                invites: HashMap::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStore for MemoryUserStore {
+    async fn name_to_id(&self, username: &str) -> Result<Option<Uuid>> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard.name_to_id.get(username).copied())
+    }
+
+    async fn get_user(&self, user_unique_id: Uuid) -> Result<Option<User>> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard.profiles.get(&user_unique_id).cloned())
+    }
+
+    async fn get_credentials(&self, user_unique_id: Uuid) -> Result<Vec<Passkey>> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard
+            .keys
+            .get(&user_unique_id)
+            .map(|keys| keys.iter().map(|key| key.passkey.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    async fn insert_user(
+        &self,
+        user: &User,
+        credential: StoredCredential,
+        invite: Option<&str>,
+        first_is_admin: bool,
+    ) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+
+        if !users_guard.profiles.contains_key(&user.unique_id) {
+            if let Some(code_hash) = invite {
+                let used = users_guard
+                    .invites
+                    .remove(code_hash)
+                    .is_some_and(|expires_at| expires_at > unix_now());
+                if !used {
+                    return Ok(false);
+                }
+            }
+            let mut user = user.clone();
+            if first_is_admin && users_guard.profiles.is_empty() && !user.has_role(Role::Admin) {
+                user.roles.push(Role::Admin);
+            }
+            users_guard
+                .name_to_id
+                .insert(user.name.to_string(), user.unique_id);
+            users_guard.profiles.insert(user.unique_id, user);
+        }
+        users_guard
+            .keys
+            .entry(user.unique_id)
+            .or_default()
+            .push(credential);
+        Ok(true)
+    }
+
+    async fn update_profile(
+        &self,
+        user_unique_id: Uuid,
+        name: &str,
+        display_name: &str,
+    ) -> Result<ProfileUpdate> {
+        let mut users_guard = self.users.write().await;
+        match users_guard.name_to_id.get(name) {
+            Some(id) if id != &user_unique_id => return Ok(ProfileUpdate::NameTaken),
+            _ => {}
+        }
+        let user = match users_guard.profiles.get_mut(&user_unique_id) {
+            Some(user) => user,
+            None => return Ok(ProfileUpdate::NotFound),
+        };
+        let old_name = std::mem::replace(&mut user.name, name.to_string());
+        user.display_name = display_name.to_string();
+        let user = user.clone();
+        users_guard.name_to_id.remove(&old_name);
+        users_guard
+            .name_to_id
+            .insert(name.to_string(), user_unique_id);
+        Ok(ProfileUpdate::Updated(user))
+    }
+
+    async fn update_credential(
+        &self,
+        user_unique_id: Uuid,
+        auth_result: &AuthenticationResult,
+    ) -> Result<()> {
+        let mut users_guard = self.users.write().await;
+        users_guard
+            .keys
+            .get_mut(&user_unique_id)
+            .map(|keys| {
+                keys.iter_mut().for_each(|key| {
+                    // This will update the credential if it's the matching
+                    // one. Otherwise it's ignored. That is why it is safe to
+                    // iterate this over the full list.
+                    if key.passkey.update_credential(auth_result).is_some() {
+                        key.last_used_at = Some(unix_now());
+                    }
+                })
+            })
+            .ok_or_else(|| anyhow::Error::msg("User has no credentials"))
+    }
+
+    async fn list_credentials(&self, user_unique_id: Uuid) -> Result<Vec<StoredCredential>> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard
+            .keys
+            .get(&user_unique_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn rename_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+        nickname: &str,
+    ) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+        let key = users_guard.keys.get_mut(&user_unique_id).and_then(|keys| {
+            keys.iter_mut()
+                .find(|key| key.passkey.cred_id().to_string() == cred_id)
+        });
+        Ok(match key {
+            Some(key) => {
+                key.nickname = nickname.to_string();
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn delete_credential(
+        &self,
+        user_unique_id: Uuid,
+        cred_id: &str,
+    ) -> Result<CredentialRemoval> {
+        let mut users_guard = self.users.write().await;
+        let keys = match users_guard.keys.get_mut(&user_unique_id) {
+            Some(keys) => keys,
+            None => return Ok(CredentialRemoval::NotFound),
+        };
+        let position = keys
+            .iter()
+            .position(|key| key.passkey.cred_id().to_string() == cred_id);
+        Ok(match position {
+            None => CredentialRemoval::NotFound,
+            Some(_) if keys.len() == 1 => CredentialRemoval::LastCredential,
+            Some(position) => {
+                keys.remove(position);
+                CredentialRemoval::Removed
+            }
+        })
+    }
+
+    async fn counts(&self) -> Result<UserCounts> {
+        let users_guard = self.users.read().await;
+        Ok(UserCounts {
+            users: users_guard.profiles.len(),
+            credentials: users_guard.keys.values().map(Vec::len).sum(),
+        })
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_unique_id: Uuid,
+        code_hashes: &[String],
+    ) -> Result<()> {
+        let mut users_guard = self.users.write().await;
+        users_guard
+            .recovery_codes
+            .insert(user_unique_id, code_hashes.to_vec());
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, user_unique_id: Uuid, code_hash: &str) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+        let codes = match users_guard.recovery_codes.get_mut(&user_unique_id) {
+            Some(codes) => codes,
+            None => return Ok(false),
+        };
+        Ok(match codes.iter().position(|hash| hash == code_hash) {
+            Some(position) => {
+                codes.remove(position);
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn count_recovery_codes(&self, user_unique_id: Uuid) -> Result<usize> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard
+            .recovery_codes
+            .get(&user_unique_id)
+            .map_or(0, Vec::len))
+    }
+
+    async fn insert_invite(&self, code_hash: &str, expires_at: i64) -> Result<()> {
+        let mut users_guard = self.users.write().await;
+        let now = unix_now();
+        users_guard.invites.retain(|_, &mut expires| expires > now);
+        users_guard
+            .invites
+            .insert(code_hash.to_string(), expires_at);
+        Ok(())
+    }
+
+    async fn is_invite_valid(&self, code_hash: &str) -> Result<bool> {
+        let users_guard = self.users.read().await;
+        Ok(users_guard
+            .invites
+            .get(code_hash)
+            .is_some_and(|&expires_at| expires_at > unix_now()))
+    }
+
+    async fn search_users(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<User>, usize)> {
+        let search = search.to_lowercase();
+        let users_guard = self.users.read().await;
+        let mut users: Vec<&User> = users_guard
+            .profiles
+            .values()
+            .filter(|user| {
+                user.name.to_lowercase().contains(&search)
+                    || user.display_name.to_lowercase().contains(&search)
+            })
+            .collect();
+        users.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+        let total = users.len();
+        let page = users
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok((page, total))
+    }
+
+    async fn set_roles(&self, user_unique_id: Uuid, roles: &[Role]) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+        Ok(match users_guard.profiles.get_mut(&user_unique_id) {
+            Some(user) => {
+                user.roles = roles.to_vec();
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn set_disabled(&self, user_unique_id: Uuid, disabled: bool) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+        Ok(match users_guard.profiles.get_mut(&user_unique_id) {
+            Some(user) => {
+                user.disabled = disabled;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn revoke_credential(&self, user_unique_id: Uuid, cred_id: &str) -> Result<bool> {
+        let mut users_guard = self.users.write().await;
+        let keys = match users_guard.keys.get_mut(&user_unique_id) {
+            Some(keys) => keys,
+            None => return Ok(false),
+        };
+        let before = keys.len();
+        keys.retain(|key| key.passkey.cred_id().to_string() != cred_id);
+        Ok(keys.len() < before)
+    }
+}